@@ -0,0 +1,72 @@
+// Compares running with the decode cache against decoding every instruction,
+// and both against the block recompiler. Run with `cargo bench`.
+
+extern crate chip8_virtual_machine;
+
+use chip8_virtual_machine::{Backend, Chip8Machine};
+
+use std::time::{Duration, Instant};
+
+const STEPS: usize = 5_000_000;
+
+// A tight loop of register arithmetic and skips that jumps back to 0x200
+const PROGRAM: [u8; 20] = [
+    0x60, 0x05, // LD V0, 0x05
+    0x61, 0x0A, // LD V1, 0x0A
+    0x80, 0x11, // OR V0, V1
+    0x80, 0x12, // AND V0, V1
+    0x80, 0x13, // XOR V0, V1
+    0x30, 0x00, // SE V0, 0x00
+    0x81, 0x00, // LD V1, V0
+    0x90, 0x10, // SNE V0, V1
+    0xA3, 0x00, // LD I, 0x300
+    0x12, 0x00, // JP 0x200
+];
+
+fn time_steps(decode_cache: bool) -> Duration {
+    let mut machine = Chip8Machine::new();
+    machine.set_decode_cache(decode_cache);
+    machine.load_memory(&PROGRAM);
+
+    let start = Instant::now();
+    for _ in 0..STEPS {
+        machine.step();
+    }
+
+    start.elapsed()
+}
+
+fn time_blocks() -> Duration {
+    let mut machine = Chip8Machine::new();
+    machine.set_backend(Backend::Recompiler);
+    machine.load_memory(&PROGRAM);
+
+    let start = Instant::now();
+    let mut executed = 0;
+    while executed < STEPS {
+        executed += machine.execute();
+    }
+
+    start.elapsed()
+}
+
+fn main() {
+    // Warm up both paths once before measuring
+    time_steps(false);
+    time_steps(true);
+    time_blocks();
+
+    let uncached = time_steps(false);
+    let cached = time_steps(true);
+    let blocks = time_blocks();
+
+    println!("{} steps", STEPS);
+    println!("decode every step: {:?}", uncached);
+    println!("decode cache:      {:?}", cached);
+    println!("recompiler:        {:?}", blocks);
+    println!(
+        "speedup:           {:.2}x cached, {:.2}x recompiled",
+        uncached.as_secs_f64() / cached.as_secs_f64(),
+        uncached.as_secs_f64() / blocks.as_secs_f64()
+    );
+}
@@ -0,0 +1,397 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write;
+
+use instructions::Instruction;
+
+// A run of instructions that is only ever entered at the top and only
+// branches at the bottom. Calls don't end a block since they come back to
+// the next instruction.
+#[derive(Clone, PartialEq, Debug)]
+pub struct BasicBlock {
+    pub start: u16,
+    // Address of the last instruction
+    pub last: u16,
+    pub successors: Vec<u16>,
+    pub calls: Vec<u16>,
+    // Ends in a BNNN jump, whose targets aren't known until it runs
+    pub computed_jump: bool,
+}
+
+// The blocks reachable from an entry point without going through a call.
+// Blocks shared between subroutines show up in each of them.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Subroutine {
+    pub entry: u16,
+    pub blocks: Vec<u16>,
+}
+
+pub struct ControlFlowGraph {
+    pub load_address: u16,
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    program: Vec<u8>,
+    code: BTreeSet<u16>,
+}
+
+impl ControlFlowGraph {
+    // Recovers the code in a program by following every path from the load
+    // address. Anything not reached this way is taken to be data.
+    pub fn analyze(program: &[u8], load_address: u16) -> ControlFlowGraph {
+        let mut graph = ControlFlowGraph {
+            load_address,
+            blocks: BTreeMap::new(),
+            subroutines: BTreeMap::new(),
+            program: program.to_vec(),
+            code: BTreeSet::new(),
+        };
+
+        let mut leaders = BTreeSet::new();
+        let mut entries = BTreeSet::new();
+        let mut pending = vec![load_address];
+        leaders.insert(load_address);
+        entries.insert(load_address);
+
+        while let Some(address) = pending.pop() {
+            if !graph.contains(address) || !graph.code.insert(address) {
+                continue;
+            }
+
+            // Words that don't decode are skipped over when run
+            let instruction = match graph.instruction(address) {
+                Some(instruction) => instruction,
+                None => {
+                    if graph.word(address) != 0 {
+                        pending.push(address + 2);
+                    }
+                    continue;
+                }
+            };
+
+            for target in graph.targets(address, &instruction) {
+                leaders.insert(target);
+                pending.push(target);
+            }
+            if let Instruction::CALL(target) = instruction {
+                if graph.contains(target) {
+                    entries.insert(target);
+                    leaders.insert(target);
+                    pending.push(target);
+                }
+            }
+            if !ends_block(&instruction) {
+                pending.push(address + 2);
+            }
+        }
+
+        for &leader in leaders.iter() {
+            if graph.code.contains(&leader) {
+                let block = graph.block_from(leader, &leaders);
+                graph.blocks.insert(leader, block);
+            }
+        }
+
+        for &entry in entries.iter() {
+            let subroutine = graph.subroutine_from(entry);
+            graph.subroutines.insert(entry, subroutine);
+        }
+
+        graph
+    }
+
+    pub fn is_code(&self, address: u16) -> bool {
+        self.code.contains(&address)
+    }
+
+    // The word at an address, reading memory past the program as zero
+    pub fn word(&self, address: u16) -> u16 {
+        let byte = |address: u16| {
+            (address as usize)
+                .checked_sub(self.load_address as usize)
+                .and_then(|offset| self.program.get(offset))
+                .cloned()
+                .unwrap_or(0) as u16
+        };
+
+        byte(address) << 8 | byte(address.wrapping_add(1))
+    }
+
+    // A zero word is where run() stops, so it isn't treated as a SYS call
+    pub fn instruction(&self, address: u16) -> Option<Instruction> {
+        match self.word(address) {
+            0 => None,
+            word => Instruction::new(word),
+        }
+    }
+
+    fn contains(&self, address: u16) -> bool {
+        address >= self.load_address
+            && ((address - self.load_address) as usize) < self.program.len()
+    }
+
+    // Where control can go after an instruction, other than calls and
+    // falling through to the next one
+    fn targets(&self, address: u16, instruction: &Instruction) -> Vec<u16> {
+        let targets = match *instruction {
+            Instruction::JP(target) => vec![target],
+            Instruction::SEC(_, _)
+            | Instruction::SNEC(_, _)
+            | Instruction::SER(_, _)
+            | Instruction::SNE(_, _)
+            | Instruction::SKP(_)
+            | Instruction::SKNP(_) => vec![address + 2, address + 4],
+            _ => Vec::new(),
+        };
+
+        targets
+            .into_iter()
+            .filter(|target| self.contains(*target))
+            .collect()
+    }
+
+    fn block_from(&self, start: u16, leaders: &BTreeSet<u16>) -> BasicBlock {
+        let mut block = BasicBlock {
+            start,
+            last: start,
+            successors: Vec::new(),
+            calls: Vec::new(),
+            computed_jump: false,
+        };
+
+        let mut address = start;
+        loop {
+            block.last = address;
+            let instruction = self.instruction(address);
+
+            match instruction {
+                Some(Instruction::CALL(target)) => block.calls.push(target),
+                Some(Instruction::JPA(_)) => block.computed_jump = true,
+                _ => {}
+            }
+
+            let ends = match instruction {
+                Some(ref instruction) => ends_block(instruction),
+                // A zero word halts, anything else that doesn't decode is
+                // skipped over
+                None => self.word(address) == 0,
+            };
+            if ends {
+                if let Some(ref instruction) = instruction {
+                    block.successors = self.targets(address, instruction);
+                }
+                return block;
+            }
+
+            let next = address + 2;
+            if !self.code.contains(&next) {
+                return block;
+            }
+            if leaders.contains(&next) {
+                block.successors.push(next);
+                return block;
+            }
+
+            address = next;
+        }
+    }
+
+    fn subroutine_from(&self, entry: u16) -> Subroutine {
+        let mut seen = HashSet::new();
+        let mut pending = vec![entry];
+
+        while let Some(start) = pending.pop() {
+            if let Some(block) = self.blocks.get(&start) {
+                if seen.insert(start) {
+                    pending.extend(block.successors.iter().cloned());
+                }
+            }
+        }
+
+        let mut blocks: Vec<u16> = seen.into_iter().collect();
+        blocks.sort();
+
+        Subroutine { entry, blocks }
+    }
+
+    pub fn subroutine_name(&self, entry: u16) -> String {
+        if entry == self.load_address {
+            "main".to_string()
+        } else {
+            format!("sub_{:03x}", entry)
+        }
+    }
+
+    // The blocks of each subroutine clustered together, with a dashed edge
+    // for each call and a dotted one to a ? node for computed jumps
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph cfg {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+
+        for subroutine in self.subroutines.values() {
+            let name = self.subroutine_name(subroutine.entry);
+            writeln!(dot, "    subgraph cluster_{} {{", name).unwrap();
+            writeln!(dot, "        label=\"{}\";", name).unwrap();
+            for start in subroutine.blocks.iter() {
+                writeln!(dot, "        \"{:03x}\";", start).unwrap();
+            }
+            writeln!(dot, "    }}").unwrap();
+        }
+
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            let mut address = block.start;
+            while address <= block.last {
+                match self.instruction(address) {
+                    Some(instruction) => write!(label, "{:03x}: {:?}\\l", address, instruction),
+                    None => write!(label, "{:03x}: {:04x}\\l", address, self.word(address)),
+                }
+                .unwrap();
+                address += 2;
+            }
+            writeln!(dot, "    \"{:03x}\" [label=\"{}\"];", block.start, label).unwrap();
+
+            for successor in block.successors.iter() {
+                writeln!(dot, "    \"{:03x}\" -> \"{:03x}\";", block.start, successor).unwrap();
+            }
+            for target in block.calls.iter() {
+                writeln!(
+                    dot,
+                    "    \"{:03x}\" -> \"{:03x}\" [style=dashed];",
+                    block.start, target
+                )
+                .unwrap();
+            }
+            if block.computed_jump {
+                writeln!(dot, "    \"{:03x}\" -> \"?\" [style=dotted];", block.start).unwrap();
+            }
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    // One node per subroutine with an edge for each subroutine it calls
+    pub fn call_graph_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph calls {{").unwrap();
+
+        for subroutine in self.subroutines.values() {
+            let name = self.subroutine_name(subroutine.entry);
+            let computed = subroutine
+                .blocks
+                .iter()
+                .any(|start| self.blocks[start].computed_jump);
+            if computed {
+                writeln!(dot, "    {} [style=dashed];", name).unwrap();
+            } else {
+                writeln!(dot, "    {};", name).unwrap();
+            }
+
+            let callees: BTreeSet<u16> = subroutine
+                .blocks
+                .iter()
+                .flat_map(|start| self.blocks[start].calls.iter().cloned())
+                .collect();
+            for callee in callees {
+                let callee = if self.subroutines.contains_key(&callee) {
+                    self.subroutine_name(callee)
+                } else {
+                    format!("\"{:03x}\"", callee)
+                };
+                writeln!(dot, "    {} -> {};", name, callee).unwrap();
+            }
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        *instruction,
+        Instruction::JP(_)
+            | Instruction::RET
+            | Instruction::JPA(_)
+            | Instruction::SEC(_, _)
+            | Instruction::SNEC(_, _)
+            | Instruction::SER(_, _)
+            | Instruction::SNE(_, _)
+            | Instruction::SKP(_)
+            | Instruction::SKNP(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200: CALL 208
+    // 202: SE V0, 1
+    // 204: JP 200
+    // 206: JP 206
+    // 208: ADD V0, 1
+    // 20A: RET
+    // 20C: data
+    const PROGRAM: [u8; 14] = [
+        0x22, 0x08, 0x30, 0x01, 0x12, 0x00, 0x12, 0x06, 0x70, 0x01, 0x00, 0xEE, 0xAB, 0xCD,
+    ];
+
+    #[test]
+    fn code_and_data() {
+        let graph = ControlFlowGraph::analyze(&PROGRAM, 0x200);
+
+        for address in [0x200, 0x202, 0x204, 0x206, 0x208, 0x20A].iter() {
+            assert!(graph.is_code(*address));
+        }
+        assert!(!graph.is_code(0x20C));
+    }
+
+    #[test]
+    fn blocks_and_edges() {
+        let graph = ControlFlowGraph::analyze(&PROGRAM, 0x200);
+
+        let starts: Vec<u16> = graph.blocks.keys().cloned().collect();
+        assert_eq!(vec![0x200, 0x204, 0x206, 0x208], starts);
+
+        let first = &graph.blocks[&0x200];
+        assert_eq!(0x202, first.last);
+        assert_eq!(vec![0x204, 0x206], first.successors);
+        assert_eq!(vec![0x208], first.calls);
+        assert_eq!(vec![0x200], graph.blocks[&0x204].successors);
+        assert!(graph.blocks[&0x208].successors.is_empty());
+    }
+
+    #[test]
+    fn subroutines() {
+        let graph = ControlFlowGraph::analyze(&PROGRAM, 0x200);
+
+        assert_eq!(vec![0x200, 0x204, 0x206], graph.subroutines[&0x200].blocks);
+        assert_eq!(vec![0x208], graph.subroutines[&0x208].blocks);
+    }
+
+    #[test]
+    fn computed_jumps_are_flagged() {
+        // LD V0, 2; JP V0, 200
+        let graph = ControlFlowGraph::analyze(&[0x60, 0x02, 0xB2, 0x00], 0x200);
+
+        assert!(graph.blocks[&0x200].computed_jump);
+        assert!(graph.blocks[&0x200].successors.is_empty());
+        assert!(graph.to_dot().contains("\"200\" -> \"?\" [style=dotted];"));
+    }
+
+    #[test]
+    fn dot_export() {
+        let graph = ControlFlowGraph::analyze(&PROGRAM, 0x200);
+
+        let cfg = graph.to_dot();
+        assert!(cfg.starts_with("digraph cfg {"));
+        assert!(cfg.contains("subgraph cluster_sub_208 {"));
+        assert!(cfg.contains("\"200\" -> \"206\";"));
+        assert!(cfg.contains("\"200\" -> \"208\" [style=dashed];"));
+        assert!(cfg.contains("208: ADDC(V0, 1)\\l20a: RET\\l"));
+
+        let calls = graph.call_graph_dot();
+        assert!(calls.contains("    main -> sub_208;"));
+    }
+}
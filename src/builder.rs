@@ -0,0 +1,193 @@
+use std::str::FromStr;
+
+use fontset::{BuiltinFont, FontSet};
+use memory;
+use quirks::Quirks;
+use system::Chip8Machine;
+use vip;
+
+// The machines a program can be written for. Picking one sets the load
+// address and quirks that machine used, which can still be overridden
+// afterwards.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MachineMode {
+    Chip8,
+    CosmacVip,
+    Eti660,
+}
+
+impl FromStr for MachineMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<MachineMode, String> {
+        match name {
+            "chip8" => Ok(MachineMode::Chip8),
+            "vip" => Ok(MachineMode::CosmacVip),
+            "eti660" => Ok(MachineMode::Eti660),
+            _ => Err(format!("unknown platform {}", name)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Chip8MachineBuilder {
+    pub(crate) memory_size: usize,
+    pub(crate) load_address: u16,
+    pub(crate) stack_depth: usize,
+    pub(crate) font_address: u16,
+    pub(crate) font: FontSet,
+    pub(crate) quirks: Quirks,
+    pub(crate) mode: MachineMode,
+    pub(crate) tick_rate: u32,
+}
+
+impl Default for Chip8MachineBuilder {
+    fn default() -> Chip8MachineBuilder {
+        Chip8MachineBuilder {
+            memory_size: memory::MEMORY_SIZE,
+            load_address: 0x200,
+            stack_depth: 16,
+            font_address: 0,
+            font: FontSet::default(),
+            quirks: Quirks::default(),
+            mode: MachineMode::Chip8,
+            tick_rate: 20,
+        }
+    }
+}
+
+impl Chip8MachineBuilder {
+    pub fn new() -> Chip8MachineBuilder {
+        Chip8MachineBuilder::default()
+    }
+
+    pub fn mode(mut self, mode: MachineMode) -> Chip8MachineBuilder {
+        match mode {
+            MachineMode::Chip8 => {
+                self.load_address = 0x200;
+                self.font = FontSet::default();
+                self.quirks = Quirks::default();
+            }
+            MachineMode::CosmacVip => {
+                self.load_address = 0x200;
+                self.font = FontSet::builtin(BuiltinFont::Vip);
+                self.quirks = Quirks::cosmac_vip();
+            }
+            // The ETI-660 interpreter sits below 0x600 and programs start
+            // there, otherwise it behaves like the VIP
+            MachineMode::Eti660 => {
+                self.load_address = 0x600;
+                self.font = FontSet::builtin(BuiltinFont::Eti660);
+                self.quirks = Quirks::cosmac_vip();
+            }
+        }
+
+        self.mode = mode;
+        self
+    }
+
+    pub fn memory_size(mut self, memory_size: usize) -> Chip8MachineBuilder {
+        self.memory_size = memory_size;
+        self
+    }
+
+    pub fn load_address(mut self, load_address: u16) -> Chip8MachineBuilder {
+        self.load_address = load_address;
+        self
+    }
+
+    pub fn stack_depth(mut self, stack_depth: usize) -> Chip8MachineBuilder {
+        self.stack_depth = stack_depth;
+        self
+    }
+
+    pub fn font_address(mut self, font_address: u16) -> Chip8MachineBuilder {
+        self.font_address = font_address;
+        self
+    }
+
+    pub fn font(mut self, font: FontSet) -> Chip8MachineBuilder {
+        self.font = font;
+        self
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> Chip8MachineBuilder {
+        self.quirks = quirks;
+        self
+    }
+
+    // How many instructions run in each 60 Hz frame
+    pub fn tick_rate(mut self, tick_rate: u32) -> Chip8MachineBuilder {
+        self.tick_rate = tick_rate;
+        self
+    }
+
+    pub fn build(self) -> Chip8Machine {
+        // Addresses are 12 bits wide in instructions but I is 16 bits, so
+        // anything up to 64K can still be reached
+        if self.memory_size < 0x200 || self.memory_size > 0x10000 {
+            panic!(
+                "Memory size must be between 512 bytes and 64K, got {}",
+                self.memory_size
+            );
+        }
+        if self.load_address as usize >= self.memory_size {
+            panic!(
+                "Load address {:#x} is outside of {} bytes of memory",
+                self.load_address, self.memory_size
+            );
+        }
+        if self.font_address as usize + self.font.len() > self.memory_size {
+            panic!("Font at {:#x} does not fit in memory", self.font_address);
+        }
+        if self.mode == MachineMode::CosmacVip && self.memory_size < vip::MEMORY_SIZE {
+            panic!("COSMAC VIP mode needs 4K of memory for native SYS calls");
+        }
+        if self.stack_depth == 0 {
+            panic!("Stack depth must be at least 1");
+        }
+        if self.tick_rate == 0 {
+            panic!("Tick rate must be at least 1");
+        }
+
+        Chip8Machine::from_builder(&self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eti660_loads_at_0x600() {
+        let builder = Chip8MachineBuilder::new().mode(MachineMode::Eti660);
+        assert_eq!(0x600, builder.load_address);
+        assert_eq!(Quirks::cosmac_vip(), builder.quirks);
+    }
+
+    #[test]
+    fn settings_after_mode_override_it() {
+        let builder = Chip8MachineBuilder::new()
+            .mode(MachineMode::CosmacVip)
+            .quirks(Quirks::default());
+        assert_eq!(Quirks::default(), builder.quirks);
+    }
+
+    #[test]
+    #[should_panic]
+    fn vip_mode_needs_4k() {
+        Chip8MachineBuilder::new()
+            .mode(MachineMode::CosmacVip)
+            .memory_size(0xE00)
+            .build();
+    }
+
+    #[test]
+    #[should_panic]
+    fn load_address_outside_memory() {
+        Chip8MachineBuilder::new()
+            .memory_size(0x1000)
+            .load_address(0x1000)
+            .build();
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use builder::Chip8MachineBuilder;
+use fontset::{BuiltinFont, FontSet};
+use gif;
+use json::{self, Value};
+use octo;
+use palette::Palette;
+use quirks::Quirks;
+use system::Chip8Machine;
+
+// Octo cartridges are GIFs with the program hidden in the pixels. Each pixel
+// carries four bits of payload in the low nibble of its color index, high
+// nibble of a byte first, running through every frame in order. The payload
+// is a 32 bit big endian length followed by that many bytes of JSON holding
+// the program and the options it was saved with.
+pub struct Cartridge {
+    pub program: Vec<u8>,
+    pub quirks: Quirks,
+    pub font: Option<BuiltinFont>,
+    pub tick_rate: Option<u32>,
+    pub palette: Option<Palette>,
+}
+
+impl Cartridge {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Cartridge, String> {
+        let mut data = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|error| error.to_string())?;
+
+        Cartridge::decode(&data)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Cartridge, String> {
+        let image = gif::decode(data)?;
+        let payload = payload(&image)?;
+        let text = String::from_utf8(payload).map_err(|_| "Payload is not UTF-8".to_string())?;
+
+        Cartridge::from_json(&json::parse(&text)?)
+    }
+
+    fn from_json(payload: &Value) -> Result<Cartridge, String> {
+        let program = match payload.get("program") {
+            Some(Value::Array(bytes)) => bytes
+                .iter()
+                .map(|byte| match byte.as_number() {
+                    Some(byte) if (0.0..=255.0).contains(&byte) && byte.fract() == 0.0 => {
+                        Ok(byte as u8)
+                    }
+                    _ => Err("Program bytes must be numbers from 0 to 255".to_string()),
+                })
+                .collect::<Result<Vec<u8>, String>>()?,
+            // Octo itself saves the source rather than the assembled program
+            Some(Value::String(source)) => {
+                octo::assemble(source)
+                    .map_err(|error| error.to_string())?
+                    .bytes
+            }
+            _ => return Err("Cartridge has no program".to_string()),
+        };
+
+        let empty = Value::Object(Default::default());
+        let options = payload.get("options").unwrap_or(&empty);
+        let option = |name: &str| options.get(name).and_then(Value::as_bool).unwrap_or(false);
+
+        // Octo names its quirks after the SUPER-CHIP behaviour, so some of
+        // them switch our flags off rather than on
+        let quirks = Quirks {
+            shift_uses_vy: !option("shiftQuirks"),
+            load_store_increments_i: !option("loadStoreQuirks"),
+            jump_uses_vx: option("jumpQuirks"),
+            logic_resets_vf: option("logicQuirks"),
+            wrap_sprites: !option("clipQuirks"),
+        };
+
+        let font = match options.get("fontStyle").and_then(Value::as_str) {
+            Some("octo") => Some(BuiltinFont::Octo),
+            Some("vip") => Some(BuiltinFont::Vip),
+            Some("dream6800") => Some(BuiltinFont::Dream6800),
+            Some("eti660") => Some(BuiltinFont::Eti660),
+            Some("schip") => Some(BuiltinFont::SuperChip),
+            _ => None,
+        };
+
+        let tick_rate = options
+            .get("tickrate")
+            .and_then(Value::as_number)
+            .filter(|rate| *rate >= 1.0)
+            .map(|rate| rate as u32);
+
+        let color = |name: &str| {
+            options
+                .get(name)
+                .and_then(Value::as_str)
+                .and_then(|color| u32::from_str_radix(color.trim_start_matches('#'), 16).ok())
+        };
+        // Octo names the colors for both planes as well, but older
+        // cartridges only have the first
+        let palette = match (color("backgroundColor"), color("fillColor")) {
+            (Some(background), Some(fill)) => match (color("fillColor2"), color("blendColor")) {
+                (Some(fill2), Some(blend)) => Some(Palette {
+                    colors: [background, fill, fill2, blend],
+                }),
+                _ => Some(Palette::two_color(background, fill)),
+            },
+            _ => None,
+        };
+
+        Ok(Cartridge {
+            program,
+            quirks,
+            font,
+            tick_rate,
+            palette,
+        })
+    }
+
+    pub fn configure(&self, mut builder: Chip8MachineBuilder) -> Chip8MachineBuilder {
+        builder = builder.quirks(self.quirks);
+        if let Some(font) = self.font {
+            builder = builder.font(FontSet::builtin(font));
+        }
+        if let Some(tick_rate) = self.tick_rate {
+            builder = builder.tick_rate(tick_rate);
+        }
+
+        builder
+    }
+
+    // Builds a machine with the cartridge's settings and its program
+    // loaded. Fails if the program doesn't fit in memory
+    pub fn build(&self) -> Result<Chip8Machine, String> {
+        let mut machine = self.configure(Chip8MachineBuilder::new()).build();
+        machine.load_rom(&self.program)?;
+
+        Ok(machine)
+    }
+}
+
+fn payload(image: &gif::Gif) -> Result<Vec<u8>, String> {
+    let nibbles: Vec<u8> = image
+        .frames
+        .iter()
+        .flat_map(|frame| frame.indices.iter().map(|index| index & 0xF))
+        .collect();
+    let bytes: Vec<u8> = nibbles
+        .chunks(2)
+        .filter(|pair| pair.len() == 2)
+        .map(|pair| pair[0] << 4 | pair[1])
+        .collect();
+
+    if bytes.len() < 4 {
+        return Err("Image is too small to hold a cartridge".to_string());
+    }
+
+    let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    if length > bytes.len() - 4 {
+        return Err(format!(
+            "Cartridge says it holds {} bytes but the image only has room for {}",
+            length,
+            bytes.len() - 4
+        ));
+    }
+
+    Ok(bytes[4..4 + length].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gif::tests::encode_grey;
+
+    // Hides the payload in a 32x16 label, spilling into extra frames as
+    // needed, with a label pattern in the high nibbles
+    fn cartridge(json: &str) -> Vec<u8> {
+        let mut bytes = (json.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(json.as_bytes());
+
+        let mut nibbles: Vec<u8> = bytes
+            .iter()
+            .flat_map(|byte| vec![byte >> 4, byte & 0xF])
+            .collect();
+        let frame_size = 32 * 16;
+        let frame_count = nibbles.len().div_ceil(frame_size);
+        nibbles.resize(frame_count * frame_size, 0);
+
+        let frames: Vec<Vec<u8>> = nibbles
+            .chunks(frame_size)
+            .map(|frame| {
+                frame
+                    .iter()
+                    .enumerate()
+                    .map(|(pixel, nibble)| ((pixel % 3) as u8) << 4 | nibble)
+                    .collect()
+            })
+            .collect();
+
+        encode_grey(32, 16, &frames)
+    }
+
+    #[test]
+    fn program_and_options() {
+        let gif = cartridge(
+            r##"{"program": [96, 5, 112, 1], "options": {"tickrate": 20,
+                "shiftQuirks": true, "loadStoreQuirks": false, "jumpQuirks": true,
+                "logicQuirks": false, "clipQuirks": true, "fontStyle": "vip",
+                "backgroundColor": "#000000", "fillColor": "#FFCC00"}}"##,
+        );
+        let cartridge = Cartridge::decode(&gif).unwrap();
+
+        assert_eq!(vec![0x60, 0x05, 0x70, 0x01], cartridge.program);
+        assert_eq!(
+            Quirks {
+                shift_uses_vy: false,
+                load_store_increments_i: true,
+                jump_uses_vx: true,
+                logic_resets_vf: false,
+                wrap_sprites: false,
+            },
+            cartridge.quirks
+        );
+        assert_eq!(Some(BuiltinFont::Vip), cartridge.font);
+        assert_eq!(Some(20), cartridge.tick_rate);
+        assert_eq!(
+            Some(Palette::two_color(0x000000, 0xFFCC00)),
+            cartridge.palette
+        );
+    }
+
+    #[test]
+    fn payload_spanning_frames() {
+        let program: Vec<String> = (0..400).map(|index| (index % 256).to_string()).collect();
+        let json = format!("{{\"program\": [{}]}}", program.join(","));
+        let cartridge = Cartridge::decode(&cartridge(&json)).unwrap();
+
+        assert_eq!(400, cartridge.program.len());
+        assert_eq!(255, cartridge.program[255]);
+    }
+
+    #[test]
+    fn configures_the_builder() {
+        let gif = cartridge(r#"{"program": [96, 42], "options": {"fontStyle": "schip"}}"#);
+        let builder = Cartridge::decode(&gif)
+            .unwrap()
+            .configure(Chip8MachineBuilder::new());
+
+        assert!(builder.quirks.shift_uses_vy);
+        assert!(builder.quirks.load_store_increments_i);
+        assert!(!builder.quirks.logic_resets_vf);
+        assert_eq!(180, builder.font.len());
+    }
+
+    #[test]
+    fn programs_too_big_for_memory() {
+        let program = vec!["0"; 0xE01].join(", ");
+        let gif = cartridge(&format!(r#"{{"program": [{}], "options": {{}}}}"#, program));
+
+        assert!(Cartridge::decode(&gif).unwrap().build().is_err());
+    }
+
+    #[test]
+    fn octo_source_is_assembled() {
+        let gif = cartridge(r#"{"program": ": main\n  v0 := 42\n", "options": {}}"#);
+        assert_eq!(vec![0x60, 42], Cartridge::decode(&gif).unwrap().program);
+
+        let gif = cartridge(r#"{"program": ": main loop", "options": {}}"#);
+        assert!(Cartridge::decode(&gif).is_err());
+    }
+
+    #[test]
+    fn length_past_the_image() {
+        // A length of 0x7FFFFFFF in a four pixel image
+        let gif = encode_grey(2, 2, &[vec![0x7, 0xF, 0xF, 0xF]]);
+        assert!(Cartridge::decode(&gif).is_err());
+    }
+}
@@ -0,0 +1,494 @@
+// A core for the RCA CDP1802, the CPU inside the COSMAC VIP. The original
+// CHIP-8 interpreter was written in 1802 machine code and SYS (0NNN) calls
+// drop out of the interpreter into native 1802 subroutines, so this is what
+// those subroutines run on.
+
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    // N lines 1-7 select the port for OUT and INP
+    fn output(&mut self, _port: u8, _value: u8) {}
+
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    // EF1-EF4 flag lines, tested by the B1-B4 and BN1-BN4 branches
+    fn flag(&mut self, _line: u8) -> bool {
+        false
+    }
+}
+
+#[derive(Debug)]
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    pub d: u8,
+    pub df: bool,
+    pub p: u8,
+    pub x: u8,
+    pub t: u8,
+    pub ie: bool,
+    pub q: bool,
+    pub idle: bool,
+    pub cycles: u64,
+}
+
+impl Default for Cdp1802 {
+    // This is the state after a hardware reset: P, X and R0 are cleared and
+    // interrupts are enabled
+    fn default() -> Cdp1802 {
+        Cdp1802 {
+            r: [0; 16],
+            d: 0,
+            df: false,
+            p: 0,
+            x: 0,
+            t: 0,
+            ie: true,
+            q: false,
+            idle: false,
+            cycles: 0,
+        }
+    }
+}
+
+impl Cdp1802 {
+    // Raise the interrupt line. Returns whether the interrupt was taken
+    pub fn interrupt(&mut self) -> bool {
+        if !self.ie {
+            return false;
+        }
+
+        self.t = (self.x << 4) | self.p;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+
+        true
+    }
+
+    // A DMA out cycle as done by the 1861 video chip: the byte at R0 goes out
+    // on the bus and R0 is incremented
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.cycles += 1;
+        self.idle = false;
+
+        value
+    }
+
+    // Execute one instruction and return the number of machine cycles it took
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.idle {
+            self.cycles += 1;
+            return 1;
+        }
+
+        let opcode = self.fetch(bus);
+        let n = (opcode & 0xF) as usize;
+        let mut cycles = 2;
+
+        match opcode >> 4 {
+            0x0 => {
+                if n == 0 {
+                    // IDL
+                    self.idle = true;
+                } else {
+                    // LDN
+                    self.d = bus.read(self.r[n]);
+                }
+            }
+            // INC
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            // DEC
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+            0x3 => {
+                let condition = self.short_condition(bus, opcode);
+                self.branch_short(bus, condition);
+            }
+            // LDA
+            0x4 => {
+                self.d = bus.read(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            // STR
+            0x5 => bus.write(self.r[n], self.d),
+            0x6 => self.run_io(bus, opcode),
+            0x7 => self.run_control(bus, opcode),
+            // GLO
+            0x8 => self.d = self.r[n] as u8,
+            // GHI
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            // PLO
+            0xA => self.r[n] = (self.r[n] & 0xFF00) | self.d as u16,
+            // PHI
+            0xB => self.r[n] = (self.r[n] & 0x00FF) | (self.d as u16) << 8,
+            0xC => {
+                self.run_long(bus, opcode);
+                cycles = 3;
+            }
+            // SEP
+            0xD => self.p = n as u8,
+            // SEX
+            0xE => self.x = n as u8,
+            _ => self.run_alu(bus, opcode),
+        }
+
+        self.cycles += cycles as u64;
+
+        cycles
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let p = self.p as usize;
+        let value = bus.read(self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+
+        value
+    }
+
+    fn read_x<B: Bus>(&self, bus: &mut B) -> u8 {
+        bus.read(self.r[self.x as usize])
+    }
+
+    fn short_condition<B: Bus>(&self, bus: &mut B, opcode: u8) -> bool {
+        let condition = match opcode & 0x7 {
+            0x0 => true,
+            0x1 => self.q,
+            0x2 => self.d == 0,
+            0x3 => self.df,
+            line => bus.flag(line - 3),
+        };
+
+        // The upper half of the row are the inverted branches, so 0x38 (SKP)
+        // becomes a branch that is never taken
+        if opcode & 0x8 == 0x8 {
+            !condition
+        } else {
+            condition
+        }
+    }
+
+    fn branch_short<B: Bus>(&mut self, bus: &mut B, condition: bool) {
+        let p = self.p as usize;
+
+        if condition {
+            let target = bus.read(self.r[p]);
+            self.r[p] = (self.r[p] & 0xFF00) | target as u16;
+        } else {
+            self.r[p] = self.r[p].wrapping_add(1);
+        }
+    }
+
+    fn run_long<B: Bus>(&mut self, bus: &mut B, opcode: u8) {
+        let p = self.p as usize;
+
+        let (condition, is_skip) = match opcode & 0xF {
+            // LBR, LBQ, LBZ, LBDF
+            0x0 => (true, false),
+            0x1 => (self.q, false),
+            0x2 => (self.d == 0, false),
+            0x3 => (self.df, false),
+            // NOP
+            0x4 => (false, true),
+            // LSNQ, LSNZ, LSNF
+            0x5 => (!self.q, true),
+            0x6 => (self.d != 0, true),
+            0x7 => (!self.df, true),
+            // LSKP
+            0x8 => (true, true),
+            // LBNQ, LBNZ, LBNF
+            0x9 => (!self.q, false),
+            0xA => (self.d != 0, false),
+            0xB => (!self.df, false),
+            // LSIE, LSQ, LSZ, LSDF
+            0xC => (self.ie, true),
+            0xD => (self.q, true),
+            0xE => (self.d == 0, true),
+            _ => (self.df, true),
+        };
+
+        if is_skip {
+            if condition {
+                self.r[p] = self.r[p].wrapping_add(2);
+            }
+        } else if condition {
+            let high = bus.read(self.r[p]) as u16;
+            let low = bus.read(self.r[p].wrapping_add(1)) as u16;
+            self.r[p] = high << 8 | low;
+        } else {
+            self.r[p] = self.r[p].wrapping_add(2);
+        }
+    }
+
+    fn run_io<B: Bus>(&mut self, bus: &mut B, opcode: u8) {
+        let x = self.x as usize;
+
+        match opcode & 0xF {
+            // IRX
+            0x0 => self.r[x] = self.r[x].wrapping_add(1),
+            // OUT 1-7
+            port @ 0x1..=0x7 => {
+                let value = bus.read(self.r[x]);
+                bus.output(port, value);
+                self.r[x] = self.r[x].wrapping_add(1);
+            }
+            // 0x68 is not an instruction on the 1802
+            0x8 => {}
+            // INP 1-7
+            port => {
+                let value = bus.input(port - 8);
+                bus.write(self.r[x], value);
+                self.d = value;
+            }
+        }
+    }
+
+    fn run_control<B: Bus>(&mut self, bus: &mut B, opcode: u8) {
+        let x = self.x as usize;
+
+        match opcode & 0xF {
+            // RET, DIS
+            0x0 | 0x1 => {
+                let value = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+                self.x = value >> 4;
+                self.p = value & 0xF;
+                self.ie = opcode == 0x70;
+            }
+            // LDXA
+            0x2 => {
+                self.d = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+            }
+            // STXD
+            0x3 => {
+                bus.write(self.r[x], self.d);
+                self.r[x] = self.r[x].wrapping_sub(1);
+            }
+            // ADC
+            0x4 => {
+                let value = self.read_x(bus);
+                self.add(value, self.df);
+            }
+            // SDB
+            0x5 => {
+                let value = self.read_x(bus);
+                self.subtract(value, self.d, self.df);
+            }
+            // SHRC
+            0x6 => {
+                let carry = self.d & 0x1 == 0x1;
+                self.d = (self.d >> 1) | if self.df { 0x80 } else { 0 };
+                self.df = carry;
+            }
+            // SMB
+            0x7 => {
+                let value = self.read_x(bus);
+                self.subtract(self.d, value, self.df);
+            }
+            // SAV
+            0x8 => bus.write(self.r[x], self.t),
+            // MARK
+            0x9 => {
+                self.t = (self.x << 4) | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            // REQ, SEQ
+            0xA => self.q = false,
+            0xB => self.q = true,
+            // ADCI
+            0xC => {
+                let value = self.fetch(bus);
+                self.add(value, self.df);
+            }
+            // SDBI
+            0xD => {
+                let value = self.fetch(bus);
+                self.subtract(value, self.d, self.df);
+            }
+            // SHLC
+            0xE => {
+                let carry = self.d & 0x80 == 0x80;
+                self.d = (self.d << 1) | self.df as u8;
+                self.df = carry;
+            }
+            // SMBI
+            _ => {
+                let value = self.fetch(bus);
+                self.subtract(self.d, value, self.df);
+            }
+        }
+    }
+
+    fn run_alu<B: Bus>(&mut self, bus: &mut B, opcode: u8) {
+        // The low three bits pick the operation, bit 3 picks between M(R(X))
+        // and an immediate byte, except for F0 (LDX) and F8 (LDI)
+        let value = if opcode & 0x8 == 0x8 {
+            self.fetch(bus)
+        } else {
+            self.read_x(bus)
+        };
+
+        match opcode & 0x7 {
+            // LDX, LDI
+            0x0 => self.d = value,
+            // OR, ORI
+            0x1 => self.d |= value,
+            // AND, ANI
+            0x2 => self.d &= value,
+            // XOR, XRI
+            0x3 => self.d ^= value,
+            // ADD, ADI
+            0x4 => self.add(value, false),
+            // SD, SDI
+            0x5 => self.subtract(value, self.d, true),
+            // SHR and SHL ignore the fetched byte, and they don't consume an
+            // immediate either so the fetch above is undone for SHL
+            0x6 => {
+                if opcode == 0xFE {
+                    let p = self.p as usize;
+                    self.r[p] = self.r[p].wrapping_sub(1);
+                    self.df = self.d & 0x80 == 0x80;
+                    self.d <<= 1;
+                } else {
+                    self.df = self.d & 0x1 == 0x1;
+                    self.d >>= 1;
+                }
+            }
+            // SM, SMI
+            _ => self.subtract(self.d, value, true),
+        }
+    }
+
+    fn add(&mut self, value: u8, carry: bool) {
+        let result = self.d as u16 + value as u16 + carry as u16;
+        self.d = result as u8;
+        self.df = result > 0xFF;
+    }
+
+    // DF is set when there was no borrow
+    fn subtract(&mut self, minuend: u8, subtrahend: u8, no_borrow: bool) {
+        let borrow = !no_borrow as i16;
+        let result = minuend as i16 - subtrahend as i16 - borrow;
+        self.d = result as u8;
+        self.df = result >= 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Ram([u8; 256]);
+
+    impl Bus for Ram {
+        fn read(&mut self, address: u16) -> u8 {
+            self.0[address as usize & 0xFF]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.0[address as usize & 0xFF] = value;
+        }
+    }
+
+    fn run(program: &[u8], steps: usize) -> (Cdp1802, Ram) {
+        let mut ram = Ram([0; 256]);
+        ram.0[..program.len()].copy_from_slice(program);
+
+        let mut cpu = Cdp1802::default();
+        for _ in 0..steps {
+            cpu.step(&mut ram);
+        }
+
+        (cpu, ram)
+    }
+
+    fn pc(cpu: &Cdp1802) -> u16 {
+        cpu.r[cpu.p as usize]
+    }
+
+    #[test]
+    fn ldi_plo_phi() {
+        // LDI 0x12; PHI R5; LDI 0x34; PLO R5
+        let (cpu, _) = run(&[0xF8, 0x12, 0xB5, 0xF8, 0x34, 0xA5], 4);
+        assert_eq!(0x1234, cpu.r[5]);
+    }
+
+    #[test]
+    fn add_sets_carry() {
+        // LDI 0xF0; ADI 0x20
+        let (cpu, _) = run(&[0xF8, 0xF0, 0xFC, 0x20], 2);
+        assert_eq!(0x10, cpu.d);
+        assert!(cpu.df);
+    }
+
+    #[test]
+    fn subtract_borrow() {
+        // LDI 0x10; SMI 0x20
+        let (cpu, _) = run(&[0xF8, 0x10, 0xFF, 0x20], 2);
+        assert_eq!(0xF0, cpu.d);
+        assert!(!cpu.df);
+    }
+
+    #[test]
+    fn shl_does_not_consume_immediate() {
+        // LDI 0x81; SHL; LDI 0x07
+        let (cpu, _) = run(&[0xF8, 0x81, 0xFE, 0xF8, 0x07], 3);
+        assert_eq!(0x07, cpu.d);
+        assert!(cpu.df);
+        assert_eq!(5, pc(&cpu));
+    }
+
+    #[test]
+    fn short_branch_on_zero() {
+        // LDI 0x00; BZ 0x10
+        let (cpu, _) = run(&[0xF8, 0x00, 0x32, 0x10], 2);
+        assert_eq!(0x10, pc(&cpu));
+    }
+
+    #[test]
+    fn long_branch() {
+        // LBR 0x0080
+        let (cpu, _) = run(&[0xC0, 0x00, 0x80], 1);
+        assert_eq!(0x80, pc(&cpu));
+        assert_eq!(3, cpu.cycles);
+    }
+
+    #[test]
+    fn sep_switches_program_counter() {
+        // LDI 0x40; PLO R3; SEP R3
+        let (cpu, _) = run(&[0xF8, 0x40, 0xA3, 0xD3], 3);
+        assert_eq!(3, cpu.p);
+        assert_eq!(0x40, pc(&cpu));
+    }
+
+    #[test]
+    fn stxd_and_ldxa() {
+        // LDI 0x80; PLO R2; SEX R2; LDI 0x55; STXD; IRX; LDXA
+        let (cpu, ram) = run(&[0xF8, 0x80, 0xA2, 0xE2, 0xF8, 0x55, 0x73, 0x60, 0x72], 7);
+        assert_eq!(0x55, ram.0[0x80]);
+        assert_eq!(0x55, cpu.d);
+        assert_eq!(0x81, cpu.r[2]);
+    }
+
+    #[test]
+    fn interrupt_saves_x_and_p() {
+        let mut cpu = Cdp1802 {
+            x: 3,
+            p: 4,
+            ..Cdp1802::default()
+        };
+
+        assert!(cpu.interrupt());
+        assert_eq!(0x34, cpu.t);
+        assert_eq!(1, cpu.p);
+        assert_eq!(2, cpu.x);
+        assert!(!cpu.interrupt());
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use analysis::ControlFlowGraph;
+use disassembler;
+use symbols::Symbols;
+
+// Which instructions ran and how often, and for each skip how often it
+// skipped and how often it fell through to the next instruction
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Coverage {
+    pub executed: BTreeMap<u16, u64>,
+    pub branches: BTreeMap<u16, (u64, u64)>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub fn record(&mut self, address: u16) {
+        *self.executed.entry(address).or_insert(0) += 1;
+    }
+
+    pub fn record_branch(&mut self, address: u16, skipped: bool) {
+        let branch = self.branches.entry(address).or_insert((0, 0));
+        if skipped {
+            branch.0 += 1;
+        } else {
+            branch.1 += 1;
+        }
+    }
+
+    // The disassembly with execution counts down the side, gcov style.
+    // Code that never ran is marked ##### and data -. Skips also show how
+    // many times they skipped / fell through
+    pub fn annotate(&self, program: &[u8], load_address: u16, symbols: &Symbols) -> String {
+        let code = self.code(program, load_address);
+        let mut listing = String::new();
+
+        for (address, line) in disassembler::lines(program, load_address, symbols) {
+            let count = match address {
+                None => String::new(),
+                Some(address) => match self.executed.get(&address) {
+                    Some(count) => count.to_string(),
+                    None if code.contains(&address) => "#####".to_string(),
+                    None => "-".to_string(),
+                },
+            };
+            let branch = match address.and_then(|address| self.branches.get(&address)) {
+                Some(&(skipped, fell_through)) => format!("{}/{}", skipped, fell_through),
+                None => String::new(),
+            };
+
+            writeln!(listing, "{:>9} {:<9}|{}", count, branch, line).unwrap();
+        }
+
+        listing
+    }
+
+    // Coverage in LCOV's tracefile format. With a source map, lines are the
+    // source lines each address came from in the source file the symbols
+    // name. Otherwise lines are those of the annotated disassembly, which
+    // is what `name` should be a file of.
+    pub fn lcov(&self, program: &[u8], load_address: u16, symbols: &Symbols, name: &str) -> String {
+        let code = self.code(program, load_address);
+
+        let lines: BTreeMap<u16, usize> = if symbols.lines.is_empty() {
+            disassembler::lines(program, load_address, symbols)
+                .into_iter()
+                .enumerate()
+                .filter_map(|(index, (address, _))| address.map(|address| (address, index + 1)))
+                .collect()
+        } else {
+            symbols
+                .lines
+                .iter()
+                .map(|(address, &(line, _))| (*address, line))
+                .collect()
+        };
+        let source = match symbols.source {
+            Some(ref source) if !symbols.lines.is_empty() => source.as_str(),
+            _ => name,
+        };
+
+        // A line counts as run as often as the busiest instruction on it
+        let mut hits: BTreeMap<usize, u64> = BTreeMap::new();
+        for address in code.iter() {
+            if let Some(&line) = lines.get(address) {
+                let count = self.executed.get(address).cloned().unwrap_or(0);
+                let hit = hits.entry(line).or_insert(0);
+                *hit = (*hit).max(count);
+            }
+        }
+
+        let mut lcov = String::new();
+        writeln!(lcov, "TN:").unwrap();
+        writeln!(lcov, "SF:{}", source).unwrap();
+
+        let mut branches_found = 0;
+        let mut branches_hit = 0;
+        for (address, instruction) in self.skips(program, load_address, &code) {
+            let line = match lines.get(&address) {
+                Some(&line) => line,
+                None => continue,
+            };
+
+            let counts = match self.branches.get(&address) {
+                Some(&(skipped, fell_through)) => [skipped.to_string(), fell_through.to_string()],
+                None => ["-".to_string(), "-".to_string()],
+            };
+            for (branch, count) in counts.iter().enumerate() {
+                writeln!(lcov, "BRDA:{},{},{},{}", line, instruction, branch, count).unwrap();
+                branches_found += 1;
+                if count != "-" && count != "0" {
+                    branches_hit += 1;
+                }
+            }
+        }
+        writeln!(lcov, "BRF:{}", branches_found).unwrap();
+        writeln!(lcov, "BRH:{}", branches_hit).unwrap();
+
+        for (line, count) in hits.iter() {
+            writeln!(lcov, "DA:{},{}", line, count).unwrap();
+        }
+        writeln!(lcov, "LF:{}", hits.len()).unwrap();
+        writeln!(
+            lcov,
+            "LH:{}",
+            hits.values().filter(|&&count| count > 0).count()
+        )
+        .unwrap();
+        writeln!(lcov, "end_of_record").unwrap();
+
+        lcov
+    }
+
+    // Everything the control flow graph finds, plus whatever actually ran
+    // since computed jumps can reach code the graph can't see. The zero word
+    // run() stops at never executes, so it doesn't count
+    fn code(&self, program: &[u8], load_address: u16) -> BTreeSet<u16> {
+        let graph = ControlFlowGraph::analyze(program, load_address);
+        let end = load_address as usize + program.len();
+
+        (load_address..end as u16)
+            .filter(|address| graph.is_code(*address) && graph.word(*address) != 0)
+            .chain(self.executed.keys().cloned())
+            .collect()
+    }
+
+    // Each skip in the code, numbered in address order for LCOV's block
+    // field
+    fn skips(&self, program: &[u8], load_address: u16, code: &BTreeSet<u16>) -> Vec<(u16, usize)> {
+        let graph = ControlFlowGraph::analyze(program, load_address);
+
+        code.iter()
+            .filter(|address| {
+                graph
+                    .instruction(**address)
+                    .is_some_and(|instruction| instruction.is_skip())
+            })
+            .enumerate()
+            .map(|(index, address)| (*address, index))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octo::{assemble, Assembly};
+    use system::Chip8Machine;
+
+    // v0 counts up to 3. The first skip is taken once and falls through
+    // twice, and the second always skips so unused never runs
+    const PROGRAM: &str = ": main\n  v0 += 1\n  if v0 != 3 then jump main\n  if v0 == 9 then unused\n: halt jump halt\n: unused\n  v1 := 1\n  return";
+
+    fn covered(steps: usize) -> (Coverage, Assembly) {
+        let assembly = assemble(PROGRAM).unwrap();
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&assembly.bytes);
+        machine.set_coverage(true);
+        for _ in 0..steps {
+            machine.execute();
+        }
+
+        (machine.coverage().unwrap().clone(), assembly)
+    }
+
+    #[test]
+    fn records_addresses_and_branches() {
+        let (coverage, _) = covered(10);
+
+        assert_eq!(Some(&3), coverage.executed.get(&0x200));
+        assert_eq!(Some(&2), coverage.executed.get(&0x204));
+        assert_eq!(None, coverage.executed.get(&0x208));
+        assert_eq!(Some(&(1, 2)), coverage.branches.get(&0x202));
+        assert_eq!(Some(&(1, 0)), coverage.branches.get(&0x206));
+    }
+
+    #[test]
+    fn annotated_disassembly() {
+        let (coverage, assembly) = covered(10);
+        let symbols = Symbols::from_assembly(&assembly, None);
+        let listing = coverage.annotate(&assembly.bytes, 0x200, &symbols);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(format!("{:19}|: main", ""), lines[0]);
+        assert!(lines[2].starts_with("        3 1/2      |    if v0 != 3 then"));
+        assert!(lines[9].starts_with("    #####          |    v1 := 1"));
+    }
+
+    #[test]
+    fn lcov_through_the_source_map() {
+        let (coverage, assembly) = covered(10);
+        let symbols = Symbols::from_assembly(&assembly, Some("game.8o"));
+        let lcov = coverage.lcov(&assembly.bytes, 0x200, &symbols, "game.ch8");
+
+        assert_eq!(
+            "TN:\nSF:game.8o\nBRDA:3,0,0,1\nBRDA:3,0,1,2\nBRDA:4,1,0,1\nBRDA:4,1,1,0\n\
+             BRF:4\nBRH:3\nDA:2,3\nDA:3,3\nDA:4,1\nDA:5,1\nDA:7,0\nDA:8,0\nLF:6\nLH:4\n\
+             end_of_record\n",
+            lcov
+        );
+    }
+
+    #[test]
+    fn lcov_without_a_source_map() {
+        let (coverage, assembly) = covered(10);
+        let lcov = coverage.lcov(&assembly.bytes, 0x200, &Symbols::default(), "game.lst");
+
+        assert!(lcov.starts_with("TN:\nSF:game.lst\nBRDA:2,0,0,1\n"));
+        assert!(lcov.contains("DA:1,3\nDA:2,3\nDA:3,2\nDA:4,1\nDA:5,0\nDA:6,1\nDA:7,0\n"));
+    }
+}
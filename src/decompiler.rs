@@ -0,0 +1,542 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use analysis::ControlFlowGraph;
+use instructions::Instruction;
+use quirks::Quirks;
+use registers::Register;
+
+// Turns recovered control flow back into something like the structured code
+// a program was written as. CHIP-8 only has conditional skips and jumps, so
+// the usual shapes are matched:
+//
+//   skip if C; JP else; <then>; JP end; else: <else>; end:
+//     becomes if (C) { <then> } else { <else> }
+//   <body>; JP head at the bottom of a run starting at head
+//     becomes loop { <body> }, or do { } while (...) when a skip guards
+//     the jump back
+//
+// Anything that doesn't fit falls back to goto.
+enum Statement {
+    Simple(u16, String),
+    If {
+        address: u16,
+        condition: String,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+    },
+    Loop {
+        address: u16,
+        body: Vec<Statement>,
+        // For do-while loops, the condition to keep going
+        condition: Option<String>,
+    },
+}
+
+struct Loop {
+    head: u16,
+    exit: u16,
+}
+
+struct Structurer<'a> {
+    graph: &'a ControlFlowGraph,
+    quirks: Quirks,
+    addresses: BTreeSet<u16>,
+    labels: BTreeSet<u16>,
+}
+
+// Shifts, BNNN and the register loads and stores are written out the way
+// the quirks make them behave
+pub fn decompile(graph: &ControlFlowGraph, quirks: Quirks) -> String {
+    let mut output = String::new();
+
+    for subroutine in graph.subroutines.values() {
+        let addresses: BTreeSet<u16> = subroutine
+            .blocks
+            .iter()
+            .flat_map(|start| {
+                let block = &graph.blocks[start];
+                (block.start..=block.last).step_by(2)
+            })
+            .collect();
+
+        let first = *addresses.iter().next().unwrap_or(&subroutine.entry);
+        let end = addresses.iter().next_back().map_or(first, |last| last + 2);
+
+        let mut structurer = Structurer {
+            graph,
+            quirks,
+            addresses,
+            labels: BTreeSet::new(),
+        };
+        let mut body = Vec::new();
+        if first != subroutine.entry {
+            structurer.labels.insert(subroutine.entry);
+            body.push(Statement::Simple(
+                first,
+                format!("goto {}", label(subroutine.entry)),
+            ));
+        }
+        body.extend(structurer.structure(first, end, &mut Vec::new()));
+
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        writeln!(
+            output,
+            "fn {}() {{",
+            graph.subroutine_name(subroutine.entry)
+        )
+        .unwrap();
+        structurer.render(&body, 1, &mut output);
+        writeln!(output, "}}").unwrap();
+    }
+
+    output
+}
+
+impl<'a> Structurer<'a> {
+    fn instruction(&self, address: u16) -> Option<Instruction> {
+        if self.addresses.contains(&address) {
+            self.graph.instruction(address)
+        } else {
+            None
+        }
+    }
+
+    // The last jump back to head between head and end, which makes head the
+    // top of a loop. A jump to itself is a halt rather than a loop.
+    fn back_edge(&self, head: u16, end: u16) -> Option<u16> {
+        self.addresses
+            .range(head + 1..end)
+            .rev()
+            .find(|address| self.instruction(**address) == Some(Instruction::JP(head)))
+            .cloned()
+    }
+
+    fn structure(&mut self, start: u16, end: u16, loops: &mut Vec<Loop>) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        let mut pc = start;
+
+        loop {
+            if pc >= end {
+                return statements;
+            }
+
+            // Skip over data and anything belonging to other subroutines
+            pc = match self.addresses.range(pc..end).next() {
+                Some(address) => *address,
+                None => return statements,
+            };
+
+            let current_head = loops.last().map(|current| current.head);
+            if current_head != Some(pc) || pc != start {
+                if let Some(jump) = self.back_edge(pc, end) {
+                    statements.push(self.structure_loop(pc, jump, loops));
+                    pc = jump + 2;
+                    continue;
+                }
+            }
+
+            let instruction = match self.instruction(pc) {
+                Some(instruction) => instruction,
+                None => {
+                    let word = self.graph.word(pc);
+                    statements.push(Statement::Simple(pc, format!("// {:04x}", word)));
+                    pc += 2;
+                    continue;
+                }
+            };
+
+            let condition = match skip_condition(&instruction) {
+                Some(condition) => condition,
+                None => {
+                    statements.push(Statement::Simple(
+                        pc,
+                        self.statement(pc, &instruction, loops),
+                    ));
+                    pc += 2;
+                    continue;
+                }
+            };
+
+            // A skip at the very end of a region guards something outside it
+            if pc + 2 >= end {
+                statements.push(Statement::Simple(
+                    pc,
+                    render(self.graph, self.quirks, &instruction),
+                ));
+                pc += 2;
+                continue;
+            }
+
+            // skip if C; JP t jumps over whatever runs when C holds
+            if let Some(Instruction::JP(target)) = self.instruction(pc + 2) {
+                if target > pc + 4 && target <= end {
+                    let then_end = target;
+                    let mut otherwise_end = target;
+
+                    if let Some(Instruction::JP(after)) = self.instruction(target - 2) {
+                        if after > target && after <= end && target - 2 >= pc + 4 {
+                            otherwise_end = after;
+                        }
+                    }
+
+                    let then = if otherwise_end > then_end {
+                        self.structure(pc + 4, then_end - 2, loops)
+                    } else {
+                        self.structure(pc + 4, then_end, loops)
+                    };
+                    let otherwise = self.structure(then_end, otherwise_end, loops);
+
+                    statements.push(Statement::If {
+                        address: pc,
+                        condition,
+                        then,
+                        otherwise,
+                    });
+                    pc = otherwise_end;
+                    continue;
+                }
+            }
+
+            // Otherwise only the next instruction is conditional
+            let then = match self.instruction(pc + 2) {
+                Some(next) => vec![Statement::Simple(
+                    pc + 2,
+                    self.statement(pc + 2, &next, loops),
+                )],
+                None => vec![Statement::Simple(
+                    pc + 2,
+                    format!("// {:04x}", self.graph.word(pc + 2)),
+                )],
+            };
+            statements.push(Statement::If {
+                address: pc,
+                condition: negate(&condition),
+                then,
+                otherwise: Vec::new(),
+            });
+            pc += 4;
+        }
+    }
+
+    fn structure_loop(&mut self, head: u16, jump: u16, loops: &mut Vec<Loop>) -> Statement {
+        loops.push(Loop {
+            head,
+            exit: jump + 2,
+        });
+
+        // A skip right before the jump back makes it a do-while loop
+        let guard = if jump >= head + 2 {
+            self.instruction(jump - 2).as_ref().and_then(skip_condition)
+        } else {
+            None
+        };
+
+        let statement = match guard {
+            Some(condition) => Statement::Loop {
+                address: head,
+                body: self.structure(head, jump - 2, loops),
+                condition: Some(negate(&condition)),
+            },
+            None => Statement::Loop {
+                address: head,
+                body: self.structure(head, jump, loops),
+                condition: None,
+            },
+        };
+
+        loops.pop();
+        statement
+    }
+
+    fn statement(&mut self, address: u16, instruction: &Instruction, loops: &[Loop]) -> String {
+        if let Instruction::JP(target) = *instruction {
+            if let Some(current) = loops.last() {
+                if target == current.head {
+                    return "continue".to_string();
+                }
+                if target == current.exit {
+                    return "break".to_string();
+                }
+            }
+            if target == address {
+                return "halt()".to_string();
+            }
+
+            self.labels.insert(target);
+            return format!("goto {}", label(target));
+        }
+
+        render(self.graph, self.quirks, instruction)
+    }
+
+    fn render(&self, statements: &[Statement], depth: usize, output: &mut String) {
+        let indent = "    ".repeat(depth);
+
+        for statement in statements {
+            let address = match *statement {
+                Statement::Simple(address, _)
+                | Statement::If { address, .. }
+                | Statement::Loop { address, .. } => address,
+            };
+            if self.labels.contains(&address) {
+                writeln!(output, "{}{}:", indent, label(address)).unwrap();
+            }
+
+            match *statement {
+                Statement::Simple(_, ref text) => writeln!(output, "{}{}", indent, text).unwrap(),
+                Statement::If {
+                    ref condition,
+                    ref then,
+                    ref otherwise,
+                    ..
+                } => {
+                    writeln!(output, "{}if ({}) {{", indent, condition).unwrap();
+                    self.render(then, depth + 1, output);
+                    if !otherwise.is_empty() {
+                        writeln!(output, "{}}} else {{", indent).unwrap();
+                        self.render(otherwise, depth + 1, output);
+                    }
+                    writeln!(output, "{}}}", indent).unwrap();
+                }
+                Statement::Loop {
+                    ref body,
+                    ref condition,
+                    ..
+                } => {
+                    match *condition {
+                        Some(_) => writeln!(output, "{}do {{", indent).unwrap(),
+                        None => writeln!(output, "{}loop {{", indent).unwrap(),
+                    }
+                    self.render(body, depth + 1, output);
+                    match *condition {
+                        Some(ref condition) => {
+                            writeln!(output, "{}}} while ({})", indent, condition).unwrap()
+                        }
+                        None => writeln!(output, "{}}}", indent).unwrap(),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn label(address: u16) -> String {
+    format!("label_{:03x}", address)
+}
+
+// Registers become variables, with VF named for what it's used for
+pub fn variable(register: Register) -> String {
+    match register {
+        Register::VF => "flag".to_string(),
+        other => format!("{:?}", other).to_lowercase(),
+    }
+}
+
+// The condition under which a skip instruction skips
+fn skip_condition(instruction: &Instruction) -> Option<String> {
+    match *instruction {
+        Instruction::SEC(x, k) => Some(format!("{} == {}", variable(x), k)),
+        Instruction::SNEC(x, k) => Some(format!("{} != {}", variable(x), k)),
+        Instruction::SER(x, y) => Some(format!("{} == {}", variable(x), variable(y))),
+        Instruction::SNE(x, y) => Some(format!("{} != {}", variable(x), variable(y))),
+        Instruction::SKP(x) => Some(format!("key_down({})", variable(x))),
+        Instruction::SKNP(x) => Some(format!("!key_down({})", variable(x))),
+        _ => None,
+    }
+}
+
+fn negate(condition: &str) -> String {
+    if condition.contains(" == ") {
+        condition.replacen(" == ", " != ", 1)
+    } else if condition.contains(" != ") {
+        condition.replacen(" != ", " == ", 1)
+    } else if let Some(inner) = condition.strip_prefix('!') {
+        inner.to_string()
+    } else {
+        format!("!{}", condition)
+    }
+}
+
+// How far FX55 and FX65 move I, if they do
+fn i_increment(quirks: Quirks, last: Register) -> String {
+    if quirks.load_store_increments_i {
+        format!("; i += {}", last as u8 + 1)
+    } else {
+        String::new()
+    }
+}
+
+fn render(graph: &ControlFlowGraph, quirks: Quirks, instruction: &Instruction) -> String {
+    let v = |register: Register| variable(register);
+
+    match *instruction {
+        Instruction::SYS(address) => format!("machine_code({:#05x})", address),
+        Instruction::CLS => "clear()".to_string(),
+        Instruction::RET => "return".to_string(),
+        Instruction::JP(target) => format!("goto {}", label(target)),
+        Instruction::CALL(target) => format!("{}()", graph.subroutine_name(target)),
+        Instruction::LDC(x, k) => format!("{} = {}", v(x), k),
+        Instruction::ADDC(x, k) => format!("{} += {}", v(x), k),
+        Instruction::LDR(x, y) => format!("{} = {}", v(x), v(y)),
+        Instruction::OR(x, y) => format!("{} |= {}", v(x), v(y)),
+        Instruction::AND(x, y) => format!("{} &= {}", v(x), v(y)),
+        Instruction::XOR(x, y) => format!("{} ^= {}", v(x), v(y)),
+        Instruction::ADDR(x, y) => format!("{} += {}", v(x), v(y)),
+        Instruction::SUB(x, y) => format!("{} -= {}", v(x), v(y)),
+        Instruction::SUBN(x, y) => format!("{} = {} - {}", v(x), v(y), v(x)),
+        Instruction::SHR(x, y) if x == y || !quirks.shift_uses_vy => format!("{} >>= 1", v(x)),
+        Instruction::SHR(x, y) => format!("{} = {} >> 1", v(x), v(y)),
+        Instruction::SHL(x, y) if x == y || !quirks.shift_uses_vy => format!("{} <<= 1", v(x)),
+        Instruction::SHL(x, y) => format!("{} = {} << 1", v(x), v(y)),
+        Instruction::LDI(address) => format!("i = {:#05x}", address),
+        Instruction::JPA(address) if quirks.jump_uses_vx => format!(
+            "jump({:#05x} + {})",
+            address,
+            v(Register::new((address >> 8) as u8 & 0xF))
+        ),
+        Instruction::JPA(address) => format!("jump({:#05x} + v0)", address),
+        Instruction::RND(x, k) => format!("{} = random() & {:#04x}", v(x), k),
+        Instruction::DRW(x, y, rows) => format!("flag = draw({}, {}, {})", v(x), v(y), rows),
+        Instruction::LDRD(x) => format!("{} = delay", v(x)),
+        Instruction::LDVK(x) => format!("{} = wait_key()", v(x)),
+        Instruction::LDDR(x) => format!("delay = {}", v(x)),
+        Instruction::LDSR(x) => format!("sound = {}", v(x)),
+        Instruction::ADDI(x) => format!("i += {}", v(x)),
+        Instruction::LDIR(x) => format!("i = digit({})", v(x)),
+        Instruction::LDIH(x) => format!("i = big_digit({})", v(x)),
+        Instruction::LDBR(x) => format!("bcd({})", v(x)),
+        Instruction::LDRS(x) => format!("save(v0..={}){}", v(x), i_increment(quirks, x)),
+        Instruction::RDRS(x) => format!("load(v0..={}){}", v(x), i_increment(quirks, x)),
+        // Skips are only rendered as conditions
+        Instruction::SEC(_, _)
+        | Instruction::SNEC(_, _)
+        | Instruction::SER(_, _)
+        | Instruction::SNE(_, _)
+        | Instruction::SKP(_)
+        | Instruction::SKNP(_) => match skip_condition(instruction) {
+            Some(condition) => format!("skip_if({})", condition),
+            None => unreachable!(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompile_program(program: &[u8]) -> String {
+        decompile(
+            &ControlFlowGraph::analyze(program, 0x200),
+            Quirks::default(),
+        )
+    }
+
+    #[test]
+    fn straight_line_and_subroutines() {
+        // CALL 206; LD V1, 3; JP 204; ADD V0, V1; RET
+        let program = [0x22, 0x06, 0x61, 0x03, 0x12, 0x04, 0x80, 0x14, 0x00, 0xEE];
+
+        assert_eq!(
+            "fn main() {\n    sub_206()\n    v1 = 3\n    halt()\n}\n\n\
+             fn sub_206() {\n    v0 += v1\n    return\n}\n",
+            decompile_program(&program)
+        );
+    }
+
+    #[test]
+    fn if_else() {
+        // 200: SE V0, 1     skip the JP when v0 == 1
+        // 202: JP 20A
+        // 204: LD V1, 1
+        // 206: LD V2, 2
+        // 208: JP 20C
+        // 20A: LD V1, 2
+        // 20C: CLS
+        let program = [
+            0x30, 0x01, 0x12, 0x0A, 0x61, 0x01, 0x62, 0x02, 0x12, 0x0C, 0x61, 0x02, 0x00, 0xE0,
+        ];
+
+        assert_eq!(
+            "fn main() {\n    if (v0 == 1) {\n        v1 = 1\n        v2 = 2\n    } else {\n        v1 = 2\n    }\n    clear()\n}\n",
+            decompile_program(&program)
+        );
+    }
+
+    #[test]
+    fn single_conditional_instruction() {
+        // SKP V3; LD V0, 5; CLS
+        let program = [0xE3, 0x9E, 0x60, 0x05, 0x00, 0xE0];
+
+        assert_eq!(
+            "fn main() {\n    if (!key_down(v3)) {\n        v0 = 5\n    }\n    clear()\n}\n",
+            decompile_program(&program)
+        );
+    }
+
+    #[test]
+    fn do_while_loop() {
+        // 200: ADD V0, 1
+        // 202: SE V0, 10    leave once v0 reaches 10
+        // 204: JP 200
+        // 206: CLS
+        let program = [0x70, 0x01, 0x30, 0x0A, 0x12, 0x00, 0x00, 0xE0];
+
+        assert_eq!(
+            "fn main() {\n    do {\n        v0 += 1\n    } while (v0 != 10)\n    clear()\n}\n",
+            decompile_program(&program)
+        );
+    }
+
+    #[test]
+    fn loop_with_break() {
+        // 200: ADD V0, 1
+        // 202: SNE VF, 0    leave when flag is clear
+        // 204: JP 208
+        // 206: JP 200
+        // 208: CLS
+        let program = [0x70, 0x01, 0x4F, 0x00, 0x12, 0x08, 0x12, 0x00, 0x00, 0xE0];
+
+        assert_eq!(
+            "fn main() {\n    loop {\n        v0 += 1\n        if (flag == 0) {\n            break\n        }\n    }\n    clear()\n}\n",
+            decompile_program(&program)
+        );
+    }
+
+    #[test]
+    fn jump_through_vx() {
+        // JP V0, 0x300, which jumps through V3 when jump_uses_vx is set
+        let graph = ControlFlowGraph::analyze(&[0xB3, 0x00], 0x200);
+
+        assert_eq!(
+            "fn main() {\n    jump(0x300 + v0)\n}\n",
+            decompile(&graph, Quirks::default())
+        );
+        assert_eq!(
+            "fn main() {\n    jump(0x300 + v3)\n}\n",
+            decompile(&graph, Quirks::super_chip())
+        );
+    }
+
+    #[test]
+    fn shifts_and_register_loads() {
+        // SHR V1, V2; SHL V3, V3; LD [I], V2; LD V4, [I]; CLS
+        let graph = ControlFlowGraph::analyze(
+            &[0x81, 0x26, 0x83, 0x3E, 0xF2, 0x55, 0xF4, 0x65, 0x00, 0xE0],
+            0x200,
+        );
+
+        assert_eq!(
+            "fn main() {\n    v1 = v2 >> 1\n    v3 <<= 1\n    save(v0..=v2); i += 3\n    \
+             load(v0..=v4); i += 5\n    clear()\n}\n",
+            decompile(&graph, Quirks::cosmac_vip())
+        );
+        assert_eq!(
+            "fn main() {\n    v1 >>= 1\n    v3 <<= 1\n    save(v0..=v2)\n    \
+             load(v0..=v4)\n    clear()\n}\n",
+            decompile(&graph, Quirks::super_chip())
+        );
+    }
+}
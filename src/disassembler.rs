@@ -0,0 +1,186 @@
+use std::fmt::Write;
+
+use analysis::ControlFlowGraph;
+use instructions::Instruction;
+use registers::Register;
+use symbols::Symbols;
+
+// Instructions are written back out in Octo syntax, with addresses replaced
+// by label names wherever the symbols have one. Other addresses stay numbers
+// so the listing assembles again.
+pub fn instruction_text(instruction: &Instruction, symbols: &Symbols) -> String {
+    let v = |register: Register| format!("{:?}", register).to_lowercase();
+    let address = |address: u16| match symbols.name(address) {
+        Some(name) => name.to_string(),
+        None => format!("{:#05x}", address),
+    };
+
+    match *instruction {
+        Instruction::SYS(target) => format!("native {}", address(target)),
+        Instruction::CLS => "clear".to_string(),
+        Instruction::RET => "return".to_string(),
+        Instruction::JP(target) => format!("jump {}", address(target)),
+        Instruction::CALL(target) => match symbols.name(target) {
+            Some(name) => name.to_string(),
+            // Octo can only call labels, so anything else is written as
+            // the instruction's bytes
+            None => format!("{:#04x} {:#04x}", 0x20 | target >> 8, target & 0xFF),
+        },
+        // Octo's if ... then skips the next instruction when the condition
+        // doesn't hold, so each skip reads as its opposite
+        Instruction::SEC(x, k) => format!("if {} != {} then", v(x), k),
+        Instruction::SNEC(x, k) => format!("if {} == {} then", v(x), k),
+        Instruction::SER(x, y) => format!("if {} != {} then", v(x), v(y)),
+        Instruction::SNE(x, y) => format!("if {} == {} then", v(x), v(y)),
+        Instruction::SKP(x) => format!("if {} -key then", v(x)),
+        Instruction::SKNP(x) => format!("if {} key then", v(x)),
+        Instruction::LDC(x, k) => format!("{} := {}", v(x), k),
+        Instruction::ADDC(x, k) => format!("{} += {}", v(x), k),
+        Instruction::LDR(x, y) => format!("{} := {}", v(x), v(y)),
+        Instruction::OR(x, y) => format!("{} |= {}", v(x), v(y)),
+        Instruction::AND(x, y) => format!("{} &= {}", v(x), v(y)),
+        Instruction::XOR(x, y) => format!("{} ^= {}", v(x), v(y)),
+        Instruction::ADDR(x, y) => format!("{} += {}", v(x), v(y)),
+        Instruction::SUB(x, y) => format!("{} -= {}", v(x), v(y)),
+        Instruction::SHR(x, y) => format!("{} >>= {}", v(x), v(y)),
+        Instruction::SUBN(x, y) => format!("{} =- {}", v(x), v(y)),
+        Instruction::SHL(x, y) => format!("{} <<= {}", v(x), v(y)),
+        Instruction::LDI(target) => format!("i := {}", address(target)),
+        Instruction::JPA(target) => format!("jump0 {}", address(target)),
+        Instruction::RND(x, k) => format!("{} := random {:#04x}", v(x), k),
+        Instruction::DRW(x, y, rows) => format!("sprite {} {} {}", v(x), v(y), rows),
+        Instruction::LDRD(x) => format!("{} := delay", v(x)),
+        Instruction::LDVK(x) => format!("{} := key", v(x)),
+        Instruction::LDDR(x) => format!("delay := {}", v(x)),
+        Instruction::LDSR(x) => format!("buzzer := {}", v(x)),
+        Instruction::ADDI(x) => format!("i += {}", v(x)),
+        Instruction::LDIR(x) => format!("i := hex {}", v(x)),
+        Instruction::LDIH(x) => format!("i := bighex {}", v(x)),
+        Instruction::LDBR(x) => format!("bcd {}", v(x)),
+        Instruction::LDRS(x) => format!("save {}", v(x)),
+        Instruction::RDRS(x) => format!("load {}", v(x)),
+    }
+}
+
+// A listing of the whole program. Code found by following control flow is
+// disassembled and everything else is shown as data bytes. Each line notes
+// its address, the raw word and, with a source map, where it came from.
+pub fn disassemble(program: &[u8], load_address: u16, symbols: &Symbols) -> String {
+    let mut listing = String::new();
+    for (_, line) in lines(program, load_address, symbols) {
+        writeln!(listing, "{}", line).unwrap();
+    }
+
+    listing
+}
+
+// The lines of the listing, each with the address it shows. Label lines
+// don't have one
+pub fn lines(program: &[u8], load_address: u16, symbols: &Symbols) -> Vec<(Option<u16>, String)> {
+    let graph = ControlFlowGraph::analyze(program, load_address);
+    let end = load_address as usize + program.len();
+    let mut lines = Vec::new();
+    let mut address = load_address;
+
+    while (address as usize) < end {
+        if let Some(name) = symbols.name(address) {
+            lines.push((None, format!(": {}", name)));
+        }
+
+        let location = symbols
+            .location(address)
+            .map(|location| format!("  {}", location))
+            .unwrap_or_default();
+
+        let instruction = if graph.is_code(address) {
+            graph.instruction(address)
+        } else {
+            None
+        };
+
+        match instruction {
+            Some(instruction) => {
+                let line = format!(
+                    "    {:<24} # {:03x}  {:04x}{}",
+                    instruction_text(&instruction, symbols),
+                    address,
+                    graph.word(address),
+                    location
+                );
+                lines.push((Some(address), line));
+                address += 2;
+            }
+            None => {
+                let byte = program[(address - load_address) as usize];
+                let line = format!(
+                    "    {:<24} # {:03x}{}",
+                    format!("{:#04x}", byte),
+                    address,
+                    location
+                );
+                lines.push((Some(address), line));
+                address += 1;
+            }
+        }
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octo::assemble;
+
+    #[test]
+    fn listing_with_symbols() {
+        let source =
+            ": main\n  i := sprite\n  draw\n: halt jump halt\n: draw\n  return\n: sprite 0xF0 0x90";
+        let assembly = assemble(source).unwrap();
+        let symbols = Symbols::from_assembly(&assembly, Some("game.8o"));
+
+        assert_eq!(
+            ": main\n\
+             \x20   i := sprite              # 200  a208  game.8o:2:3\n\
+             \x20   draw                     # 202  2206  game.8o:3:3\n\
+             : halt\n\
+             \x20   jump halt                # 204  1204  game.8o:4:8\n\
+             : draw\n\
+             \x20   return                   # 206  00ee  game.8o:6:3\n\
+             : sprite\n\
+             \x20   0xf0                     # 208  game.8o:7:10\n\
+             \x20   0x90                     # 209  game.8o:7:15\n",
+            disassemble(&assembly.bytes, 0x200, &symbols)
+        );
+    }
+
+    #[test]
+    fn without_symbols() {
+        let symbols = Symbols::default();
+
+        assert_eq!(
+            "if v1 != 3 then",
+            instruction_text(&Instruction::SEC(Register::V1, 3), &symbols)
+        );
+        assert_eq!(
+            "0x22 0x08",
+            instruction_text(&Instruction::CALL(0x208), &symbols)
+        );
+    }
+
+    #[test]
+    fn disassembly_assembles_back() {
+        // The jump to 0x20c and the call to 0x210 land where there's no
+        // label once only main and halt are kept
+        let source = ": main\n  v0 := 5\n  if v0 != 5 then v1 += 1\n  v2 <<= v3\n  sprite v0 v1 4\n  jump skip\n: skip\n  sub\n: halt jump halt\n: sub return";
+        let assembly = assemble(source).unwrap();
+        let mut symbols = Symbols::default();
+        symbols.labels.insert(0x200, "main".to_string());
+        symbols.labels.insert(0x20E, "halt".to_string());
+
+        let listing = disassemble(&assembly.bytes, 0x200, &symbols);
+        assert!(listing.contains("jump 0x20c"));
+        assert!(listing.contains("0x22 0x10"));
+        assert_eq!(assembly.bytes, assemble(&listing).unwrap().bytes);
+    }
+}
@@ -1,45 +1,126 @@
 use std::fmt;
 
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
 
+// The part of the screen that changed, in pixels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// Each row is packed into a u64 with the leftmost pixel in the top bit, so
+// a whole sprite row can be drawn with one XOR. Rows and columns that have
+// changed are remembered until a renderer takes them.
 pub struct Chip8Display {
-    pixels: [bool; WIDTH * HEIGHT],
+    rows: [u64; HEIGHT],
+    dirty_rows: u32,
+    dirty_columns: u64,
 }
 
 impl Chip8Display {
     pub fn draw_pixel(&mut self, x: usize, y: usize, pixel: bool) -> bool {
-        if x >= 64 || y >= 32 {
+        if x >= WIDTH || y >= HEIGHT {
             panic!("Tried to flip pixel that was out of range: ({}, {})", x, y);
         }
 
-        let display_pixel = self
-            .pixels
-            .chunks_mut(WIDTH)
-            .nth(y)
-            .unwrap()
-            .get_mut(x)
-            .unwrap();
-        let collision: bool = *display_pixel && pixel;
+        self.draw_bits(y, (pixel as u64) << (WIDTH - 1 - x))
+    }
+
+    // XORs eight pixels onto a row starting at x. Pixels past the right edge
+    // wrap around to the left. Returns whether any pixel was turned off.
+    pub fn draw_row(&mut self, x: usize, y: usize, pixels: u8) -> bool {
+        if x >= WIDTH || y >= HEIGHT {
+            panic!("Tried to draw row that was out of range: ({}, {})", x, y);
+        }
+
+        self.draw_bits(y, ((pixels as u64) << (WIDTH - 8)).rotate_right(x as u32))
+    }
+
+    // Like draw_row, but pixels past the right edge are dropped
+    pub fn draw_row_clipped(&mut self, x: usize, y: usize, pixels: u8) -> bool {
+        let visible = WIDTH.saturating_sub(x).min(8);
+        let mask = (0xFF00u16 >> visible) as u8;
+
+        self.draw_row(x, y, pixels & mask)
+    }
+
+    fn draw_bits(&mut self, y: usize, bits: u64) -> bool {
+        let collision = self.rows[y] & bits != 0;
 
-        *display_pixel ^= pixel;
+        self.rows[y] ^= bits;
+        if bits != 0 {
+            self.dirty_rows |= 1 << y;
+            self.dirty_columns |= bits;
+        }
 
         collision
     }
 
-    pub fn draw() {
-        unimplemented!();
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        self.rows[y] & (1 << (WIDTH - 1 - x)) != 0
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, pixel: bool) {
+        if self.get_pixel(x, y) != pixel {
+            self.draw_bits(y, 1 << (WIDTH - 1 - x));
+        }
+    }
+
+    pub fn row(&self, y: usize) -> u64 {
+        self.rows[y]
     }
 
     pub fn clear(&mut self) {
-        self.pixels = [false; HEIGHT * WIDTH];
+        for (y, row) in self.rows.iter_mut().enumerate() {
+            if *row != 0 {
+                self.dirty_rows |= 1 << y;
+                self.dirty_columns |= *row;
+                *row = 0;
+            }
+        }
+    }
+
+    // Rows that changed since the dirty region was last taken, bit y for row y
+    pub fn dirty_rows(&self) -> u32 {
+        self.dirty_rows
+    }
+
+    // The smallest rectangle covering every changed pixel, or None if
+    // nothing changed since the last time it was taken
+    pub fn dirty_rect(&self) -> Option<DirtyRect> {
+        if self.dirty_rows == 0 {
+            return None;
+        }
+
+        let x = self.dirty_columns.leading_zeros() as usize;
+        let y = self.dirty_rows.trailing_zeros() as usize;
+        Some(DirtyRect {
+            x,
+            y,
+            width: WIDTH - self.dirty_columns.trailing_zeros() as usize - x,
+            height: HEIGHT - self.dirty_rows.leading_zeros() as usize - y,
+        })
+    }
+
+    pub fn take_dirty_rect(&mut self) -> Option<DirtyRect> {
+        let rect = self.dirty_rect();
+        self.dirty_rows = 0;
+        self.dirty_columns = 0;
+
+        rect
     }
 }
 
 impl Default for Chip8Display {
     fn default() -> Chip8Display {
         Chip8Display {
-            pixels: [false; HEIGHT * WIDTH],
+            rows: [0; HEIGHT],
+            dirty_rows: 0,
+            dirty_columns: 0,
         }
     }
 }
@@ -48,9 +129,9 @@ impl fmt::Debug for Chip8Display {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut screen = String::new();
 
-        for row in self.pixels.chunks(WIDTH) {
-            for pixel in row {
-                if *pixel {
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if self.get_pixel(x, y) {
                     screen += "*";
                 } else {
                     screen += "-";
@@ -62,3 +143,61 @@ impl fmt::Debug for Chip8Display {
         f.write_str(screen.as_ref())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_xor_and_collide() {
+        let mut display = Chip8Display::default();
+
+        assert!(!display.draw_row(4, 2, 0b1100_0000));
+        assert!(display.get_pixel(4, 2) && display.get_pixel(5, 2));
+        assert!(!display.get_pixel(6, 2));
+
+        assert!(display.draw_row(5, 2, 0b1000_0000));
+        assert!(display.get_pixel(4, 2) && !display.get_pixel(5, 2));
+        assert!(!display.draw_pixel(5, 2, false));
+    }
+
+    #[test]
+    fn rows_wrap_at_the_right_edge() {
+        let mut display = Chip8Display::default();
+        display.draw_row(60, 0, 0xFF);
+
+        assert_eq!(0xF000_0000_0000_000F, display.row(0));
+
+        display.clear();
+        display.draw_row_clipped(60, 0, 0xFF);
+        assert_eq!(0x0000_0000_0000_000F, display.row(0));
+    }
+
+    #[test]
+    fn dirty_region() {
+        let mut display = Chip8Display::default();
+        assert_eq!(None, display.dirty_rect());
+
+        display.draw_row(10, 3, 0b0011_1000);
+        display.draw_pixel(20, 7, true);
+        assert_eq!(0b1000_1000, display.dirty_rows());
+        assert_eq!(
+            Some(DirtyRect {
+                x: 12,
+                y: 3,
+                width: 9,
+                height: 5
+            }),
+            display.take_dirty_rect()
+        );
+        assert_eq!(None, display.take_dirty_rect());
+
+        // Drawing nothing or setting a pixel to what it already is isn't a change
+        display.draw_row(0, 0, 0);
+        display.set_pixel(20, 7, true);
+        assert_eq!(None, display.dirty_rect());
+
+        display.clear();
+        assert_eq!(Some(3), display.dirty_rect().map(|rect| rect.y));
+    }
+}
@@ -0,0 +1,467 @@
+use std::str::FromStr;
+
+use builder::Chip8MachineBuilder;
+use romdb::RomInfo;
+use script::{self, Condition, Value};
+use system::Chip8Machine;
+
+// Where a game keeps its score
+#[derive(Clone, PartialEq, Debug)]
+pub struct Score {
+    source: Source,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Source {
+    // A register or a single byte of memory
+    Value(Value),
+    // Memory from the first address to the last, big endian. Seven bytes at
+    // most, so the score always fits
+    Bytes(u16, u16),
+    // Memory from the first address to the last holding one decimal digit
+    // a byte, most significant first, the way FX33 leaves numbers for
+    // drawing
+    Digits(u16, u16),
+}
+
+impl Score {
+    pub fn read(&self, machine: &Chip8Machine) -> Result<i64, String> {
+        let (first, last, base) = match self.source {
+            Source::Value(ref value) => return value.read(machine).map(|value| value as i64),
+            Source::Bytes(first, last) => (first, last, 256),
+            Source::Digits(first, last) => (first, last, 10),
+        };
+
+        let mut score = 0;
+        for address in first..=last {
+            let byte = machine
+                .read_memory(address)
+                .ok_or_else(|| format!("{:#x} is past the end of memory", address))?;
+            score = score * base + byte as i64;
+        }
+
+        Ok(score)
+    }
+}
+
+// Written as a register like v3, memory[0x300], a range of bytes like
+// memory[0x300..0x301], or digits memory[0x300..0x302]
+impl FromStr for Score {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Score, String> {
+        let (digits, range) = match text.strip_prefix("digits ") {
+            Some(range) => (true, range.trim()),
+            None => (false, text.trim()),
+        };
+
+        let bounds = range
+            .strip_prefix("memory[")
+            .and_then(|range| range.strip_suffix(']'))
+            .and_then(|range| {
+                let mut ends = range.splitn(2, "..");
+                Some((ends.next()?, ends.next()?))
+            });
+
+        let source = match bounds {
+            Some((first, last)) => {
+                let first = script::parse_number(first)?;
+                let last = script::parse_number(last)?;
+                let longest = if digits { 8 } else { 7 };
+                if last < first || last - first >= longest {
+                    return Err(format!("{} is not a score", text));
+                }
+
+                if digits {
+                    Source::Digits(first, last)
+                } else {
+                    Source::Bytes(first, last)
+                }
+            }
+            None if !digits => Source::Value(script::parse_value(range)?),
+            None => return Err(format!("{} is not a score", text)),
+        };
+
+        Ok(Score { source })
+    }
+}
+
+// How an environment tells how well a game is going. The reward for a step
+// is how much the score went up, and the episode is over once game_over
+// holds or the program stops
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Rewards {
+    pub score: Option<Score>,
+    pub game_over: Option<Condition>,
+}
+
+impl Rewards {
+    pub fn from_rom(info: &RomInfo) -> Rewards {
+        Rewards {
+            score: info.score.clone(),
+            game_over: info.game_over.clone(),
+        }
+    }
+}
+
+// What one step of an environment saw
+#[derive(Clone, PartialEq, Debug)]
+pub struct Transition {
+    // The screen levels, row by row from the top left
+    pub observation: Vec<u8>,
+    pub reward: f64,
+    pub done: bool,
+}
+
+// A program as something to learn to play. Each step holds down the keys
+// chosen for it for frame_skip frames and reports how the score changed.
+// Episodes start from a fresh machine, and RND is seeded on reset so the
+// same seed and the same keys always play out the same way.
+pub struct Environment {
+    builder: Chip8MachineBuilder,
+    program: Vec<u8>,
+    rewards: Rewards,
+    frame_skip: usize,
+    max_frames: Option<usize>,
+    machine: Chip8Machine,
+    score: i64,
+    frames: usize,
+    done: bool,
+}
+
+impl Environment {
+    // Fails if the program doesn't fit or the score or game over look past
+    // the end of memory
+    pub fn new(
+        builder: Chip8MachineBuilder,
+        program: &[u8],
+        rewards: Rewards,
+    ) -> Result<Environment, String> {
+        let machine = builder.clone().build();
+        let mut environment = Environment {
+            builder,
+            program: program.to_vec(),
+            rewards,
+            frame_skip: 1,
+            max_frames: None,
+            machine,
+            score: 0,
+            frames: 0,
+            done: false,
+        };
+
+        environment.machine.load_rom(program)?;
+        if let Some(ref score) = environment.rewards.score {
+            score.read(&environment.machine)?;
+        }
+        if let Some(ref game_over) = environment.rewards.game_over {
+            game_over.read(&environment.machine)?;
+        }
+
+        environment.reset(0);
+        Ok(environment)
+    }
+
+    pub fn set_frame_skip(&mut self, frames: usize) {
+        if frames == 0 {
+            panic!("Steps have to run at least one frame");
+        }
+
+        self.frame_skip = frames;
+    }
+
+    // Ends episodes that run this many frames without the game ending
+    pub fn set_max_frames(&mut self, frames: Option<usize>) {
+        self.max_frames = frames;
+    }
+
+    pub fn machine(&self) -> &Chip8Machine {
+        &self.machine
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // Starts a new episode and returns the first observation
+    pub fn reset(&mut self, seed: u64) -> Vec<u8> {
+        self.machine = self.builder.clone().build();
+        self.machine.load_memory(&self.program);
+        self.machine.set_seed(seed);
+        self.score = self.read_score();
+        self.frames = 0;
+        self.done = false;
+
+        self.machine.screen_levels()
+    }
+
+    // Keys are the keypad keys, 0 to F, to hold down for the step. Every
+    // other key is let go
+    pub fn step(&mut self, keys: &[u8]) -> Transition {
+        if self.done {
+            panic!("The episode is over, reset the environment before stepping it");
+        }
+
+        for key in 0..16 {
+            self.machine.set_key(key, keys.contains(&key));
+        }
+
+        for _ in 0..self.frame_skip {
+            self.machine.run_frame();
+            self.frames += 1;
+            self.done = self.game_over();
+            if self.done {
+                break;
+            }
+        }
+
+        let score = self.read_score();
+        let reward = (score - self.score) as f64;
+        self.score = score;
+
+        Transition {
+            observation: self.machine.screen_levels(),
+            reward,
+            done: self.done,
+        }
+    }
+
+    fn read_score(&self) -> i64 {
+        self.rewards
+            .score
+            .as_ref()
+            .map_or(0, |score| score.read(&self.machine).unwrap_or(0))
+    }
+
+    fn game_over(&self) -> bool {
+        self.machine.halted()
+            || self.max_frames.is_some_and(|frames| self.frames >= frames)
+            || self
+                .rewards
+                .game_over
+                .as_ref()
+                .is_some_and(|game_over| game_over.holds(&self.machine))
+    }
+}
+
+// A batch of environments stepped together. Any that finish start a new
+// episode on their next step, each with a seed none of the others use
+pub struct VecEnvironment {
+    environments: Vec<Environment>,
+    next_seeds: Vec<u64>,
+}
+
+impl VecEnvironment {
+    pub fn new(
+        builder: Chip8MachineBuilder,
+        program: &[u8],
+        rewards: Rewards,
+        count: usize,
+    ) -> Result<VecEnvironment, String> {
+        let environments = (0..count)
+            .map(|_| Environment::new(builder.clone(), program, rewards.clone()))
+            .collect::<Result<Vec<Environment>, String>>()?;
+
+        Ok(VecEnvironment {
+            environments,
+            next_seeds: (0..count as u64).collect(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.environments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.environments.is_empty()
+    }
+
+    pub fn environments(&self) -> &[Environment] {
+        &self.environments
+    }
+
+    pub fn set_frame_skip(&mut self, frames: usize) {
+        for environment in &mut self.environments {
+            environment.set_frame_skip(frames);
+        }
+    }
+
+    pub fn set_max_frames(&mut self, frames: Option<usize>) {
+        for environment in &mut self.environments {
+            environment.set_max_frames(frames);
+        }
+    }
+
+    // Environment n starts with seed + n
+    pub fn reset(&mut self, seed: u64) -> Vec<Vec<u8>> {
+        let count = self.len() as u64;
+        for (index, next) in self.next_seeds.iter_mut().enumerate() {
+            *next = seed.wrapping_add(index as u64);
+        }
+
+        (0..self.environments.len())
+            .map(|index| {
+                let seed = self.next_seeds[index];
+                self.next_seeds[index] = seed.wrapping_add(count);
+                self.environments[index].reset(seed)
+            })
+            .collect()
+    }
+
+    // One set of keys for each environment
+    pub fn step<K: AsRef<[u8]>>(&mut self, actions: &[K]) -> Vec<Transition> {
+        if actions.len() != self.len() {
+            panic!(
+                "Got actions for {} environments, not {}",
+                actions.len(),
+                self.len()
+            );
+        }
+
+        let count = self.len() as u64;
+        self.environments
+            .iter_mut()
+            .zip(self.next_seeds.iter_mut())
+            .zip(actions)
+            .map(|((environment, next), keys)| {
+                if environment.is_done() {
+                    environment.reset(*next);
+                    *next = next.wrapping_add(count);
+                }
+                environment.step(keys.as_ref())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use display::{HEIGHT, WIDTH};
+
+    // Adds one to V0 and stores it at 0x300 each time 5 is pressed and let go
+    const PRESS_COUNTER: [u8; 18] = [
+        0xA3, 0x00, 0x61, 0x05, 0xE1, 0x9E, 0x12, 0x04, 0x70, 0x01, 0xF0, 0x55, 0xE1, 0xA1, 0x12,
+        0x0C, 0x12, 0x04,
+    ];
+
+    fn counter(count: usize) -> VecEnvironment {
+        let rewards = Rewards {
+            score: Some("memory[0x300]".parse().unwrap()),
+            game_over: Some("v0 == 3".parse().unwrap()),
+        };
+        VecEnvironment::new(Chip8MachineBuilder::new(), &PRESS_COUNTER, rewards, count).unwrap()
+    }
+
+    #[test]
+    fn rewards_follow_the_score() {
+        let mut environment = counter(1).environments.remove(0);
+        assert_eq!(WIDTH * HEIGHT, environment.reset(7).len());
+
+        let mut rewards = Vec::new();
+        let mut done = false;
+        for step in 0..5 {
+            let keys: &[u8] = if step % 2 == 0 { &[5] } else { &[] };
+            let transition = environment.step(keys);
+            rewards.push(transition.reward);
+            done = transition.done;
+        }
+
+        assert_eq!(vec![1.0, 0.0, 1.0, 0.0, 1.0], rewards);
+        assert!(done);
+    }
+
+    #[test]
+    fn start_screens_wait_for_a_key() {
+        // FX0A into V0, then stores it at 0x300 and loops
+        let program = [0xF0, 0x0A, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x06];
+        let rewards = Rewards {
+            score: Some("memory[0x300]".parse().unwrap()),
+            game_over: None,
+        };
+        let mut environment =
+            Environment::new(Chip8MachineBuilder::new(), &program, rewards).unwrap();
+
+        assert_eq!(0.0, environment.step(&[]).reward);
+        assert_eq!(0x200, environment.machine().pc());
+        assert_eq!(9.0, environment.step(&[9]).reward);
+        assert_eq!(0x206, environment.machine().pc());
+    }
+
+    #[test]
+    fn frame_skip_and_time_limit() {
+        let mut environment = counter(1).environments.remove(0);
+        environment.set_frame_skip(4);
+        environment.set_max_frames(Some(10));
+
+        assert!(!environment.step(&[5]).done);
+        assert_eq!(4, environment.frames);
+        assert!(!environment.step(&[]).done);
+        assert!(environment.step(&[]).done);
+        assert_eq!(10, environment.frames);
+    }
+
+    #[test]
+    fn seeds_repeat_random_numbers() {
+        // Stores a new random number at 0x300 every instruction or two
+        let program = [0xA3, 0x00, 0xC0, 0xFF, 0xF0, 0x55, 0x12, 0x02];
+        let rewards = Rewards {
+            score: Some("memory[0x300]".parse().unwrap()),
+            game_over: None,
+        };
+        let mut batch =
+            VecEnvironment::new(Chip8MachineBuilder::new(), &program, rewards, 3).unwrap();
+
+        let play = |batch: &mut VecEnvironment, seed| {
+            batch.reset(seed);
+            (0..4)
+                .map(|_| batch.step(&[[], [], []]))
+                .map(|transitions| transitions.iter().map(|t| t.reward).collect::<Vec<f64>>())
+                .collect::<Vec<Vec<f64>>>()
+        };
+
+        // Environment 1 with seed 5 plays like environment 0 with seed 6
+        let first = play(&mut batch, 5);
+        let second = play(&mut batch, 6);
+        assert_eq!(first, play(&mut batch, 5));
+        for (first, second) in first.iter().zip(second.iter()) {
+            assert_eq!(first[1], second[0]);
+            assert_ne!(first[0], first[1]);
+        }
+    }
+
+    #[test]
+    fn batches_reset_finished_games() {
+        let mut batch = counter(2);
+        batch.reset(0);
+
+        for _ in 0..4 {
+            batch.step(&[vec![5], vec![]]);
+            batch.step(&[vec![], vec![]]);
+        }
+
+        // The first game ended after its third press and started again
+        assert_eq!(1, batch.environments()[0].machine().register(0));
+        assert_eq!(0, batch.environments()[1].machine().register(0));
+    }
+
+    #[test]
+    fn scores() {
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&[0x01, 0x02, 0x03]);
+
+        let score = |text: &str| text.parse::<Score>().unwrap().read(&machine).unwrap();
+        assert_eq!(0x0102, score("memory[0x200..0x201]"));
+        assert_eq!(123, score("digits memory[0x200..0x202]"));
+        assert_eq!(0x200, score("pc"));
+        assert!("memory[0x201..0x200]".parse::<Score>().is_err());
+        assert!("memory[0x200..0x207]".parse::<Score>().is_err());
+        assert!("digits memory[0x200..0x207]".parse::<Score>().is_ok());
+        assert!("digits v0".parse::<Score>().is_err());
+
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&[0xFF; 8]);
+        let longest = "memory[0x200..0x206]".parse::<Score>().unwrap();
+        assert_eq!(Ok(0xFF_FFFF_FFFF_FFFF), longest.read(&machine));
+    }
+}
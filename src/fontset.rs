@@ -0,0 +1,248 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+pub const SMALL_GLYPH_SIZE: usize = 5;
+pub const LARGE_GLYPH_SIZE: usize = 10;
+const SMALL_FONT_SIZE: usize = SMALL_GLYPH_SIZE * 16;
+
+// The fonts different interpreters shipped with. Small glyphs are the 4x5
+// hex digits FX29 points at, large glyphs are the 8x10 digits FX30 points at
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BuiltinFont {
+    Vip,
+    Dream6800,
+    Eti660,
+    SuperChip,
+    Octo,
+}
+
+const OCTO_SMALL: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // Zero
+    0x20, 0x60, 0x20, 0x20, 0x70, // One
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // Two
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // Three
+    0x90, 0x90, 0xF0, 0x10, 0x10, // Four
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // Five
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // Six
+    0xF0, 0x10, 0x20, 0x40, 0x40, // Seven
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // Eight
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // Nine
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+const VIP_SMALL: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // Zero
+    0x60, 0x20, 0x20, 0x20, 0x70, // One
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // Two
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // Three
+    0xA0, 0xA0, 0xF0, 0x20, 0x20, // Four
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // Five
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // Six
+    0xF0, 0x10, 0x10, 0x10, 0x10, // Seven
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // Eight
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // Nine
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+const DREAM6800_SMALL: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // Zero
+    0x40, 0x40, 0x40, 0x40, 0x40, // One
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // Two
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // Three
+    0x80, 0xA0, 0xA0, 0xE0, 0x20, // Four
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // Five
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // Six
+    0xE0, 0x20, 0x20, 0x20, 0x20, // Seven
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // Eight
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // Nine
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+const ETI660_SMALL: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // Zero
+    0x20, 0x20, 0x20, 0x20, 0x20, // One
+    0xE0, 0x20, 0xE0, 0x80, 0xE0, // Two
+    0xE0, 0x20, 0xE0, 0x20, 0xE0, // Three
+    0xA0, 0xA0, 0xE0, 0x20, 0x20, // Four
+    0xE0, 0x80, 0xE0, 0x20, 0xE0, // Five
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0, // Six
+    0xE0, 0x20, 0x20, 0x20, 0x20, // Seven
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // Eight
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0, // Nine
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+    0x80, 0x80, 0xE0, 0xA0, 0xE0, // B
+    0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+    0x20, 0x20, 0xE0, 0xA0, 0xE0, // D
+    0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+    0xE0, 0x80, 0xC0, 0x80, 0x80, // F
+];
+
+// SUPER-CHIP only has big versions of the decimal digits
+const SUPER_CHIP_LARGE: [u8; LARGE_GLYPH_SIZE * 10] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // Zero
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // One
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // Two
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // Three
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // Four
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // Five
+    0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // Six
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // Seven
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // Eight
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // Nine
+];
+
+const OCTO_LARGE: [u8; LARGE_GLYPH_SIZE * 16] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // Zero
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // One
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // Two
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // Three
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // Four
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // Five
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // Six
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // Seven
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // Eight
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // Nine
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+// A complete font as it gets laid out in memory: the 16 small glyphs
+// followed by however many large glyphs the font has
+#[derive(Clone, PartialEq, Debug)]
+pub struct FontSet {
+    small: Vec<u8>,
+    large: Vec<u8>,
+}
+
+impl Default for FontSet {
+    fn default() -> FontSet {
+        FontSet::builtin(BuiltinFont::Octo)
+    }
+}
+
+impl FontSet {
+    pub fn builtin(font: BuiltinFont) -> FontSet {
+        let (small, large): (&[u8], &[u8]) = match font {
+            BuiltinFont::Vip => (&VIP_SMALL, &[]),
+            BuiltinFont::Dream6800 => (&DREAM6800_SMALL, &[]),
+            BuiltinFont::Eti660 => (&ETI660_SMALL, &[]),
+            BuiltinFont::SuperChip => (&OCTO_SMALL, &SUPER_CHIP_LARGE),
+            BuiltinFont::Octo => (&OCTO_SMALL, &OCTO_LARGE),
+        };
+
+        FontSet {
+            small: small.to_vec(),
+            large: large.to_vec(),
+        }
+    }
+
+    // A font is 80 bytes of small glyphs, optionally followed by 100 bytes
+    // for ten large digits or 160 bytes for all sixteen
+    pub fn from_bytes(bytes: &[u8]) -> Option<FontSet> {
+        let large_size = bytes.len().checked_sub(SMALL_FONT_SIZE)?;
+
+        if large_size != 0
+            && large_size != LARGE_GLYPH_SIZE * 10
+            && large_size != LARGE_GLYPH_SIZE * 16
+        {
+            return None;
+        }
+
+        Some(FontSet {
+            small: bytes[..SMALL_FONT_SIZE].to_vec(),
+            large: bytes[SMALL_FONT_SIZE..].to_vec(),
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<FontSet> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        FontSet::from_bytes(&bytes).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("A font file can't be {} bytes long", bytes.len()),
+            )
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.small.len() + self.large.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = self.small.clone();
+        bytes.extend_from_slice(&self.large);
+
+        bytes
+    }
+
+    pub fn large_glyphs(&self) -> usize {
+        self.large.len() / LARGE_GLYPH_SIZE
+    }
+
+    // Where the large glyph for digit starts, relative to the start of the
+    // font
+    pub fn large_offset(&self, digit: u8) -> Option<usize> {
+        if (digit as usize) < self.large_glyphs() {
+            Some(SMALL_FONT_SIZE + digit as usize * LARGE_GLYPH_SIZE)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_sizes() {
+        assert_eq!(80, FontSet::builtin(BuiltinFont::Vip).len());
+        assert_eq!(180, FontSet::builtin(BuiltinFont::SuperChip).len());
+        assert_eq!(240, FontSet::builtin(BuiltinFont::Octo).len());
+    }
+
+    #[test]
+    fn large_offsets() {
+        let font = FontSet::builtin(BuiltinFont::SuperChip);
+        assert_eq!(Some(80), font.large_offset(0));
+        assert_eq!(Some(170), font.large_offset(9));
+        assert_eq!(None, font.large_offset(0xA));
+        assert_eq!(None, FontSet::builtin(BuiltinFont::Vip).large_offset(0));
+    }
+
+    #[test]
+    fn from_bytes_checks_length() {
+        assert!(FontSet::from_bytes(&[0; 80]).is_some());
+        assert!(FontSet::from_bytes(&[0; 180]).is_some());
+        assert!(FontSet::from_bytes(&[0; 240]).is_some());
+        assert!(FontSet::from_bytes(&[0; 79]).is_none());
+        assert!(FontSet::from_bytes(&[0; 100]).is_none());
+    }
+}
@@ -0,0 +1,365 @@
+// A GIF decoder, just enough to pull the frames out of Octo cartridges. It
+// handles GIF87a and GIF89a, global and local color tables, interlacing and
+// multiple frames. Extensions are skipped. There's also a simple encoder
+// for screenshots.
+
+const MAX_CODE_SIZE: u32 = 12;
+
+// Frame sizes come from the file, so only this much is set aside for the
+// pixels up front and the rest grows as they actually decode
+const MAX_RESERVED_PIXELS: usize = 1 << 20;
+
+// Only the color indices are kept, since that's where cartridges hide their
+// payload. Palettes and frame positions are read past.
+pub struct Frame {
+    // One color index per pixel, row by row
+    pub indices: Vec<u8>,
+}
+
+pub struct Gif {
+    pub frames: Vec<Frame>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self
+            .data
+            .get(self.position)
+            .ok_or_else(|| "GIF ends early".to_string())?;
+        self.position += 1;
+
+        Ok(byte)
+    }
+
+    fn word(&mut self) -> Result<u16, String> {
+        let low = self.byte()? as u16;
+        let high = self.byte()? as u16;
+
+        Ok(high << 8 | low)
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.position + length > self.data.len() {
+            return Err("GIF ends early".to_string());
+        }
+
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+
+        Ok(bytes)
+    }
+
+    fn skip_palette(&mut self, packed: u8) -> Result<(), String> {
+        if packed & 0x80 == 0x80 {
+            let size = 2usize << (packed & 0x7);
+            self.bytes(size * 3)?;
+        }
+
+        Ok(())
+    }
+
+    // Data sub-blocks are runs of up to 255 bytes, each prefixed with its
+    // length and ended by an empty block
+    fn sub_blocks(&mut self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+
+        loop {
+            let length = self.byte()? as usize;
+            if length == 0 {
+                return Ok(data);
+            }
+
+            data.extend_from_slice(self.bytes(length)?);
+        }
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Gif, String> {
+    let mut reader = Reader { data, position: 0 };
+
+    let signature = reader.bytes(6)?;
+    if signature != b"GIF87a" && signature != b"GIF89a" {
+        return Err("Not a GIF file".to_string());
+    }
+
+    // Logical screen size, then the flags, background color and aspect
+    reader.bytes(4)?;
+    let packed = reader.byte()?;
+    reader.bytes(2)?;
+    reader.skip_palette(packed)?;
+
+    let mut frames = Vec::new();
+
+    loop {
+        match reader.byte()? {
+            // Extension
+            0x21 => {
+                let _label = reader.byte()?;
+                reader.sub_blocks()?;
+            }
+            // Image descriptor
+            0x2C => {
+                // Frame position first, which doesn't matter here
+                reader.bytes(4)?;
+                let frame_width = reader.word()?;
+                let frame_height = reader.word()?;
+                let packed = reader.byte()?;
+                reader.skip_palette(packed)?;
+
+                let minimum_code_size = reader.byte()? as u32;
+                let compressed = reader.sub_blocks()?;
+                let pixel_count = frame_width as usize * frame_height as usize;
+                let mut indices = decompress(&compressed, minimum_code_size, pixel_count)?;
+
+                if packed & 0x40 == 0x40 {
+                    indices = deinterlace(&indices, frame_width as usize, frame_height as usize);
+                }
+
+                frames.push(Frame { indices });
+            }
+            // Trailer
+            0x3B => break,
+            block => return Err(format!("Unknown GIF block {:#x}", block)),
+        }
+    }
+
+    Ok(Gif { frames })
+}
+
+fn decompress(data: &[u8], minimum_code_size: u32, pixel_count: usize) -> Result<Vec<u8>, String> {
+    if !(1..=11).contains(&minimum_code_size) {
+        return Err(format!("Bad LZW code size {}", minimum_code_size));
+    }
+
+    let clear_code = 1usize << minimum_code_size;
+    let end_code = clear_code + 1;
+
+    // Each table entry is a previous entry plus one more index, so strings
+    // are rebuilt by walking back through prefixes
+    let mut prefixes: Vec<Option<usize>> = Vec::with_capacity(4096);
+    let mut suffixes: Vec<u8> = Vec::with_capacity(4096);
+    let mut firsts: Vec<u8> = Vec::with_capacity(4096);
+
+    let reset =
+        |prefixes: &mut Vec<Option<usize>>, suffixes: &mut Vec<u8>, firsts: &mut Vec<u8>| {
+            prefixes.clear();
+            suffixes.clear();
+            firsts.clear();
+            for index in 0..end_code + 1 {
+                prefixes.push(None);
+                suffixes.push(index as u8);
+                firsts.push(index as u8);
+            }
+        };
+    reset(&mut prefixes, &mut suffixes, &mut firsts);
+
+    let mut output = Vec::with_capacity(pixel_count.min(MAX_RESERVED_PIXELS));
+    let mut code_size = minimum_code_size + 1;
+    let mut previous: Option<usize> = None;
+    let mut bit_buffer = 0u32;
+    let mut bit_count = 0u32;
+    let mut string = Vec::new();
+
+    for byte in data {
+        bit_buffer |= (*byte as u32) << bit_count;
+        bit_count += 8;
+
+        while bit_count >= code_size {
+            let code = (bit_buffer & ((1 << code_size) - 1)) as usize;
+            bit_buffer >>= code_size;
+            bit_count -= code_size;
+
+            if code == clear_code {
+                reset(&mut prefixes, &mut suffixes, &mut firsts);
+                code_size = minimum_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == end_code {
+                output.truncate(pixel_count);
+                return Ok(output);
+            }
+
+            let first = if code < prefixes.len() {
+                firsts[code]
+            } else if code == prefixes.len() && previous.is_some() {
+                // The KwKwK case: the code being defined right now
+                firsts[previous.unwrap()]
+            } else {
+                return Err(format!("Bad LZW code {}", code));
+            };
+
+            if let Some(previous) = previous {
+                if prefixes.len() < 4096 {
+                    prefixes.push(Some(previous));
+                    suffixes.push(first);
+                    firsts.push(firsts[previous]);
+                }
+            }
+
+            string.clear();
+            let mut current = Some(code);
+            while let Some(entry) = current {
+                string.push(suffixes[entry]);
+                current = prefixes[entry];
+            }
+            output.extend(string.iter().rev());
+            if output.len() >= pixel_count {
+                output.truncate(pixel_count);
+                return Ok(output);
+            }
+
+            if prefixes.len() == (1 << code_size) && code_size < MAX_CODE_SIZE {
+                code_size += 1;
+            }
+
+            previous = Some(code);
+        }
+    }
+
+    // Some encoders leave out the end code
+    output.truncate(pixel_count);
+    Ok(output)
+}
+
+// Interlaced images store every 8th row from 0, every 8th from 4, every
+// 4th from 2 and then every 2nd from 1
+fn deinterlace(indices: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut rows = Vec::with_capacity(height);
+    for &(start, step) in [(0, 8), (4, 8), (2, 4), (1, 2)].iter() {
+        let mut row = start;
+        while row < height {
+            rows.push(row);
+            row += step;
+        }
+    }
+
+    // Frames that end early leave the rows they're missing blank
+    let mut output = vec![0; indices.len()];
+    for (source, destination) in rows.iter().enumerate() {
+        let start = source * width;
+        if start + width > indices.len() {
+            break;
+        }
+        if (destination + 1) * width > output.len() {
+            continue;
+        }
+
+        output[destination * width..(destination + 1) * width]
+            .copy_from_slice(&indices[start..start + width]);
+    }
+
+    output
+}
+
+// Writes a GIF using 8 bit literal codes only, with a clear code often
+// enough that the code size never grows. Not small, but valid. The color
+// table is padded out to 256 entries.
+pub fn encode(width: u16, height: u16, colors: &[[u8; 3]], frames: &[Vec<u8>]) -> Vec<u8> {
+    assert!(colors.len() <= 256, "GIFs have at most 256 colors");
+
+    let mut gif = b"GIF89a".to_vec();
+    gif.extend_from_slice(&width.to_le_bytes());
+    gif.extend_from_slice(&height.to_le_bytes());
+    gif.extend_from_slice(&[0xF7, 0, 0]);
+    for index in 0..256 {
+        gif.extend_from_slice(colors.get(index).unwrap_or(&[0; 3]));
+    }
+
+    for frame in frames {
+        gif.push(0x2C);
+        gif.extend_from_slice(&[0, 0, 0, 0]);
+        gif.extend_from_slice(&width.to_le_bytes());
+        gif.extend_from_slice(&height.to_le_bytes());
+        gif.push(0);
+        gif.push(8);
+
+        let mut codes = vec![256];
+        for (count, index) in frame.iter().enumerate() {
+            if count > 0 && count % 254 == 0 {
+                codes.push(256);
+            }
+            codes.push(*index as u32);
+        }
+        codes.push(257);
+
+        let mut packed = Vec::new();
+        let mut buffer = 0u32;
+        let mut bits = 0;
+        for code in codes {
+            buffer |= code << bits;
+            bits += 9;
+            while bits >= 8 {
+                packed.push(buffer as u8);
+                buffer >>= 8;
+                bits -= 8;
+            }
+        }
+        if bits > 0 {
+            packed.push(buffer as u8);
+        }
+
+        for chunk in packed.chunks(255) {
+            gif.push(chunk.len() as u8);
+            gif.extend_from_slice(chunk);
+        }
+        gif.push(0);
+    }
+
+    gif.push(0x3B);
+    gif
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Color index n is grey level n, for tests that only care about indices
+    pub fn encode_grey(width: u16, height: u16, frames: &[Vec<u8>]) -> Vec<u8> {
+        let colors: Vec<[u8; 3]> = (0..256).map(|index| [index as u8; 3]).collect();
+        encode(width, height, &colors, frames)
+    }
+
+    #[test]
+    fn round_trip() {
+        let frames = vec![
+            (0..600).map(|index| (index % 251) as u8).collect(),
+            vec![7; 600],
+        ];
+        let gif = decode(&encode_grey(30, 20, &frames)).unwrap();
+
+        assert_eq!(2, gif.frames.len());
+        assert_eq!(frames[0], gif.frames[0].indices);
+        assert_eq!(frames[1], gif.frames[1].indices);
+    }
+
+    #[test]
+    fn compressed_codes() {
+        // 4x1 image of index 1 with a 2 bit minimum code size: clear, 1,
+        // then code 6 (1, 1) and the KwKwK case 7 (1, 1, 1) cut to 4 pixels
+        let data = [0x8C, 0x2D, 0x01];
+        assert_eq!(vec![1, 1, 1, 1], decompress(&data, 2, 4).unwrap());
+    }
+
+    #[test]
+    fn short_interlaced_frames() {
+        // A 2x8 interlaced frame with only its first four rows of data, the
+        // ones that belong at rows 0, 4, 2 and 6
+        let mut gif = encode_grey(2, 8, &[vec![1; 8]]);
+        gif[790] = 0x40;
+
+        let indices = &decode(&gif).unwrap().frames[0].indices;
+        assert_eq!(&vec![1, 1, 0, 0, 1, 1, 0, 0], indices);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(decode(b"PNG").is_err());
+        assert!(decode(b"GIF89a\x01\x00").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use instructions::Instruction;
+use memory::Chip8Memory;
+use registers::Chip8Registers;
+
+// The longest loop body worth looking for. Polling loops are a handful of
+// instructions, anything longer is almost always doing real work.
+const LONGEST_LOOP: u16 = 16;
+
+// A loop the program is running that only reads the timers, keys and
+// registers. Between two 60 Hz ticks none of those change, so once one
+// pass around it leaves the registers exactly as they were, every pass
+// after it until the next tick will too.
+pub struct IdleLoop {
+    pub start: u16,
+    registers: Chip8Registers,
+    // How many instructions had run in the frame when it was last at start
+    executed: usize,
+}
+
+impl IdleLoop {
+    pub fn new(start: u16, registers: &Chip8Registers, executed: usize) -> IdleLoop {
+        IdleLoop {
+            start,
+            registers: registers.clone(),
+            executed,
+        }
+    }
+
+    // Called on arriving back at the start. Returns how many instructions
+    // the passes that would fit in what's left of the frame take, so they
+    // can be skipped. Passes that don't fit still have to run, so the frame
+    // ends in the same place it would have.
+    pub fn revisit(&mut self, registers: &Chip8Registers, executed: usize, budget: usize) -> usize {
+        let length = executed - self.executed;
+        let repeats = *registers == self.registers && length > 0;
+
+        self.registers = registers.clone();
+        self.executed = executed;
+
+        if !repeats {
+            return 0;
+        }
+
+        let skipped = (budget.saturating_sub(executed) / length) * length;
+        self.executed += skipped;
+        skipped
+    }
+}
+
+// Whether the code at start is a straight run of instructions without side
+// effects that ends by jumping back to start. Skips in it may leave the
+// loop, but anything that writes memory, draws, calls, sets a timer or
+// picks a random number rules it out.
+pub fn is_polling_loop(memory: &Chip8Memory, start: u16) -> bool {
+    let mut address = start;
+
+    while address < start.saturating_add(LONGEST_LOOP * 2) {
+        if address as usize + 1 >= memory.size() {
+            return false;
+        }
+
+        match Instruction::new(memory.read_instruction(address as usize)) {
+            Some(Instruction::JP(target)) => return target == start,
+            Some(ref instruction) if is_pure(instruction) => match address.checked_add(2) {
+                Some(next) => address = next,
+                None => return false,
+            },
+            _ => return false,
+        }
+    }
+
+    false
+}
+
+fn is_pure(instruction: &Instruction) -> bool {
+    matches!(
+        *instruction,
+        Instruction::SEC(_, _)
+            | Instruction::SNEC(_, _)
+            | Instruction::SER(_, _)
+            | Instruction::SNE(_, _)
+            | Instruction::SKP(_)
+            | Instruction::SKNP(_)
+            | Instruction::LDC(_, _)
+            | Instruction::ADDC(_, _)
+            | Instruction::LDR(_, _)
+            | Instruction::OR(_, _)
+            | Instruction::AND(_, _)
+            | Instruction::XOR(_, _)
+            | Instruction::ADDR(_, _)
+            | Instruction::SUB(_, _)
+            | Instruction::SHR(_, _)
+            | Instruction::SUBN(_, _)
+            | Instruction::SHL(_, _)
+            | Instruction::LDI(_)
+            | Instruction::LDRD(_)
+            | Instruction::ADDI(_)
+            | Instruction::LDIR(_)
+            | Instruction::LDIH(_)
+            | Instruction::RDRS(_)
+    )
+}
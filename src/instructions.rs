@@ -1,6 +1,7 @@
 use registers::Register;
 
-#[derive(PartialEq, Debug)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Instruction {
     SYS(u16),
     CLS,
@@ -18,9 +19,9 @@ pub enum Instruction {
     XOR(Register, Register),
     ADDR(Register, Register), // this stands for Add-Registers
     SUB(Register, Register),
-    SHR(Register),
+    SHR(Register, Register),
     SUBN(Register, Register),
-    SHL(Register),
+    SHL(Register, Register),
     SNE(Register, Register),
     LDI(u16), // this stands for Load-I
     JPA(u16), // this stands for Jump-Address
@@ -34,6 +35,7 @@ pub enum Instruction {
     LDSR(Register), // this stands for Load-Sound-Register
     ADDI(Register),
     LDIR(Register), // this stands for Load-I-Register
+    LDIH(Register), // this stands for Load-I-High-resolution-digit
     LDBR(Register), // this stands for Load-B-Register
     LDRS(Register), // this stands for Load-Registers
     RDRS(Register), // this stands for Read-Registers
@@ -145,10 +147,12 @@ impl Instruction {
 
                 Some(Instruction::SUB(register_x, register_y))
             }
-            // SHR Vx
-            (0x8, register_x_bits, _, 0x6) => {
+            // SHR Vx Vy
+            (0x8, register_x_bits, register_y_bits, 0x6) => {
                 let register_x = Register::new(register_x_bits);
-                Some(Instruction::SHR(register_x))
+                let register_y = Register::new(register_y_bits);
+
+                Some(Instruction::SHR(register_x, register_y))
             }
             // SUBN Vx Vy
             (0x8, register_x_bits, register_y_bits, 0x7) => {
@@ -157,10 +161,12 @@ impl Instruction {
 
                 Some(Instruction::SUBN(register_x, register_y))
             }
-            // SHL Vx
-            (0x8, register_x_bits, _, 0xE) => {
+            // SHL Vx Vy
+            (0x8, register_x_bits, register_y_bits, 0xE) => {
                 let register_x = Register::new(register_x_bits);
-                Some(Instruction::SHL(register_x))
+                let register_y = Register::new(register_y_bits);
+
+                Some(Instruction::SHL(register_x, register_y))
             }
             // SNE Vx Vy
             (0x9, register_x_bits, register_y_bits, 0x0) => {
@@ -233,6 +239,11 @@ impl Instruction {
                 let register = Register::new(register_bits);
                 Some(Instruction::LDIR(register))
             }
+            // LD HF Vx
+            (0xF, register_bits, 0x3, 0x0) => {
+                let register = Register::new(register_bits);
+                Some(Instruction::LDIH(register))
+            }
             // LD B Vx
             (0xF, register_bits, 0x3, 0x3) => {
                 let register = Register::new(register_bits);
@@ -251,6 +262,61 @@ impl Instruction {
             (_, _, _, _) => None,
         }
     }
+
+    // Skips move PC on by an extra instruction when their condition holds
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            *self,
+            Instruction::SEC(_, _)
+                | Instruction::SNEC(_, _)
+                | Instruction::SER(_, _)
+                | Instruction::SNE(_, _)
+                | Instruction::SKP(_)
+                | Instruction::SKNP(_)
+        )
+    }
+
+    // The variant's name without its operands
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Instruction::SYS(_) => "SYS",
+            Instruction::CLS => "CLS",
+            Instruction::RET => "RET",
+            Instruction::JP(_) => "JP",
+            Instruction::CALL(_) => "CALL",
+            Instruction::SEC(_, _) => "SEC",
+            Instruction::SNEC(_, _) => "SNEC",
+            Instruction::SER(_, _) => "SER",
+            Instruction::LDC(_, _) => "LDC",
+            Instruction::ADDC(_, _) => "ADDC",
+            Instruction::LDR(_, _) => "LDR",
+            Instruction::OR(_, _) => "OR",
+            Instruction::AND(_, _) => "AND",
+            Instruction::XOR(_, _) => "XOR",
+            Instruction::ADDR(_, _) => "ADDR",
+            Instruction::SUB(_, _) => "SUB",
+            Instruction::SHR(_, _) => "SHR",
+            Instruction::SUBN(_, _) => "SUBN",
+            Instruction::SHL(_, _) => "SHL",
+            Instruction::SNE(_, _) => "SNE",
+            Instruction::LDI(_) => "LDI",
+            Instruction::JPA(_) => "JPA",
+            Instruction::RND(_, _) => "RND",
+            Instruction::DRW(_, _, _) => "DRW",
+            Instruction::SKP(_) => "SKP",
+            Instruction::SKNP(_) => "SKNP",
+            Instruction::LDRD(_) => "LDRD",
+            Instruction::LDVK(_) => "LDVK",
+            Instruction::LDDR(_) => "LDDR",
+            Instruction::LDSR(_) => "LDSR",
+            Instruction::ADDI(_) => "ADDI",
+            Instruction::LDIR(_) => "LDIR",
+            Instruction::LDIH(_) => "LDIH",
+            Instruction::LDBR(_) => "LDBR",
+            Instruction::LDRS(_) => "LDRS",
+            Instruction::RDRS(_) => "RDRS",
+        }
+    }
 }
 
 #[cfg(test)]
@@ -355,8 +421,8 @@ mod tests {
 
     #[test]
     fn decode_shr() {
-        let shr = Instruction::new(0x8106);
-        assert_eq!(Some(Instruction::SHR(Register::V1)), shr);
+        let shr = Instruction::new(0x8126);
+        assert_eq!(Some(Instruction::SHR(Register::V1, Register::V2)), shr);
     }
 
     #[test]
@@ -367,8 +433,8 @@ mod tests {
 
     #[test]
     fn decode_shl() {
-        let shl = Instruction::new(0x810E);
-        assert_eq!(Some(Instruction::SHL(Register::V1)), shl);
+        let shl = Instruction::new(0x812E);
+        assert_eq!(Some(Instruction::SHL(Register::V1, Register::V2)), shl);
     }
 
     #[test]
@@ -449,6 +515,12 @@ mod tests {
         assert_eq!(Some(Instruction::LDIR(Register::V1)), ldir);
     }
 
+    #[test]
+    fn decode_ldih() {
+        let ldih = Instruction::new(0xF130);
+        assert_eq!(Some(Instruction::LDIH(Register::V1)), ldih);
+    }
+
     #[test]
     fn decode_ldbr() {
         let ldbr = Instruction::new(0xF133);
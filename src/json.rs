@@ -0,0 +1,237 @@
+// A small JSON parser for the options Octo stores in its cartridges. Numbers
+// are kept as f64 like JavaScript does.
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Arrays and objects can't nest deeper than this, so a cartridge can't run
+// the parser out of stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(HashMap<String, Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut chars = text.chars().peekable();
+    let value = parse_value(&mut chars, 0)?;
+
+    skip_whitespace(&mut chars);
+    match chars.next() {
+        None => Ok(value),
+        Some(c) => Err(format!("Unexpected {:?} after JSON value", c)),
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while let Some(c) = chars.peek() {
+        if !c.is_whitespace() {
+            break;
+        }
+        chars.next();
+    }
+}
+
+fn expect(chars: &mut Peekable<Chars>, word: &str) -> Result<(), String> {
+    for expected in word.chars() {
+        if chars.next() != Some(expected) {
+            return Err(format!("Expected {}", word));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_value(chars: &mut Peekable<Chars>, depth: usize) -> Result<Value, String> {
+    skip_whitespace(chars);
+    if depth > MAX_DEPTH {
+        return Err("JSON nests too deeply".to_string());
+    }
+
+    match chars.peek() {
+        Some('n') => expect(chars, "null").map(|_| Value::Null),
+        Some('t') => expect(chars, "true").map(|_| Value::Bool(true)),
+        Some('f') => expect(chars, "false").map(|_| Value::Bool(false)),
+        Some('"') => parse_string(chars).map(Value::String),
+        Some('[') => {
+            chars.next();
+            let mut items = Vec::new();
+
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Ok(Value::Array(items));
+            }
+
+            loop {
+                items.push(parse_value(chars, depth + 1)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => return Ok(Value::Array(items)),
+                    _ => return Err("Expected , or ] in array".to_string()),
+                }
+            }
+        }
+        Some('{') => {
+            chars.next();
+            let mut members = HashMap::new();
+
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Ok(Value::Object(members));
+            }
+
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                if chars.next() != Some(':') {
+                    return Err(format!("Expected : after {:?}", key));
+                }
+                members.insert(key, parse_value(chars, depth + 1)?);
+
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => return Ok(Value::Object(members)),
+                    _ => return Err("Expected , or } in object".to_string()),
+                }
+            }
+        }
+        Some(c) if *c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+            while let Some(c) = chars.peek() {
+                if !(c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                    break;
+                }
+                number.push(*c);
+                chars.next();
+            }
+
+            number
+                .parse()
+                .map(Value::Number)
+                .map_err(|_| format!("Bad number {}", number))
+        }
+        Some(c) => Err(format!("Unexpected {:?}", c)),
+        None => Err("JSON ends early".to_string()),
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    if chars.next() != Some('"') {
+        return Err("Expected a string".to_string());
+    }
+
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(string),
+            Some('\\') => match chars.next() {
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some('r') => string.push('\r'),
+                Some('b') => string.push('\u{8}'),
+                Some('f') => string.push('\u{c}'),
+                Some('u') => {
+                    let mut code = parse_hex4(chars)?;
+                    // Characters outside the BMP come as a surrogate pair
+                    if (0xD800..0xDC00).contains(&code) {
+                        expect(chars, "\\u")?;
+                        let low = parse_hex4(chars)?;
+                        code =
+                            0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                    }
+                    string.push(std::char::from_u32(code).unwrap_or('\u{FFFD}'));
+                }
+                Some(c) => string.push(c),
+                None => return Err("JSON ends early".to_string()),
+            },
+            Some(c) => string.push(c),
+            None => return Err("Unterminated string".to_string()),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut Peekable<Chars>) -> Result<u32, String> {
+    let mut code = 0;
+    for _ in 0..4 {
+        let digit = chars
+            .next()
+            .and_then(|c| c.to_digit(16))
+            .ok_or_else(|| "Bad \\u escape".to_string())?;
+        code = code << 4 | digit;
+    }
+
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_values() {
+        let value = parse(r#" {"a": [1, -2.5e1, true, null], "b": {"c": "d\"é"}} "#).unwrap();
+
+        assert_eq!(
+            Some(&Value::Array(vec![
+                Value::Number(1.0),
+                Value::Number(-25.0),
+                Value::Bool(true),
+                Value::Null
+            ])),
+            value.get("a")
+        );
+        let c = value.get("b").and_then(|b| b.get("c"));
+        assert_eq!(Some("d\"é"), c.and_then(Value::as_str));
+    }
+
+    #[test]
+    fn rejects_bad_json() {
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse(&"[".repeat(100_000)).is_err());
+        assert!(parse(&format!("{}{}", "[".repeat(64), "]".repeat(64))).is_ok());
+    }
+}
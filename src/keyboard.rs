@@ -47,7 +47,7 @@ impl ToKey for u8 {
             13 => Key::D,
             14 => Key::E,
             15 => Key::F,
-            key @ _ => panic!(format!("Tried to create invalid key from {}", key)),
+            key => panic!("Tried to create invalid key from {}", key),
         }
     }
 }
@@ -96,12 +96,31 @@ impl Chip8Keyboard {
         }
     }
 
-    // TODO: poll keys and update inner variables. Possibly use ncurses for this
-    pub fn poll(&mut self) {
-        unimplemented!();
+    pub fn set_pressed<T: ToKey>(&mut self, key: T, pressed: bool) {
+        let key = key.to_key();
+
+        match key {
+            Key::Zero => self.zero = pressed,
+            Key::One => self.one = pressed,
+            Key::Two => self.two = pressed,
+            Key::Three => self.three = pressed,
+            Key::Four => self.four = pressed,
+            Key::Five => self.five = pressed,
+            Key::Six => self.six = pressed,
+            Key::Seven => self.seven = pressed,
+            Key::Eight => self.eight = pressed,
+            Key::Nine => self.nine = pressed,
+            Key::A => self.a = pressed,
+            Key::B => self.b = pressed,
+            Key::C => self.c = pressed,
+            Key::D => self.d = pressed,
+            Key::E => self.e = pressed,
+            Key::F => self.f = pressed,
+        }
     }
-}
 
-pub fn get_key() -> Key {
-    unimplemented!();
+    // The lowest numbered key held down, if any
+    pub fn first_pressed(&self) -> Option<u8> {
+        (0..16).find(|key| self.is_pressed(*key))
+    }
 }
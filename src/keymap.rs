@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use system::Chip8Machine;
+
+const EMBEDDED: &str = include_str!("keymaps.txt");
+
+// Host keys with names instead of a character
+const NAMED_KEYS: [&str; 7] = ["space", "enter", "tab", "up", "down", "left", "right"];
+
+// The CHIP-8 keypad from the top left, row by row
+const KEYPAD: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+// Which CHIP-8 key each host key presses
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Keymap {
+    bindings: BTreeMap<String, u8>,
+}
+
+impl Keymap {
+    pub fn new() -> Keymap {
+        Keymap::default()
+    }
+
+    pub fn bind(&mut self, host_key: &str, key: u8) -> Result<(), String> {
+        if key > 0xF {
+            return Err(format!("{:X} is not a CHIP-8 key", key));
+        }
+
+        let host_key = host_key.to_lowercase();
+        if host_key.chars().count() != 1 && !NAMED_KEYS.contains(&host_key.as_str()) {
+            return Err(format!("{} is not a host key", host_key));
+        }
+
+        self.bindings.insert(host_key, key);
+        Ok(())
+    }
+
+    // The CHIP-8 key a host key presses, if any. Letters match either case
+    pub fn key(&self, host_key: &str) -> Option<u8> {
+        self.bindings.get(&host_key.to_lowercase()).cloned()
+    }
+
+    // Presses or lets go of the CHIP-8 key a host key is bound to. Returns
+    // false for host keys the keymap doesn't use
+    pub fn apply(&self, machine: &mut Chip8Machine, host_key: &str, pressed: bool) -> bool {
+        match self.key(host_key) {
+            Some(key) => {
+                machine.set_key(key, pressed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn host_keys(&self, key: u8) -> Vec<&str> {
+        self.bindings
+            .iter()
+            .filter(|&(_, bound)| *bound == key)
+            .map(|(host_key, _)| host_key.as_str())
+            .collect()
+    }
+
+    // The host keys laid out like the keypad, for telling the player which
+    // keys to press. Keys with more than one binding show the first and
+    // unbound ones show as -
+    pub fn layout(&self) -> String {
+        let mut layout = String::new();
+
+        for row in KEYPAD.chunks(4) {
+            let keys: Vec<&str> = row
+                .iter()
+                .map(|key| self.host_keys(*key).first().cloned().unwrap_or("-"))
+                .collect();
+            layout += &keys.join(" ");
+            layout += "\n";
+        }
+
+        layout
+    }
+}
+
+// Keymaps by name. The built-in qwerty, azerty and dvorak maps come from
+// a file in the same format users write their own in
+#[derive(Default)]
+pub struct Keymaps {
+    maps: HashMap<String, Keymap>,
+}
+
+impl Keymaps {
+    pub fn embedded() -> Keymaps {
+        Keymaps::parse(EMBEDDED).unwrap_or_else(|error| panic!("Embedded keymaps: {}", error))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Keymaps, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|error| error.to_string())?;
+
+        Keymaps::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Keymaps, String> {
+        let mut maps = HashMap::new();
+        let mut current: Option<(String, Keymap)> = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let line_number = index + 1;
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Some((name, keymap)) = current.take() {
+                    maps.insert(name, keymap);
+                }
+
+                let name = line[1..line.len() - 1].trim().to_string();
+                if name.is_empty() {
+                    return Err(format!("line {}: keymap has no name", line_number));
+                }
+
+                current = Some((name, Keymap::new()));
+                continue;
+            }
+
+            let keymap = match current {
+                Some((_, ref mut keymap)) => keymap,
+                None => return Err(format!("line {}: binding outside of a keymap", line_number)),
+            };
+
+            let mut parts = line.splitn(2, '=');
+            let host_key = parts.next().unwrap_or("").trim();
+            let key = match parts.next() {
+                Some(key) => key.trim(),
+                None => return Err(format!("line {}: expected host key = key", line_number)),
+            };
+            let key = u8::from_str_radix(key, 16)
+                .map_err(|_| format!("line {}: {} is not a CHIP-8 key", line_number, key))?;
+
+            keymap
+                .bind(host_key, key)
+                .map_err(|error| format!("line {}: {}", line_number, error))?;
+        }
+
+        if let Some((name, keymap)) = current {
+            maps.insert(name, keymap);
+        }
+
+        Ok(Keymaps { maps })
+    }
+
+    // Keymaps from other replace keymaps with the same name
+    pub fn merge(&mut self, other: Keymaps) {
+        self.maps.extend(other.maps);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Keymap> {
+        self.maps.get(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.maps.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_layouts_cover_the_keypad() {
+        let keymaps = Keymaps::embedded();
+        assert_eq!(vec!["azerty", "dvorak", "qwerty"], keymaps.names());
+
+        for name in keymaps.names() {
+            let keymap = keymaps.get(name).unwrap();
+            for key in 0..16 {
+                assert_eq!(1, keymap.host_keys(key).len(), "{} key {:X}", name, key);
+            }
+        }
+
+        let qwerty = keymaps.get("qwerty").unwrap();
+        assert_eq!("1 2 3 4\nq w e r\na s d f\nz x c v\n", qwerty.layout());
+        assert_eq!(Some(0x5), qwerty.key("W"));
+        let azerty = keymaps.get("azerty").unwrap();
+        assert_eq!("& é \" '\na z e r\nq s d f\nw x c v\n", azerty.layout());
+        assert_eq!(Some(0x2), azerty.key("É"));
+        assert_eq!(Some(0x5), azerty.key("z"));
+        assert_eq!(Some(0x5), keymaps.get("dvorak").unwrap().key(","));
+    }
+
+    #[test]
+    fn custom_keymaps() {
+        let mut keymaps = Keymaps::embedded();
+        keymaps.merge(
+            Keymaps::parse(
+                "# Arrows for a game that steers with 2, 4, 6 and 8
+                 [arrows]
+                 up = 2
+                 left = 4
+                 right = 6
+                 down = 8
+                 space = 5
+                 w = 2",
+            )
+            .unwrap(),
+        );
+
+        let arrows = keymaps.get("arrows").unwrap();
+        assert_eq!(Some(0x6), arrows.key("right"));
+        assert_eq!(vec!["up", "w"], arrows.host_keys(0x2));
+        assert_eq!(
+            "- up - -\nleft space right -\n- down - -\n- - - -\n",
+            arrows.layout()
+        );
+    }
+
+    #[test]
+    fn host_keys_press_the_keypad() {
+        let azerty = Keymaps::embedded().get("azerty").unwrap().clone();
+        let mut machine = Chip8Machine::new();
+
+        assert!(azerty.apply(&mut machine, "Z", true));
+        assert!(machine.is_key_pressed(0x5));
+        assert!(!azerty.apply(&mut machine, "y", true));
+        assert!(azerty.apply(&mut machine, "z", false));
+        assert!((0..16).all(|key| !machine.is_key_pressed(key)));
+    }
+
+    #[test]
+    fn bad_bindings() {
+        assert!(Keymaps::parse("q = 4").is_err());
+        assert!(Keymaps::parse("[x]\nq = 10").is_err());
+        assert!(Keymaps::parse("[x]\nqq = 1").is_err());
+        assert!(Keymaps::parse("[x]\nq 1").is_err());
+    }
+}
@@ -1,10 +1,63 @@
-pub use system::Chip8Machine;
+pub use analysis::{BasicBlock, ControlFlowGraph, Subroutine};
+pub use builder::{Chip8MachineBuilder, MachineMode};
+pub use cartridge::Cartridge;
+pub use coverage::Coverage;
+pub use decompiler::decompile;
+pub use disassembler::{disassemble, instruction_text};
+pub use display::{Chip8Display, DirtyRect, HEIGHT, WIDTH};
+pub use env::{Environment, Rewards, Score, Transition, VecEnvironment};
+pub use fontset::{BuiltinFont, FontSet};
+pub use keymap::{Keymap, Keymaps};
+pub use lint::LintWarning;
+pub use octo::{assemble, AssembleError, Assembly, Span};
+pub use palette::{render_ansi, Palette};
+pub use phosphor::{render_text, Persistence};
+pub use profiler::Profiler;
+pub use quirks::Quirks;
+pub use romdb::{RomDatabase, RomInfo};
+pub use scheduler::{Clock, MockClock, Scheduler, SchedulerStats, SystemClock};
+pub use screenshot::{write_gif, write_pgm, write_ppm};
+pub use script::{Condition, Script};
+pub use speed::{Speed, SpeedController};
+pub use symbols::Symbols;
+pub use system::{Backend, Chip8Machine};
+pub use upscale::{parse_filters, upscale, Filter, Image};
+pub use vip::VipMachine;
 
+mod analysis;
+mod builder;
+mod cartridge;
+mod cdp1802;
+mod coverage;
+mod decompiler;
+mod disassembler;
 mod display;
+mod env;
+mod fontset;
+mod gif;
+mod idle;
 mod instructions;
+mod json;
 mod keyboard;
+mod keymap;
+mod lint;
 mod memory;
+mod octo;
+mod palette;
+mod phosphor;
+mod profiler;
+mod quirks;
+mod recompiler;
 mod registers;
+mod romdb;
+mod scheduler;
+mod screenshot;
+mod script;
+mod sha1;
+mod speed;
 mod sprites;
 mod stack;
+mod symbols;
 mod system;
+mod upscale;
+mod vip;
@@ -0,0 +1,280 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use instructions::Instruction;
+
+// Things that look wrong or unportable in a program. None of them stop it
+// from running, so they're only reported.
+#[derive(Clone, PartialEq, Debug)]
+pub enum LintWarning {
+    // A reachable word that isn't an instruction. It's skipped when run.
+    Undecodable { address: u16, word: u16 },
+    // A jump or call to somewhere the program wasn't loaded
+    JumpOutsideProgram { address: u16, target: u16 },
+    // A jump or call to an odd address, which will run misaligned words
+    OddJumpTarget { address: u16, target: u16 },
+    // A call into native 1802 code, which only works on a VIP
+    SysCall { address: u16, target: u16 },
+    // An instruction that does different things depending on the quirks.
+    // Only the first one for each quirk is reported.
+    QuirkSensitive { address: u16, quirk: &'static str },
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LintWarning::Undecodable { address, word } => {
+                write!(f, "{:#05x}: {:04x} is not an instruction", address, word)
+            }
+            LintWarning::JumpOutsideProgram { address, target } => write!(
+                f,
+                "{:#05x}: jump to {:#05x} is outside the program",
+                address, target
+            ),
+            LintWarning::OddJumpTarget { address, target } => {
+                write!(f, "{:#05x}: jump to odd address {:#05x}", address, target)
+            }
+            LintWarning::SysCall { address, target } => {
+                write!(f, "{:#05x}: machine code call to {:#05x}", address, target)
+            }
+            LintWarning::QuirkSensitive { address, quirk } => {
+                write!(f, "{:#05x}: behaviour depends on {}", address, quirk)
+            }
+        }
+    }
+}
+
+// Follows every path from the load address the way the interpreter would and
+// reports anything suspicious on the way. Jumps through BNNN can't be
+// followed without running the program, so code only reached that way isn't
+// checked.
+pub fn lint(program: &[u8], load_address: u16) -> Vec<LintWarning> {
+    let start = load_address as usize;
+    let end = start + program.len();
+
+    // Memory after the program reads as zero
+    let word_at = |address: usize| {
+        let byte = |address: usize| {
+            address
+                .checked_sub(start)
+                .and_then(|offset| program.get(offset))
+                .cloned()
+                .unwrap_or(0) as u16
+        };
+        byte(address) << 8 | byte(address + 1)
+    };
+
+    let mut warnings = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![start];
+
+    while let Some(address) = pending.pop() {
+        if address < start || address >= end || !visited.insert(address) {
+            continue;
+        }
+
+        let word = word_at(address);
+        // run() stops at a zero word
+        if word == 0 {
+            continue;
+        }
+
+        let here = address as u16;
+        let next = address + 2;
+
+        let instruction = match Instruction::new(word) {
+            Some(instruction) => instruction,
+            None => {
+                warnings.push(LintWarning::Undecodable {
+                    address: here,
+                    word,
+                });
+                pending.push(next);
+                continue;
+            }
+        };
+
+        let mut jump = |target: u16, warnings: &mut Vec<LintWarning>| {
+            if (target as usize) < start || target as usize >= end {
+                warnings.push(LintWarning::JumpOutsideProgram {
+                    address: here,
+                    target,
+                });
+                return;
+            }
+            if target % 2 == 1 {
+                warnings.push(LintWarning::OddJumpTarget {
+                    address: here,
+                    target,
+                });
+            }
+            pending.push(target as usize);
+        };
+
+        match instruction {
+            Instruction::JP(target) => jump(target, &mut warnings),
+            Instruction::CALL(target) => {
+                jump(target, &mut warnings);
+                pending.push(next);
+            }
+            Instruction::RET => {}
+            Instruction::JPA(_) => warnings.push(LintWarning::QuirkSensitive {
+                address: here,
+                quirk: "jump_uses_vx",
+            }),
+            Instruction::SYS(target) => {
+                warnings.push(LintWarning::SysCall {
+                    address: here,
+                    target,
+                });
+                pending.push(next);
+            }
+            Instruction::SEC(_, _)
+            | Instruction::SNEC(_, _)
+            | Instruction::SER(_, _)
+            | Instruction::SNE(_, _)
+            | Instruction::SKP(_)
+            | Instruction::SKNP(_) => {
+                pending.push(next);
+                pending.push(next + 2);
+            }
+            other => {
+                let quirk = match other {
+                    Instruction::SHR(x, y) | Instruction::SHL(x, y) if x != y => {
+                        Some("shift_uses_vy")
+                    }
+                    Instruction::OR(_, _) | Instruction::AND(_, _) | Instruction::XOR(_, _) => {
+                        Some("logic_resets_vf")
+                    }
+                    Instruction::LDRS(_) | Instruction::RDRS(_) => Some("load_store_increments_i"),
+                    // Whether a sprite crosses the edge depends on where it's
+                    // drawn, so any of them might
+                    Instruction::DRW(_, _, _) => Some("wrap_sprites"),
+                    _ => None,
+                };
+                if let Some(quirk) = quirk {
+                    warnings.push(LintWarning::QuirkSensitive {
+                        address: here,
+                        quirk,
+                    });
+                }
+                pending.push(next);
+            }
+        }
+    }
+
+    warnings.sort_by_key(|warning| match *warning {
+        LintWarning::Undecodable { address, .. }
+        | LintWarning::JumpOutsideProgram { address, .. }
+        | LintWarning::OddJumpTarget { address, .. }
+        | LintWarning::SysCall { address, .. }
+        | LintWarning::QuirkSensitive { address, .. } => address,
+    });
+
+    let mut quirks = HashSet::new();
+    warnings.retain(|warning| match *warning {
+        LintWarning::QuirkSensitive { quirk, .. } => quirks.insert(quirk),
+        _ => true,
+    });
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_program() {
+        // LD V0, 1; ADD V0, 1; SE V0, 3; JP 0x202
+        let program = [0x60, 0x01, 0x70, 0x01, 0x30, 0x03, 0x12, 0x02];
+        assert!(lint(&program, 0x200).is_empty());
+    }
+
+    #[test]
+    fn bad_jumps_and_sys() {
+        // SYS 0x123; CALL 0x208; JP 0x800; then at 0x208 JP 0x20B, which
+        // lands on LD V0, 5 spread across two words
+        let program = [
+            0x01, 0x23, 0x22, 0x08, 0x18, 0x00, 0x00, 0x00, 0x12, 0x0B, 0x00, 0x60, 0x05, 0x00,
+            0x00,
+        ];
+
+        assert_eq!(
+            vec![
+                LintWarning::SysCall {
+                    address: 0x200,
+                    target: 0x123
+                },
+                LintWarning::JumpOutsideProgram {
+                    address: 0x204,
+                    target: 0x800
+                },
+                LintWarning::OddJumpTarget {
+                    address: 0x208,
+                    target: 0x20B
+                },
+            ],
+            lint(&program, 0x200)
+        );
+    }
+
+    #[test]
+    fn undecodable_words() {
+        // 8XY8 isn't an instruction, and data after the JP isn't reachable
+        let program = [0x81, 0x28, 0x12, 0x02, 0xFF, 0xFF];
+        assert_eq!(
+            vec![LintWarning::Undecodable {
+                address: 0x200,
+                word: 0x8128
+            }],
+            lint(&program, 0x200)
+        );
+    }
+
+    #[test]
+    fn quirk_sensitive_opcodes() {
+        // SHR V1, V2; SHR V1, V1; OR V1, V2; LD [I], V3; DRW V0, V1, 5;
+        // JP V0, 0x300
+        let program = [
+            0x81, 0x26, 0x81, 0x16, 0x81, 0x21, 0xF3, 0x55, 0xD0, 0x15, 0xB3, 0x00,
+        ];
+        let quirks: Vec<&str> = lint(&program, 0x200)
+            .into_iter()
+            .map(|warning| match warning {
+                LintWarning::QuirkSensitive { quirk, .. } => quirk,
+                other => panic!("Unexpected {}", other),
+            })
+            .collect();
+
+        assert_eq!(
+            vec![
+                "shift_uses_vy",
+                "logic_resets_vf",
+                "load_store_increments_i",
+                "wrap_sprites",
+                "jump_uses_vx"
+            ],
+            quirks
+        );
+    }
+
+    #[test]
+    fn each_quirk_reported_once() {
+        // OR V1, V2; XOR V1, V2; SHR V1, V2; AND V1, V2; JP 0x200
+        let program = [0x81, 0x21, 0x81, 0x23, 0x81, 0x26, 0x81, 0x22, 0x12, 0x00];
+
+        assert_eq!(
+            vec![
+                LintWarning::QuirkSensitive {
+                    address: 0x200,
+                    quirk: "logic_resets_vf"
+                },
+                LintWarning::QuirkSensitive {
+                    address: 0x204,
+                    quirk: "shift_uses_vy"
+                },
+            ],
+            lint(&program, 0x200)
+        );
+    }
+}
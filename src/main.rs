@@ -1,24 +1,152 @@
 extern crate chip8_virtual_machine;
 
-use chip8_virtual_machine::Chip8Machine;
+use chip8_virtual_machine::{
+    assemble, decompile, disassemble, parse_filters, render_ansi, render_text, upscale, write_gif,
+    write_pgm, write_ppm, Cartridge, Chip8Machine, Chip8MachineBuilder, ControlFlowGraph, Filter,
+    Image, Keymap, Keymaps, MachineMode, Palette, Persistence, RomDatabase, Scheduler, Script,
+    Speed, Symbols, SystemClock, VipMachine,
+};
 
 use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::str;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+// Terminals only say when a key is typed and not when it's let go, so each
+// key typed holds its CHIP-8 key down for this many refreshes
+const KEY_HOLD: u32 = 6;
+
+// Host keys that control the emulator in realtime mode, when the keymap
+// doesn't already use them. Turbo lasts as long as its key is held
+const PAUSE_KEY: &str = "space";
+const FRAME_ADVANCE_KEY: &str = "right";
+const TURBO_KEY: &str = "tab";
+
+// How long to run under the VIP interpreter when --frames isn't given
+const VIP_FRAMES: usize = 60;
+
+const USAGE: &str =
+    "Usage: chip8 [--mode chip8|vip|eti660] [--vip-interpreter FILE] [--romdb FILE] [--no-romdb] [--keymap qwerty|azerty|dvorak|NAME] [--keymaps FILE] [--dot cfg|calls] [--decompile] [--disassemble] [--symbols FILE] [--write-symbols FILE] [--trace] [--profile] [--flamegraph FILE] [--lcov FILE] [--annotate FILE] [--frames N] [--script FILE] [--realtime] [--ips N] [--speed 2x|0.5x|turbo] [--persistence off|decay:N|blend:N] [--palette mono|green|amber|octo|COLORS] [--screenshot FILE.pgm|.ppm|.gif] [--scale nearest:N|scale2x|scale3x|scanlines:N|grid:N,...] PROGRAM";
 
 fn main() {
     //let mut instructions: Vec<u16> = Vec::new();
 
-    let program_path = env::args_os()
-        .nth(1)
-        .expect("Please specify program binary");
-    let mut program_file = File::open(program_path).expect("File not found");
+    let mut program_path = None;
+    let mut mode: Option<MachineMode> = None;
+    let mut use_romdb = true;
+    let mut extra_romdb = None;
+    let mut listing = None;
+    let mut symbols_path = None;
+    let mut write_symbols = None;
+    let mut keymap: Option<OsString> = None;
+    let mut extra_keymaps = None;
+    let mut vip_interpreter = None;
+    let mut tools = Tools::default();
+
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--mode") => {
+                let name = args.next().expect(USAGE);
+                let name = name.to_str().expect(USAGE);
+                mode = Some(name.parse().unwrap_or_else(|error| panic!("{}", error)));
+            }
+            Some("--vip-interpreter") => vip_interpreter = Some(args.next().expect(USAGE)),
+            Some("--romdb") => extra_romdb = Some(args.next().expect(USAGE)),
+            Some("--no-romdb") => use_romdb = false,
+            Some("--keymap") => keymap = Some(args.next().expect(USAGE)),
+            Some("--keymaps") => extra_keymaps = Some(args.next().expect(USAGE)),
+            Some("--dot") => listing = Some(args.next().expect(USAGE)),
+            Some("--decompile") => listing = Some("code".into()),
+            Some("--disassemble") => listing = Some("asm".into()),
+            Some("--symbols") => symbols_path = Some(args.next().expect(USAGE)),
+            Some("--write-symbols") => write_symbols = Some(args.next().expect(USAGE)),
+            Some("--trace") => tools.trace = true,
+            Some("--profile") => tools.profile = true,
+            Some("--flamegraph") => tools.flamegraph = Some(args.next().expect(USAGE)),
+            Some("--frames") => {
+                let frames = args.next().expect(USAGE);
+                let frames = frames.to_str().and_then(|frames| frames.parse().ok());
+                tools.frames = Some(frames.expect(USAGE));
+            }
+            Some("--script") => {
+                let path = args.next().expect(USAGE);
+                tools.script = Some(Script::load(path).unwrap_or_else(|error| panic!("{}", error)));
+            }
+            Some("--realtime") => tools.realtime = true,
+            Some("--ips") => {
+                let ips = args.next().expect(USAGE);
+                let ips = ips.to_str().and_then(|ips| ips.parse().ok());
+                tools.instructions_per_second = Some(ips.expect(USAGE));
+                tools.realtime = true;
+            }
+            Some("--speed") => {
+                let speed = args.next().expect(USAGE);
+                let speed = speed.to_str().expect(USAGE);
+                tools.speed = speed.parse().unwrap_or_else(|error| panic!("{}", error));
+                tools.realtime = true;
+            }
+            Some("--persistence") => {
+                let persistence = args.next().expect(USAGE);
+                let persistence = persistence.to_str().expect(USAGE);
+                tools.persistence = persistence
+                    .parse()
+                    .unwrap_or_else(|error| panic!("{}", error));
+            }
+            Some("--palette") => {
+                let palette = args.next().expect(USAGE);
+                let palette = palette.to_str().expect(USAGE);
+                tools.palette = Some(palette.parse().unwrap_or_else(|error| panic!("{}", error)));
+            }
+            Some("--scale") => {
+                let filters = args.next().expect(USAGE);
+                let filters = filters.to_str().expect(USAGE);
+                tools.filters = parse_filters(filters).unwrap_or_else(|error| panic!("{}", error));
+            }
+            Some("--screenshot") => tools.screenshot = Some(args.next().expect(USAGE)),
+            Some("--lcov") => tools.lcov = Some(args.next().expect(USAGE)),
+            Some("--annotate") => tools.annotate = Some(args.next().expect(USAGE)),
+            _ => program_path = Some(arg),
+        }
+    }
+
+    let program_path = program_path.expect("Please specify program binary");
+    let mut program_file = File::open(&program_path).expect("File not found");
 
     let mut program_data = Vec::new();
     program_file
         .read_to_end(&mut program_data)
         .expect("Could not read file");
 
+    // Symbols come from a file given on the command line, or one sitting
+    // next to the program with a .sym extension
+    let symbols_path =
+        symbols_path.or_else(|| Some(Path::new(&program_path).with_extension("sym").into()));
+    let mut symbols = symbols_path
+        .filter(|path| Path::new(path).exists())
+        .map(|path| Symbols::load(path).unwrap_or_else(|error| panic!("{}", error)))
+        .unwrap_or_default();
+
+    // Octo source gets assembled first, and brings its own symbols
+    if Path::new(&program_path).extension() == Some(OsStr::new("8o")) {
+        let source = String::from_utf8(program_data).expect("Source is not UTF-8");
+        let assembly = assemble(&source).unwrap_or_else(|error| panic!("{}", error));
+        let name = Path::new(&program_path).file_name().and_then(OsStr::to_str);
+        symbols = Symbols::from_assembly(&assembly, name);
+        program_data = assembly.bytes;
+    }
+
+    if let Some(path) = write_symbols {
+        symbols
+            .save(path)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
     // Because Rust only lets me read files in as u8's,
     // I take 2 at a time and concatenate them into a u16
     /*
@@ -34,7 +162,384 @@ fn main() {
     }
     */
 
-    let mut machine = Chip8Machine::new();
-    machine.load_memory(&program_data);
-    machine.run();
+    // Run the program under a dump of the VIP's own interpreter on an
+    // emulated CDP1802 instead
+    if let Some(path) = vip_interpreter {
+        let mut interpreter = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut interpreter))
+            .expect("Could not read interpreter");
+
+        let mut vip = VipMachine::new(&interpreter);
+        vip.load_memory(&program_data);
+        for _ in 0..tools.frames.unwrap_or(VIP_FRAMES) {
+            vip.run_frame();
+        }
+        print!("{}", show_levels(&vip.screen_levels(), tools.palette));
+        return;
+    }
+
+    let mut keymaps = Keymaps::embedded();
+    if let Some(path) = extra_keymaps {
+        keymaps.merge(Keymaps::load(path).unwrap_or_else(|error| panic!("{}", error)));
+    }
+    let mut keymap = keymap.map(|name| name.into_string().expect(USAGE));
+
+    // Octo cartridges carry their own settings, so they skip the database
+    if program_data.starts_with(b"GIF8") {
+        let cartridge =
+            Cartridge::decode(&program_data).unwrap_or_else(|error| panic!("{}", error));
+        let mut builder = cartridge.configure(Chip8MachineBuilder::new());
+        if let Some(mode) = mode {
+            builder = builder.mode(mode);
+        }
+        tools.palette = tools.palette.or(cartridge.palette);
+        tools.keymap = choose_keymap(&keymaps, keymap);
+
+        run(builder.build(), &cartridge.program, symbols, tools);
+        return;
+    }
+
+    let mut builder = Chip8MachineBuilder::new();
+
+    // Settings from the ROM database come first so anything given on the
+    // command line wins over them
+    if use_romdb {
+        let mut database = RomDatabase::embedded();
+        if let Some(path) = extra_romdb {
+            database.merge(RomDatabase::load(path).unwrap_or_else(|error| panic!("{}", error)));
+        }
+
+        if let Some(info) = database.lookup(&program_data) {
+            eprintln!("Detected {}", info.title);
+            builder = info.configure(builder);
+            keymap = keymap.or_else(|| info.keymap.clone());
+            if let Some((background, foreground)) = info.colors {
+                tools.palette = tools
+                    .palette
+                    .or(Some(Palette::two_color(background, foreground)));
+            }
+        }
+    }
+
+    if let Some(mode) = mode {
+        builder = builder.mode(mode);
+    }
+
+    // Print the program's structure as Graphviz, as pseudo-code or as a
+    // listing instead of running it. The machine it would run on decides
+    // where it's loaded and how its quirky instructions read
+    if let Some(listing) = listing {
+        let machine = builder.build();
+        let load_address = machine.load_address();
+        let analysis = ControlFlowGraph::analyze(&program_data, load_address);
+        match listing.to_str() {
+            Some("cfg") => print!("{}", analysis.to_dot()),
+            Some("calls") => print!("{}", analysis.call_graph_dot()),
+            Some("code") => print!("{}", decompile(&analysis, machine.quirks())),
+            Some("asm") => print!("{}", disassemble(&program_data, load_address, &symbols)),
+            _ => panic!("{}", USAGE),
+        }
+        return;
+    }
+
+    tools.keymap = choose_keymap(&keymaps, keymap);
+
+    run(builder.build(), &program_data, symbols, tools);
+}
+
+// The keymap named on the command line or in the ROM database, QWERTY if
+// neither has one
+fn choose_keymap(keymaps: &Keymaps, name: Option<String>) -> Keymap {
+    let name = name.unwrap_or_else(|| "qwerty".to_string());
+    match keymaps.get(&name) {
+        Some(keymap) => keymap.clone(),
+        None => panic!(
+            "Unknown keymap {}, expected one of {}",
+            name,
+            keymaps.names().join(", ")
+        ),
+    }
+}
+
+// What to watch the program do while it runs
+#[derive(Default)]
+struct Tools {
+    trace: bool,
+    profile: bool,
+    flamegraph: Option<OsString>,
+    lcov: Option<OsString>,
+    annotate: Option<OsString>,
+    // Run this many 60 Hz frames headless instead of until the program stops
+    frames: Option<usize>,
+    // Play this headless instead, and fail if it does
+    script: Option<Script>,
+    // Pace the program against the wall clock, showing the screen at 60 Hz
+    realtime: bool,
+    instructions_per_second: Option<u32>,
+    speed: Speed,
+    persistence: Persistence,
+    // Shows the screen in color. Without one the terminal gets plain text
+    palette: Option<Palette>,
+    // Where to save the screen once the program is done
+    screenshot: Option<OsString>,
+    // How to scale the screenshot up
+    filters: Vec<Filter>,
+    keymap: Keymap,
+}
+
+// Runs the program, then writes out whatever the tools collected. The
+// profile report goes to stderr and everything else to its own file
+fn run(mut machine: Chip8Machine, program: &[u8], symbols: Symbols, tools: Tools) {
+    load(&mut machine, program);
+    machine.set_symbols(symbols.clone());
+    if tools.trace {
+        machine.set_trace_output(Some(Box::new(io::stderr())));
+    }
+    machine.set_profiling(tools.profile || tools.flamegraph.is_some());
+    machine.set_coverage(tools.lcov.is_some() || tools.annotate.is_some());
+    machine.set_persistence(tools.persistence);
+
+    let mut failed = false;
+    match (&tools.script, tools.frames) {
+        (Some(script), _) => {
+            match script.run(&mut machine) {
+                Ok(frames) => eprintln!("Script passed after {} frames", frames),
+                Err(error) => {
+                    eprintln!("Script failed: {}", error);
+                    failed = true;
+                }
+            }
+            print!("{}", show(&machine, tools.palette));
+        }
+        (None, Some(frames)) => {
+            machine.run_frames(frames);
+            print!("{}", show(&machine, tools.palette));
+        }
+        (None, None) if tools.realtime => {
+            eprint!("Keys:\n{}", tools.keymap.layout());
+            eprintln!(
+                "{} pauses, {} runs one frame while paused, hold {} for turbo",
+                PAUSE_KEY, FRAME_ADVANCE_KEY, TURBO_KEY
+            );
+            let terminal = Terminal::unbuffered();
+            let typed = host_keys();
+            let mut held: Vec<(String, u32)> = Vec::new();
+
+            let mut scheduler = Scheduler::new(SystemClock::new());
+            scheduler.speed.set_speed(tools.speed);
+            if let Some(ips) = tools.instructions_per_second {
+                scheduler.set_instructions_per_second(&mut machine, ips);
+            }
+
+            scheduler.run(&mut machine, |machine, speed| {
+                println!("{}", show(machine, tools.palette));
+
+                for &mut (ref host_key, ref mut left) in held.iter_mut() {
+                    *left -= 1;
+                    if *left == 0
+                        && !tools.keymap.apply(machine, host_key, false)
+                        && host_key == TURBO_KEY
+                    {
+                        speed.set_speed(tools.speed);
+                    }
+                }
+                held.retain(|&(_, left)| left > 0);
+
+                for host_key in typed.try_iter() {
+                    let hold = match &host_key[..] {
+                        _ if tools.keymap.apply(machine, &host_key, true) => true,
+                        PAUSE_KEY => {
+                            let paused = speed.is_paused();
+                            speed.set_paused(!paused);
+                            false
+                        }
+                        FRAME_ADVANCE_KEY => {
+                            speed.advance_frame();
+                            false
+                        }
+                        TURBO_KEY => {
+                            if speed.speed() != Speed::Turbo {
+                                speed.set_speed(Speed::Turbo);
+                            }
+                            true
+                        }
+                        _ => false,
+                    };
+                    if hold {
+                        held.retain(|other| other.0 != host_key);
+                        held.push((host_key, KEY_HOLD));
+                    }
+                }
+            });
+            drop(terminal);
+
+            let stats = scheduler.stats();
+            eprintln!(
+                "{} refreshes ({} dropped), {} frames, {:.0} instructions per second",
+                stats.refreshes,
+                stats.dropped,
+                stats.frames,
+                stats.instructions_per_second()
+            );
+        }
+        (None, None) => machine.run(),
+    }
+
+    // The format comes from the extension, PPM unless it says otherwise
+    if let Some(path) = tools.screenshot {
+        let levels = machine.screen_levels();
+        let screen = Image::screen(tools.palette.unwrap_or_default().rgb(&[&levels]));
+        let screen = upscale(&screen, &tools.filters);
+        let mut image = Vec::new();
+        match Path::new(&path).extension().and_then(OsStr::to_str) {
+            Some("pgm") => write_pgm(&screen, &mut image),
+            Some("gif") => write_gif(&screen, &mut image),
+            _ => write_ppm(&screen, &mut image),
+        }
+        .unwrap_or_else(|error| panic!("Could not save screenshot: {}", error));
+        write_file(path, &image);
+    }
+
+    if let Some(profiler) = machine.profiler() {
+        if tools.profile {
+            eprint!("{}", profiler.report(&symbols));
+        }
+        if let Some(path) = tools.flamegraph {
+            write_file(path, profiler.collapsed_stacks(&symbols));
+        }
+    }
+
+    if let Some(coverage) = machine.coverage() {
+        let load_address = machine.load_address();
+
+        // Without a source map the LCOV lines point into the annotated
+        // listing, so it's named as the source when there is one
+        let listing = tools.annotate.clone().unwrap_or_else(|| "program".into());
+        if let Some(path) = tools.lcov {
+            let name = listing.to_string_lossy();
+            write_file(path, coverage.lcov(program, load_address, &symbols, &name));
+        }
+        if let Some(path) = tools.annotate {
+            write_file(path, coverage.annotate(program, load_address, &symbols));
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+// The screen for the terminal, in the palette's colors if there is one
+fn show(machine: &Chip8Machine, palette: Option<Palette>) -> String {
+    show_levels(&machine.screen_levels(), palette)
+}
+
+fn show_levels(levels: &[u8], palette: Option<Palette>) -> String {
+    match palette {
+        Some(palette) => render_ansi(&palette.rgb(&[levels])),
+        None => render_text(levels),
+    }
+}
+
+// Host keys as they're typed, by the names keymaps use
+fn host_keys() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut pending = Vec::new();
+        for byte in io::stdin().lock().bytes() {
+            let byte = match byte {
+                Ok(byte) => byte,
+                Err(_) => return,
+            };
+
+            // Arrow keys come as escape sequences
+            pending.push(byte);
+            let host_key = match pending[..] {
+                [0x1B] | [0x1B, b'['] => continue,
+                [0x1B, b'[', b'A'] => "up".to_string(),
+                [0x1B, b'[', b'B'] => "down".to_string(),
+                [0x1B, b'[', b'C'] => "right".to_string(),
+                [0x1B, b'[', b'D'] => "left".to_string(),
+                [b' '] => "space".to_string(),
+                [b'\n'] | [b'\r'] => "enter".to_string(),
+                [b'\t'] => "tab".to_string(),
+                [byte] if byte.is_ascii() => (byte as char).to_string(),
+                [0x1B, ..] => {
+                    pending.clear();
+                    continue;
+                }
+                // Keys like é on other layouts type more than one byte
+                _ => match str::from_utf8(&pending) {
+                    Ok(character) => character.to_string(),
+                    Err(error) if error.error_len().is_none() => continue,
+                    Err(_) => {
+                        pending.clear();
+                        continue;
+                    }
+                },
+            };
+            pending.clear();
+
+            if sender.send(host_key).is_err() {
+                return;
+            }
+        }
+    });
+
+    receiver
+}
+
+// Turns off the terminal's line buffering and echo so keys arrive as
+// they're typed, and puts the settings back when dropped. Does nothing
+// when stdin isn't a terminal
+struct Terminal {
+    settings: Option<String>,
+}
+
+impl Terminal {
+    fn unbuffered() -> Terminal {
+        let settings = stty(&["-g"]).filter(|_| stty(&["-icanon", "-echo", "min", "1"]).is_some());
+        Terminal { settings }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if let Some(ref settings) = self.settings {
+            stty(&[settings.trim()]);
+        }
+    }
+}
+
+fn stty(arguments: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(arguments)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+
+    if output.status.success() {
+        String::from_utf8(output.stdout).ok()
+    } else {
+        None
+    }
+}
+
+fn write_file<T: AsRef<[u8]>>(path: OsString, contents: T) {
+    File::create(&path)
+        .and_then(|mut file| file.write_all(contents.as_ref()))
+        .unwrap_or_else(|error| panic!("Could not write {:?}: {}", path, error));
+}
+
+fn load(machine: &mut Chip8Machine, program: &[u8]) {
+    let warnings = machine
+        .load_rom(program)
+        .unwrap_or_else(|error| panic!("{}", error));
+
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
 }
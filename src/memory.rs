@@ -1,160 +1,127 @@
 use std::fmt;
 
-const MEMORY_SIZE: usize = 4096;
+use fontset::FontSet;
+use instructions::Instruction;
+
+pub const MEMORY_SIZE: usize = 4096;
+
+// A slot is None until the word at that address has been decoded. The inner
+// Option is the decode result itself, so words that aren't instructions are
+// cached too.
+type DecodeSlot = Option<Option<Instruction>>;
 
 pub struct Chip8Memory {
-    memory_bank: [u8; MEMORY_SIZE],
+    memory_bank: Vec<u8>,
+    decoded: Vec<DecodeSlot>,
+    // Bytes that belong to compiled blocks, and whether any of them has been
+    // written since the blocks were compiled
+    code: Vec<bool>,
+    code_written: bool,
 }
 
 impl Chip8Memory {
+    // Blank memory with the font at font_address
+    pub fn new(size: usize, font_address: usize, font: &FontSet) -> Chip8Memory {
+        let mut memory = Chip8Memory {
+            memory_bank: vec![0; size],
+            decoded: vec![None; size],
+            code: vec![false; size],
+            code_written: false,
+        };
+
+        for (offset, byte) in font.bytes().iter().enumerate() {
+            memory.write(font_address + offset, *byte);
+        }
+
+        memory
+    }
+
+    pub fn size(&self) -> usize {
+        self.memory_bank.len()
+    }
+
     pub fn write(&mut self, location: usize, value: u8) {
         *self
             .memory_bank
             .get_mut(location)
-            .expect(format!("Tried to write invalid memory location: {:#x}", location).as_ref()) =
-            value
+            .unwrap_or_else(|| panic!("Tried to write invalid memory location: {:#x}", location)) =
+            value;
+
+        // The byte is the first half of the instruction at location and the
+        // second half of the one before it, which wraps around from the top
+        self.decoded[location] = None;
+        let previous = location.checked_sub(1).unwrap_or(self.size() - 1);
+        self.decoded[previous] = None;
+
+        if self.code[location] {
+            self.code_written = true;
+        }
     }
 
     pub fn read(&self, location: usize) -> u8 {
         *self
             .memory_bank
             .get(location)
-            .expect(format!("Tried to read invalid memory location: {:#x}", location).as_ref())
+            .unwrap_or_else(|| panic!("Tried to read invalid memory location: {:#x}", location))
     }
 
     pub fn read_instruction(&self, location: usize) -> u16 {
         let first = *self
             .memory_bank
             .get(location)
-            .expect(format!("Tried to read invalid memory location: {:#x}", location).as_ref());
+            .unwrap_or_else(|| panic!("Tried to read invalid memory location: {:#x}", location));
 
-        let second = *self
-            .memory_bank
-            .get(location + 1)
-            .expect(format!("Tried to read invalid memory location: {:#x}", location + 1).as_ref());
+        let second = self.memory_bank[self.after(location)];
 
         (first as u16) << 8 | second as u16
     }
+
+    // Same as decoding read_instruction, but each address is only decoded
+    // once until something writes over it
+    pub fn decode_instruction(&mut self, location: usize) -> Option<Instruction> {
+        if let Some(instruction) = self.decoded[location] {
+            return instruction;
+        }
+
+        let instruction = Instruction::new(self.read_instruction(location));
+        self.decoded[location] = Some(instruction);
+
+        instruction
+    }
+
+    pub fn mark_code(&mut self, location: usize) {
+        self.code[location] = true;
+        let second = self.after(location);
+        self.code[second] = true;
+    }
+
+    // An instruction at the last byte of memory takes its second half from
+    // the first, the same as PC wrapping around
+    fn after(&self, location: usize) -> usize {
+        (location + 1) % self.size()
+    }
+
+    pub fn clear_code(&mut self) {
+        for code in self.code.iter_mut() {
+            *code = false;
+        }
+    }
+
+    pub fn code_written(&self) -> bool {
+        self.code_written
+    }
+
+    pub fn take_code_written(&mut self) -> bool {
+        let code_written = self.code_written;
+        self.code_written = false;
+
+        code_written
+    }
 }
 
 impl Default for Chip8Memory {
     fn default() -> Chip8Memory {
-        let mut memory = [0u8; MEMORY_SIZE];
-
-        // This is where I insert the ASCII sprites defined by chip8
-        // Zero
-        memory[0] = 0xF0;
-        memory[1] = 0x90;
-        memory[2] = 0x90;
-        memory[3] = 0x90;
-        memory[4] = 0xF0;
-        // One
-        memory[5] = 0x20;
-        memory[6] = 0x60;
-        memory[7] = 0x20;
-        memory[8] = 0x20;
-        memory[9] = 0x70;
-        // Two
-        memory[10] = 0xF0;
-        memory[11] = 0x10;
-        memory[12] = 0xF0;
-        memory[13] = 0x80;
-        memory[14] = 0xF0;
-
-        // Three
-        memory[15] = 0xF0;
-        memory[16] = 0x10;
-        memory[17] = 0xF0;
-        memory[18] = 0x10;
-        memory[19] = 0xF0;
-
-        // Four
-        memory[20] = 0x90;
-        memory[21] = 0x90;
-        memory[22] = 0xF0;
-        memory[23] = 0x10;
-        memory[24] = 0x10;
-
-        // Five
-        memory[25] = 0xF0;
-        memory[26] = 0x80;
-        memory[27] = 0xF0;
-        memory[28] = 0x10;
-        memory[29] = 0xF0;
-
-        // Six
-        memory[30] = 0xF0;
-        memory[31] = 0x80;
-        memory[32] = 0xF0;
-        memory[33] = 0x90;
-        memory[34] = 0xF0;
-
-        // Seven
-        memory[35] = 0xF0;
-        memory[36] = 0x10;
-        memory[37] = 0x20;
-        memory[38] = 0x40;
-        memory[39] = 0x40;
-
-        // Eight
-        memory[40] = 0xF0;
-        memory[41] = 0x90;
-        memory[42] = 0xF0;
-        memory[43] = 0x90;
-        memory[44] = 0xF0;
-
-        // Nine
-        memory[45] = 0xF0;
-        memory[46] = 0x90;
-        memory[47] = 0xF0;
-        memory[48] = 0x10;
-        memory[49] = 0xF0;
-
-        // A
-        memory[50] = 0xF0;
-        memory[51] = 0x90;
-        memory[52] = 0xF0;
-        memory[53] = 0x90;
-        memory[54] = 0x90;
-
-        // B
-        memory[55] = 0xE0;
-        memory[56] = 0x90;
-        memory[57] = 0xE0;
-        memory[58] = 0x90;
-        memory[59] = 0xE0;
-
-        // C
-        memory[60] = 0xF0;
-        memory[61] = 0x80;
-        memory[62] = 0x80;
-        memory[63] = 0x80;
-        memory[64] = 0xF0;
-
-        // D
-        memory[65] = 0xE0;
-        memory[66] = 0x90;
-        memory[67] = 0x90;
-        memory[68] = 0x90;
-        memory[69] = 0xE0;
-
-        // E
-        memory[70] = 0xF0;
-        memory[71] = 0x80;
-        memory[72] = 0xF0;
-        memory[73] = 0x80;
-        memory[74] = 0xF0;
-
-        // F
-        memory[75] = 0xF0;
-        memory[76] = 0x80;
-        memory[77] = 0xF0;
-        memory[78] = 0x80;
-        memory[79] = 0x80;
-
-        Chip8Memory {
-            memory_bank: memory,
-        }
+        Chip8Memory::new(MEMORY_SIZE, 0, &FontSet::default())
     }
 }
 
@@ -189,3 +156,52 @@ impl fmt::Debug for Chip8Memory {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use registers::Register;
+
+    #[test]
+    fn decode_is_cached() {
+        let mut memory = Chip8Memory::default();
+        memory.write(0x200, 0x60);
+        memory.write(0x201, 0x12);
+
+        assert_eq!(
+            Some(Instruction::LDC(Register::V0, 0x12)),
+            memory.decode_instruction(0x200)
+        );
+        assert!(memory.decoded[0x200].is_some());
+    }
+
+    #[test]
+    fn write_invalidates_both_halves() {
+        let mut memory = Chip8Memory::default();
+        memory.write(0x200, 0x60);
+        memory.write(0x201, 0x12);
+        memory.decode_instruction(0x200);
+
+        memory.write(0x201, 0x34);
+        assert_eq!(
+            Some(Instruction::LDC(Register::V0, 0x34)),
+            memory.decode_instruction(0x200)
+        );
+
+        memory.write(0x200, 0x61);
+        assert_eq!(
+            Some(Instruction::LDC(Register::V1, 0x34)),
+            memory.decode_instruction(0x200)
+        );
+    }
+
+    #[test]
+    fn undecodable_words_are_cached() {
+        let mut memory = Chip8Memory::default();
+        memory.write(0x200, 0xFF);
+        memory.write(0x201, 0xFF);
+
+        assert_eq!(None, memory.decode_instruction(0x200));
+        assert_eq!(Some(None), memory.decoded[0x200]);
+    }
+}
@@ -0,0 +1,1166 @@
+// An assembler for Octo, the high level CHIP-8 assembly language. It covers
+// everything that runs on a plain CHIP-8: labels, :alias, :const, :org,
+// :byte, :unpack, macros, register operators, if/then, if/begin/else/end and
+// loop/while/again. SUPER-CHIP and XO-CHIP only statements are rejected.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::mem;
+
+const LOAD_ADDRESS: u16 = 0x200;
+const MAX_MACRO_EXPANSIONS: usize = 10000;
+
+const UNSUPPORTED: [&str; 13] = [
+    "hires",
+    "lores",
+    "scroll-down",
+    "scroll-up",
+    "scroll-left",
+    "scroll-right",
+    "exit",
+    "saveflags",
+    "loadflags",
+    "plane",
+    "audio",
+    "pitch",
+    "long",
+];
+
+// Where a token is in the source, counting lines and columns from 1
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct AssembleError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.line, self.span.column, self.message
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Assembly {
+    // The program as loaded at 0x200
+    pub bytes: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+    // The statement every emitted word or byte came from, by address
+    pub source_map: BTreeMap<u16, Span>,
+}
+
+impl Assembly {
+    pub fn span_at(&self, address: u16) -> Option<Span> {
+        self.source_map.get(&address).cloned()
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, AssembleError> {
+    let mut assembler = Assembler::new(tokenize(source));
+    assembler.run()?;
+    assembler.finish()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    span: Span,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+
+        let mut start = None;
+        for (column, c) in line.char_indices().chain(Some((line.len(), ' '))) {
+            match (c.is_whitespace(), start) {
+                (false, None) => start = Some(column),
+                (true, Some(first)) => {
+                    tokens.push_back(Token {
+                        text: line[first..column].to_string(),
+                        span: Span {
+                            line: index + 1,
+                            column: line[..first].chars().count() + 1,
+                            length: line[first..column].chars().count(),
+                        },
+                    });
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    tokens
+}
+
+enum Operand {
+    Register(u8),
+    Constant(u16),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    Key,
+    NotKey,
+}
+
+impl Comparison {
+    fn negate(self) -> Comparison {
+        match self {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Less => Comparison::GreaterEqual,
+            Comparison::GreaterEqual => Comparison::Less,
+            Comparison::Greater => Comparison::LessEqual,
+            Comparison::LessEqual => Comparison::Greater,
+            Comparison::Key => Comparison::NotKey,
+            Comparison::NotKey => Comparison::Key,
+        }
+    }
+}
+
+struct Condition {
+    register: u8,
+    comparison: Comparison,
+    operand: Operand,
+}
+
+enum Flow {
+    Loop {
+        span: Span,
+        address: u16,
+        breaks: Vec<u16>,
+    },
+    Begin {
+        span: Span,
+        jump: u16,
+    },
+    Else {
+        span: Span,
+        jump: u16,
+    },
+}
+
+// A use of a label before it was defined, filled in once they all are
+enum FixupKind {
+    // The low 12 bits of the instruction at the address
+    Address,
+    // v0 := nibble and the high bits of the label, as in :unpack
+    UnpackHigh(u8),
+    // v1 := the low byte of the label
+    UnpackLow,
+}
+
+struct Fixup {
+    address: u16,
+    name: Token,
+    kind: FixupKind,
+}
+
+struct Macro {
+    arguments: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    here: u16,
+    // Set once a byte has gone at 0xFFFF, so here can't move on
+    full: bool,
+    // The first statement that tried to write past 0xFFFF
+    overflow: Option<Span>,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i32>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    flow: Vec<Flow>,
+    source_map: BTreeMap<u16, Span>,
+    has_main: bool,
+    expansions: usize,
+    last_span: Span,
+}
+
+impl Assembler {
+    fn new(tokens: VecDeque<Token>) -> Assembler {
+        let mut assembler = Assembler {
+            tokens,
+            rom: Vec::new(),
+            here: LOAD_ADDRESS,
+            full: false,
+            overflow: None,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            flow: Vec::new(),
+            source_map: BTreeMap::new(),
+            has_main: true,
+            expansions: 0,
+            last_span: Span {
+                line: 1,
+                column: 1,
+                length: 0,
+            },
+        };
+
+        // Programs start with a jump to main, which is dropped again if
+        // main turns out to be right after it
+        let span = assembler.last_span;
+        assembler.instruction(0x1000, span);
+        assembler
+    }
+
+    fn error<T>(&self, span: Span, message: String) -> Result<T, AssembleError> {
+        Err(AssembleError { span, message })
+    }
+
+    fn next(&mut self) -> Result<Token, AssembleError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.last_span = token.span;
+                Ok(token)
+            }
+            None => self.error(self.last_span, "Unexpected end of program".to_string()),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AssembleError> {
+        let token = self.next()?;
+        if token.text != text {
+            return self.error(
+                token.span,
+                format!("Expected {} but found {}", text, token.text),
+            );
+        }
+
+        Ok(token)
+    }
+
+    fn byte(&mut self, value: u8, span: Span) {
+        if self.full {
+            self.overflow = self.overflow.or(Some(span));
+            return;
+        }
+
+        let index = (self.here - LOAD_ADDRESS) as usize;
+        if self.rom.len() <= index {
+            self.rom.resize(index + 1, 0);
+        }
+
+        self.rom[index] = value;
+        self.source_map.insert(self.here, span);
+        match self.here.checked_add(1) {
+            Some(next) => self.here = next,
+            None => self.full = true,
+        }
+    }
+
+    fn instruction(&mut self, word: u16, span: Span) {
+        let address = self.here;
+        self.byte((word >> 8) as u8, span);
+        self.byte(word as u8, span);
+        // Only the start of an instruction is mapped
+        self.source_map.remove(&address.wrapping_add(1));
+    }
+
+    // Points the jump at address to target, which has to be somewhere a
+    // 12 bit address can reach
+    fn patch(&mut self, address: u16, target: u16, span: Span) -> Result<(), AssembleError> {
+        if target > 0xFFF {
+            return self.error(span, format!("{:#x} is not a 12 bit address", target));
+        }
+
+        let index = (address - LOAD_ADDRESS) as usize;
+        self.rom[index] = (self.rom[index] & 0xF0) | ((target >> 8) as u8 & 0x0F);
+        self.rom[index + 1] = target as u8;
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), AssembleError> {
+        while !self.tokens.is_empty() {
+            self.statement()?;
+
+            if let Some(span) = self.overflow {
+                return self.error(span, "Program runs past the end of memory".to_string());
+            }
+        }
+
+        if let Some(flow) = self.flow.last() {
+            let (span, name) = match *flow {
+                Flow::Loop { span, .. } => (span, "loop without again"),
+                Flow::Begin { span, .. } | Flow::Else { span, .. } => (span, "begin without end"),
+            };
+            return self.error(span, name.to_string());
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Assembly, AssembleError> {
+        if self.has_main {
+            let main = match self.labels.get("main") {
+                Some(main) => *main,
+                None => {
+                    return self.error(
+                        Span {
+                            line: 1,
+                            column: 1,
+                            length: 0,
+                        },
+                        "This program has no main label".to_string(),
+                    )
+                }
+            };
+            let span = Span {
+                line: 1,
+                column: 1,
+                length: 0,
+            };
+            self.patch(LOAD_ADDRESS, main, span)?;
+        }
+
+        let fixups = mem::take(&mut self.fixups);
+        for fixup in fixups.iter() {
+            let target = match self.labels.get(&fixup.name.text) {
+                Some(target) => *target,
+                None => {
+                    return self.error(
+                        fixup.name.span,
+                        format!("Undefined name {}", fixup.name.text),
+                    )
+                }
+            };
+
+            let index = (fixup.address - LOAD_ADDRESS) as usize;
+            match fixup.kind {
+                FixupKind::Address => {
+                    if target > 0xFFF {
+                        return self.error(
+                            fixup.name.span,
+                            format!("{} is not a 12 bit address", fixup.name.text),
+                        );
+                    }
+                    self.patch(fixup.address, target, fixup.name.span)?;
+                }
+                FixupKind::UnpackHigh(nibble) => {
+                    self.rom[index + 1] = nibble << 4 | (target >> 8) as u8 & 0x0F;
+                }
+                FixupKind::UnpackLow => self.rom[index + 1] = target as u8,
+            }
+        }
+
+        Ok(Assembly {
+            bytes: self.rom,
+            labels: self.labels.into_iter().collect(),
+            source_map: self.source_map,
+        })
+    }
+
+    fn register(&self, token: &Token) -> Option<u8> {
+        if let Some(register) = self.aliases.get(&token.text) {
+            return Some(*register);
+        }
+
+        let text = token.text.to_lowercase();
+        if text.len() == 2 && text.starts_with('v') {
+            return u8::from_str_radix(&text[1..], 16).ok();
+        }
+
+        None
+    }
+
+    fn expect_register(&mut self) -> Result<u8, AssembleError> {
+        let token = self.next()?;
+        match self.register(&token) {
+            Some(register) => Ok(register),
+            None => self.error(
+                token.span,
+                format!("Expected a register but found {}", token.text),
+            ),
+        }
+    }
+
+    fn number(&self, token: &Token) -> Option<i32> {
+        if let Some(value) = self.constants.get(&token.text) {
+            return Some(*value);
+        }
+
+        let (negative, text) = match token.text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, token.text.as_str()),
+        };
+        let value = if let Some(hex) = text.strip_prefix("0x") {
+            i32::from_str_radix(hex, 16).ok()
+        } else if let Some(binary) = text.strip_prefix("0b") {
+            i32::from_str_radix(binary, 2).ok()
+        } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+            text.parse().ok()
+        } else {
+            None
+        }?;
+
+        Some(if negative { -value } else { value })
+    }
+
+    // A number that fits in max as either unsigned or signed, so -128 to
+    // 0xFF for a byte
+    fn expect_signed(&mut self, max: u16) -> Result<i32, AssembleError> {
+        let token = self.next()?;
+        let value = match self.number(&token) {
+            Some(value) => value,
+            None => {
+                return self.error(
+                    token.span,
+                    format!("Expected a number but found {}", token.text),
+                )
+            }
+        };
+
+        let max = max as i32;
+        if value < -(max + 1) / 2 || value > max {
+            return self.error(
+                token.span,
+                format!("{} does not fit in {:#x}", token.text, max),
+            );
+        }
+
+        Ok(value)
+    }
+
+    // Negative numbers count from the top of the range, so -1 is 0xFF for
+    // a byte
+    fn expect_number(&mut self, max: u16) -> Result<u16, AssembleError> {
+        let value = self.expect_signed(max)?;
+        if value < 0 {
+            return Ok((value + max as i32 + 1) as u16);
+        }
+
+        Ok(value as u16)
+    }
+
+    fn operand(&mut self) -> Result<Operand, AssembleError> {
+        if let Some(register) = self.tokens.front().and_then(|token| self.register(token)) {
+            self.next()?;
+            return Ok(Operand::Register(register));
+        }
+
+        Ok(Operand::Constant(self.expect_number(0xFF)?))
+    }
+
+    // Emits an instruction taking a 12 bit address, which can be a number,
+    // a constant or a label that may not have been defined yet
+    fn address_instruction(&mut self, opcode: u16, span: Span) -> Result<(), AssembleError> {
+        let token = self.next()?;
+
+        let target = match self.number(&token) {
+            Some(value) => Some(value),
+            None => self.labels.get(&token.text).map(|label| *label as i32),
+        };
+        if let Some(target) = target {
+            if !(0..=0xFFF).contains(&target) {
+                return self.error(
+                    token.span,
+                    format!("{} is not a 12 bit address", token.text),
+                );
+            }
+            self.instruction(opcode | target as u16, span);
+            return Ok(());
+        }
+
+        self.fixups.push(Fixup {
+            address: self.here,
+            name: token,
+            kind: FixupKind::Address,
+        });
+        self.instruction(opcode, span);
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: Token) -> Result<(), AssembleError> {
+        if self.labels.contains_key(&name.text) {
+            return self.error(name.span, format!("{} is already defined", name.text));
+        }
+
+        if name.text == "main" && self.here == LOAD_ADDRESS + 2 && self.rom.len() == 2 {
+            self.rom.clear();
+            self.source_map.clear();
+            self.here = LOAD_ADDRESS;
+            self.has_main = false;
+        }
+
+        self.labels.insert(name.text, self.here);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> Result<(), AssembleError> {
+        let token = self.next()?;
+        let span = token.span;
+
+        if let Some(register) = self.register(&token) {
+            return self.register_statement(register, span);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(name)?;
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.expect_register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.expect_signed(0xFFFF)?;
+                self.constants.insert(name.text, value);
+            }
+            ":org" => {
+                let address = self.expect_number(0xFFFF)?;
+                if address < LOAD_ADDRESS {
+                    return self.error(span, format!("Cannot :org below {:#x}", LOAD_ADDRESS));
+                }
+                self.here = address;
+                self.full = false;
+            }
+            ":byte" => {
+                let value = self.expect_number(0xFF)?;
+                self.byte(value as u8, span);
+            }
+            ":unpack" => {
+                let nibble = self.expect_number(0xF)? as u8;
+                let name = self.next()?;
+                match self.labels.get(&name.text).cloned() {
+                    Some(target) => {
+                        self.instruction(0x6000 | (nibble as u16) << 4 | target >> 8, span);
+                        self.instruction(0x6100 | (target & 0xFF), span);
+                    }
+                    None => {
+                        self.fixups.push(Fixup {
+                            address: self.here,
+                            name: name.clone(),
+                            kind: FixupKind::UnpackHigh(nibble),
+                        });
+                        self.instruction(0x6000, span);
+                        self.fixups.push(Fixup {
+                            address: self.here,
+                            name,
+                            kind: FixupKind::UnpackLow,
+                        });
+                        self.instruction(0x6100, span);
+                    }
+                }
+            }
+            ":macro" => self.define_macro()?,
+            ":breakpoint" => {
+                self.next()?;
+            }
+            "clear" => self.instruction(0x00E0, span),
+            "return" | ";" => self.instruction(0x00EE, span),
+            "jump" => self.address_instruction(0x1000, span)?,
+            "jump0" => self.address_instruction(0xB000, span)?,
+            "native" => self.address_instruction(0x0000, span)?,
+            "sprite" => {
+                let x = self.expect_register()? as u16;
+                let y = self.expect_register()? as u16;
+                let rows = self.expect_number(0xF)?;
+                self.instruction(0xD000 | x << 8 | y << 4 | rows, span);
+            }
+            "bcd" => {
+                let x = self.expect_register()? as u16;
+                self.instruction(0xF033 | x << 8, span);
+            }
+            "save" => {
+                let x = self.expect_register()? as u16;
+                self.instruction(0xF055 | x << 8, span);
+            }
+            "load" => {
+                let x = self.expect_register()? as u16;
+                self.instruction(0xF065 | x << 8, span);
+            }
+            "delay" => {
+                self.expect(":=")?;
+                let x = self.expect_register()? as u16;
+                self.instruction(0xF015 | x << 8, span);
+            }
+            "buzzer" => {
+                self.expect(":=")?;
+                let x = self.expect_register()? as u16;
+                self.instruction(0xF018 | x << 8, span);
+            }
+            "i" => self.i_statement(span)?,
+            "loop" => self.flow.push(Flow::Loop {
+                span,
+                address: self.here,
+                breaks: Vec::new(),
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                self.skip_unless(&condition, true, span);
+
+                let jump = self.here;
+                match self
+                    .flow
+                    .iter_mut()
+                    .rev()
+                    .find(|flow| matches!(flow, Flow::Loop { .. }))
+                {
+                    Some(Flow::Loop { breaks, .. }) => breaks.push(jump),
+                    _ => return self.error(span, "while without loop".to_string()),
+                }
+                self.instruction(0x1000, span);
+            }
+            "again" => match self.flow.pop() {
+                Some(Flow::Loop {
+                    address, breaks, ..
+                }) => {
+                    let again = self.here;
+                    self.instruction(0x1000, span);
+                    self.patch(again, address, span)?;
+                    for jump in breaks {
+                        let here = self.here;
+                        self.patch(jump, here, span)?;
+                    }
+                }
+                _ => return self.error(span, "again without loop".to_string()),
+            },
+            "if" => {
+                let condition = self.condition()?;
+                let keyword = self.next()?;
+                match keyword.text.as_str() {
+                    "then" => {
+                        self.skip_unless(&condition, false, span);
+                        self.statement()?;
+                    }
+                    "begin" => {
+                        self.skip_unless(&condition, true, span);
+                        self.flow.push(Flow::Begin {
+                            span,
+                            jump: self.here,
+                        });
+                        self.instruction(0x1000, span);
+                    }
+                    _ => {
+                        return self.error(
+                            keyword.span,
+                            format!("Expected then or begin but found {}", keyword.text),
+                        )
+                    }
+                }
+            }
+            "else" => match self.flow.pop() {
+                Some(Flow::Begin { jump, .. }) => {
+                    let else_jump = self.here;
+                    self.instruction(0x1000, span);
+                    let here = self.here;
+                    self.patch(jump, here, span)?;
+                    self.flow.push(Flow::Else {
+                        span,
+                        jump: else_jump,
+                    });
+                }
+                _ => return self.error(span, "else without begin".to_string()),
+            },
+            "end" => match self.flow.pop() {
+                Some(Flow::Begin { jump, .. }) | Some(Flow::Else { jump, .. }) => {
+                    let here = self.here;
+                    self.patch(jump, here, span)?;
+                }
+                _ => return self.error(span, "end without begin".to_string()),
+            },
+            text if UNSUPPORTED.contains(&text) => {
+                return self.error(span, format!("{} needs SUPER-CHIP or XO-CHIP", text));
+            }
+            text if self.macros.contains_key(text) => self.expand_macro(token)?,
+            _ => {
+                if let Some(value) = self.number(&token) {
+                    // Bare numbers are data
+                    if !(-0x80..=0xFF).contains(&value) {
+                        return self.error(span, format!("{} does not fit in a byte", token.text));
+                    }
+                    self.byte(value as u8, span);
+                } else if token.text.starts_with(':') || token.text.contains(":=") {
+                    return self.error(span, format!("Unknown directive {}", token.text));
+                } else {
+                    // Anything else is a call to a label
+                    self.tokens.push_front(token);
+                    self.address_instruction(0x2000, span)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn i_statement(&mut self, span: Span) -> Result<(), AssembleError> {
+        let operator = self.next()?;
+        match operator.text.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.expect_register()? as u16;
+                    self.instruction(0xF029 | x << 8, span);
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.expect_register()? as u16;
+                    self.instruction(0xF030 | x << 8, span);
+                }
+                _ => self.address_instruction(0xA000, span)?,
+            },
+            "+=" => {
+                let x = self.expect_register()? as u16;
+                self.instruction(0xF01E | x << 8, span);
+            }
+            _ => {
+                return self.error(
+                    operator.span,
+                    format!("Expected := or += but found {}", operator.text),
+                )
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register_statement(&mut self, register: u8, span: Span) -> Result<(), AssembleError> {
+        let x = (register as u16) << 8;
+        let operator = self.next()?;
+
+        // Operators that only take a register
+        let register_opcode = match operator.text.as_str() {
+            "|=" => Some(0x8001),
+            "&=" => Some(0x8002),
+            "^=" => Some(0x8003),
+            "-=" => Some(0x8005),
+            ">>=" => Some(0x8006),
+            "=-" => Some(0x8007),
+            "<<=" => Some(0x800E),
+            _ => None,
+        };
+        if let Some(opcode) = register_opcode {
+            let y = (self.expect_register()? as u16) << 4;
+            self.instruction(opcode | x | y, span);
+            return Ok(());
+        }
+
+        match operator.text.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.expect_number(0xFF)?;
+                    self.instruction(0xC000 | x | mask, span);
+                }
+                Some("delay") => {
+                    self.next()?;
+                    self.instruction(0xF007 | x, span);
+                }
+                Some("key") => {
+                    self.next()?;
+                    self.instruction(0xF00A | x, span);
+                }
+                _ => match self.operand()? {
+                    Operand::Register(y) => self.instruction(0x8000 | x | (y as u16) << 4, span),
+                    Operand::Constant(value) => self.instruction(0x6000 | x | value, span),
+                },
+            },
+            "+=" => match self.operand()? {
+                Operand::Register(y) => self.instruction(0x8004 | x | (y as u16) << 4, span),
+                Operand::Constant(value) => self.instruction(0x7000 | x | value, span),
+            },
+            _ => return self.error(operator.span, format!("Unknown operator {}", operator.text)),
+        }
+
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, AssembleError> {
+        let register = self.expect_register()?;
+        let token = self.next()?;
+
+        let comparison = match token.text.as_str() {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessEqual,
+            ">=" => Comparison::GreaterEqual,
+            "key" => Comparison::Key,
+            "-key" => Comparison::NotKey,
+            _ => {
+                return self.error(
+                    token.span,
+                    format!("Expected a comparison but found {}", token.text),
+                )
+            }
+        };
+
+        let operand = match comparison {
+            Comparison::Key | Comparison::NotKey => Operand::Constant(0),
+            _ => self.operand()?,
+        };
+
+        Ok(Condition {
+            register,
+            comparison,
+            operand,
+        })
+    }
+
+    // Emits a skip over the next instruction for when the condition (or its
+    // opposite, if negated) doesn't hold
+    fn skip_unless(&mut self, condition: &Condition, negated: bool, span: Span) {
+        let comparison = if negated {
+            condition.comparison.negate()
+        } else {
+            condition.comparison
+        };
+        let x = (condition.register as u16) << 8;
+
+        match (comparison, &condition.operand) {
+            (Comparison::Equal, &Operand::Constant(value)) => {
+                self.instruction(0x4000 | x | value, span)
+            }
+            (Comparison::Equal, &Operand::Register(y)) => {
+                self.instruction(0x9000 | x | (y as u16) << 4, span)
+            }
+            (Comparison::NotEqual, &Operand::Constant(value)) => {
+                self.instruction(0x3000 | x | value, span)
+            }
+            (Comparison::NotEqual, &Operand::Register(y)) => {
+                self.instruction(0x5000 | x | (y as u16) << 4, span)
+            }
+            (Comparison::Key, _) => self.instruction(0xE0A1 | x, span),
+            (Comparison::NotKey, _) => self.instruction(0xE09E | x, span),
+            (comparison, operand) => {
+                // Ordering goes through VF: load the operand into it, then
+                // subtract so the borrow flag holds the answer
+                match *operand {
+                    Operand::Register(y) => self.instruction(0x8F00 | (y as u16) << 4, span),
+                    Operand::Constant(value) => self.instruction(0x6F00 | value, span),
+                }
+                let x = (condition.register as u16) << 4;
+                let (subtract, skip) = match comparison {
+                    // vf = vx - operand, flag set when vx >= operand
+                    Comparison::GreaterEqual => (0x8F07, 0x3F00),
+                    Comparison::Less => (0x8F07, 0x3F01),
+                    // vf = operand - vx, flag set when operand >= vx
+                    Comparison::LessEqual => (0x8F05, 0x3F00),
+                    _ => (0x8F05, 0x3F01),
+                };
+                self.instruction(subtract | x, span);
+                self.instruction(skip, span);
+            }
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), AssembleError> {
+        let name = self.next()?;
+        let mut arguments = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            arguments.push(token.text);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+
+        self.macros.insert(name.text, Macro { arguments, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: Token) -> Result<(), AssembleError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return self.error(name.span, format!("Too many expansions of {}", name.text));
+        }
+
+        let count = self.macros[&name.text].arguments.len();
+        let mut values = HashMap::new();
+        for index in 0..count {
+            let value = self.next()?;
+            values.insert(self.macros[&name.text].arguments[index].clone(), value.text);
+        }
+
+        let macro_ = &self.macros[&name.text];
+        for token in macro_.body.iter().rev() {
+            let text = values.get(&token.text).unwrap_or(&token.text).clone();
+            self.tokens.push_front(Token {
+                text,
+                span: token.span,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        assemble(source)
+            .unwrap_or_else(|error| panic!("{}", error))
+            .bytes
+    }
+
+    #[test]
+    fn main_first_needs_no_jump() {
+        assert_eq!(
+            vec![0x00, 0xE0, 0x12, 0x02],
+            bytes(": main clear : halt jump halt")
+        );
+    }
+
+    #[test]
+    fn jump_to_main() {
+        assert_eq!(
+            vec![0x12, 0x04, 0x00, 0xEE, 0x22, 0x02],
+            bytes(": sub return : main sub")
+        );
+    }
+
+    #[test]
+    fn register_operators() {
+        let source = ": main
+            v0 := 5  v1 := v0  v2 += 3  v2 += v1  v3 -= v4  v3 =- v4
+            v5 |= v6  v5 &= v6  v5 ^= v6  v7 >>= v7  v7 <<= v8
+            v9 := random 0x0F  va := delay  vb := key  vF := -1";
+
+        assert_eq!(
+            vec![
+                0x60, 0x05, 0x81, 0x00, 0x72, 0x03, 0x82, 0x14, 0x83, 0x45, 0x83, 0x47, 0x85, 0x61,
+                0x85, 0x62, 0x85, 0x63, 0x87, 0x76, 0x87, 0x8E, 0xC9, 0x0F, 0xFA, 0x07, 0xFB, 0x0A,
+                0x6F, 0xFF,
+            ],
+            bytes(source)
+        );
+    }
+
+    #[test]
+    fn other_statements() {
+        let source = ": main
+            i := 0x300  i := hex v1  i := bighex v2  i += v3
+            sprite v0 v1 5  bcd v4  save v5  load v6
+            delay := v7  buzzer := v8  jump0 0x300  native 0x123";
+
+        assert_eq!(
+            vec![
+                0xA3, 0x00, 0xF1, 0x29, 0xF2, 0x30, 0xF3, 0x1E, 0xD0, 0x15, 0xF4, 0x33, 0xF5, 0x55,
+                0xF6, 0x65, 0xF7, 0x15, 0xF8, 0x18, 0xB3, 0x00, 0x01, 0x23,
+            ],
+            bytes(source)
+        );
+    }
+
+    #[test]
+    fn aliases_constants_and_data() {
+        let source = ":alias x v3 :const speed 4
+            : main x += speed i := data
+            : data 0xFF 0b1010 :byte 7";
+
+        assert_eq!(
+            vec![0x73, 0x04, 0xA2, 0x04, 0xFF, 0x0A, 0x07],
+            bytes(source)
+        );
+    }
+
+    #[test]
+    fn if_then() {
+        assert_eq!(
+            vec![0x40, 0x05, 0x00, 0xE0, 0x50, 0x10, 0x00, 0xE0, 0xE2, 0xA1, 0x00, 0xEE],
+            bytes(": main if v0 == 5 then clear if v0 != v1 then clear if v2 key then return")
+        );
+    }
+
+    #[test]
+    fn if_begin_else_end() {
+        // 200: SE V0, 1     skip the jump to else when v0 == 1
+        // 202: JP 20A
+        // 204: V1 := 1
+        // 206: V2 := 2
+        // 208: JP 20C
+        // 20A: V1 := 2
+        // 20C: CLS
+        assert_eq!(
+            vec![
+                0x30, 0x01, 0x12, 0x0A, 0x61, 0x01, 0x62, 0x02, 0x12, 0x0C, 0x61, 0x02, 0x00, 0xE0,
+            ],
+            bytes(": main if v0 == 1 begin v1 := 1 v2 := 2 else v1 := 2 end clear")
+        );
+    }
+
+    #[test]
+    fn loops() {
+        // 200: V0 += 1
+        // 202: SNE V0, 10   skip the way out while v0 != 10
+        // 204: JP 208
+        // 206: JP 200
+        // 208: CLS
+        assert_eq!(
+            vec![0x70, 0x01, 0x40, 0x0A, 0x12, 0x08, 0x12, 0x00, 0x00, 0xE0],
+            bytes(": main loop v0 += 1 while v0 != 10 again clear")
+        );
+    }
+
+    #[test]
+    fn ordering_comparisons() {
+        // if v1 < 5 then: vf := 5; vf =- v1; skip when vf == 1
+        assert_eq!(
+            vec![0x6F, 0x05, 0x8F, 0x17, 0x3F, 0x01, 0x00, 0xE0],
+            bytes(": main if v1 < 5 then clear")
+        );
+        // if v1 > v2 then: vf := v2; vf -= v1; skip when vf == 1
+        assert_eq!(
+            vec![0x8F, 0x20, 0x8F, 0x15, 0x3F, 0x01, 0x00, 0xE0],
+            bytes(": main if v1 > v2 then clear")
+        );
+    }
+
+    #[test]
+    fn forward_references_and_unpack() {
+        assert_eq!(
+            vec![0xA2, 0x06, 0x60, 0xA2, 0x61, 0x08, 0x00, 0x01, 0x00, 0x02],
+            bytes(": main i := later :unpack 0xA data : later 0 1 : data 0 2")
+        );
+    }
+
+    #[test]
+    fn macros() {
+        let source = ":macro twice reg { reg += 1 reg += 1 }
+            : main twice v3 twice v4";
+
+        assert_eq!(
+            vec![0x73, 0x01, 0x73, 0x01, 0x74, 0x01, 0x74, 0x01],
+            bytes(source)
+        );
+    }
+
+    #[test]
+    fn comments_and_source_map() {
+        let assembly = assemble(": main # entry\n  clear\n  v0 := 1 # set\n").unwrap();
+
+        assert_eq!(Some(0x200), assembly.labels.get("main").cloned());
+        assert_eq!(
+            Some(Span {
+                line: 3,
+                column: 3,
+                length: 2
+            }),
+            assembly.span_at(0x202)
+        );
+        assert_eq!(None, assembly.span_at(0x203));
+    }
+
+    #[test]
+    fn errors_have_spans() {
+        let error = assemble(": main\n  v0 := 300").unwrap_err();
+        assert_eq!(2, error.span.line);
+        assert_eq!(9, error.span.column);
+
+        let error = assemble(": main\n  jump nowhere").unwrap_err();
+        assert_eq!("2:8: Undefined name nowhere", error.to_string());
+
+        assert!(assemble("clear").is_err());
+        assert!(assemble(": main loop clear").is_err());
+        assert!(assemble(": main hires").is_err());
+    }
+
+    #[test]
+    fn numbers_out_of_range() {
+        let source = ":const big 0x9000 :const minus -1 : main v0 := minus v1 := -128";
+        assert_eq!(vec![0x60, 0xFF, 0x61, 0x80], bytes(source));
+        assert_eq!(
+            "1:13: 0x10000 does not fit in 0xffff",
+            assemble(":const huge 0x10000").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "1:25: big does not fit in 0xff",
+            assemble(":const big 0x9000 v0 := big")
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(assemble(":const big 0x9000 : main jump big").is_err());
+        assert!(assemble(": main v0 := -129").is_err());
+    }
+
+    #[test]
+    fn org_at_the_top_of_memory() {
+        let error = assemble(": main clear :org 0xFFFF 1 2").unwrap_err();
+        assert_eq!(
+            "1:28: Program runs past the end of memory",
+            error.to_string()
+        );
+        let error = assemble(": main clear :org 0xFFFF clear").unwrap_err();
+        assert_eq!(26, error.span.column);
+    }
+
+    #[test]
+    fn forward_jumps_past_0xfff() {
+        assert_eq!(
+            "1:13: later is not a 12 bit address",
+            assemble(": main jump later :org 0x1000 : later clear")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "1:45: 0x1002 is not a 12 bit address",
+            assemble(": main if v0 == 1 begin :org 0x1000 v1 := 2 end")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "1:31: 0x1000 is not a 12 bit address",
+            assemble(": main :org 0x1000 loop clear again")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+}
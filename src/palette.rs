@@ -0,0 +1,189 @@
+use std::str::FromStr;
+
+use display::WIDTH;
+
+// The colors the screen is shown in, one for every combination of display
+// planes. Plane 1 is bit 0 of the index and plane 2 bit 1, the way XO-CHIP
+// numbers them, so index 0 is the background and index 3 is both planes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Palette {
+    // As 0xRRGGBB
+    pub colors: [u32; 4],
+}
+
+impl Palette {
+    // For programs that only draw on one plane. Anything on the second plane
+    // shows in the foreground too
+    pub fn two_color(background: u32, foreground: u32) -> Palette {
+        Palette {
+            colors: [background, foreground, foreground, foreground],
+        }
+    }
+
+    // mono, green, amber or octo
+    pub fn builtin(name: &str) -> Option<Palette> {
+        let colors = match name {
+            "mono" => [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555],
+            "green" => [0x0A1A0A, 0x33FF66, 0x1A8033, 0xB3FFC6],
+            "amber" => [0x1A0F00, 0xFFB000, 0x805800, 0xFFE0A0],
+            // What Octo uses when a program doesn't pick its own
+            "octo" => [0x996600, 0xFFCC00, 0xFF6600, 0x662200],
+            _ => return None,
+        };
+
+        Some(Palette { colors })
+    }
+
+    // The color where the planes in the bits of planes are lit
+    pub fn color(&self, planes: usize) -> u32 {
+        self.colors[planes & 3]
+    }
+
+    // RGB for every pixel, given the screen levels of each plane. Pixels
+    // that are partly lit, like ones fading out, mix the colors of the
+    // plane combinations in proportion.
+    pub fn rgb(&self, planes: &[&[u8]]) -> Vec<[u8; 3]> {
+        if planes.is_empty() || planes.len() > 2 {
+            panic!("Palettes cover one or two planes, not {}", planes.len());
+        }
+
+        (0..planes[0].len())
+            .map(|index| {
+                let mut mixed = [0.0; 3];
+                for combination in 0..1 << planes.len() {
+                    let weight: f64 = planes
+                        .iter()
+                        .enumerate()
+                        .map(|(plane, levels)| {
+                            let level = levels[index] as f64 / 255.0;
+                            if combination & (1 << plane) != 0 {
+                                level
+                            } else {
+                                1.0 - level
+                            }
+                        })
+                        .product();
+
+                    let color = self.color(combination);
+                    for (channel, value) in mixed.iter_mut().enumerate() {
+                        *value += weight * ((color >> (16 - channel * 8)) & 0xFF) as f64;
+                    }
+                }
+
+                [
+                    mixed[0].round() as u8,
+                    mixed[1].round() as u8,
+                    mixed[2].round() as u8,
+                ]
+            })
+            .collect()
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::builtin("mono").unwrap()
+    }
+}
+
+// Written as the name of a built-in palette, or as two or four hex colors
+// separated by commas: background and foreground, or all four in order
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Palette, String> {
+        if let Some(palette) = Palette::builtin(text) {
+            return Ok(palette);
+        }
+
+        let colors = text
+            .split(',')
+            .map(|color| parse_color(color.trim()))
+            .collect::<Result<Vec<u32>, String>>()?;
+
+        match colors.len() {
+            2 => Ok(Palette::two_color(colors[0], colors[1])),
+            4 => Ok(Palette {
+                colors: [colors[0], colors[1], colors[2], colors[3]],
+            }),
+            _ => Err(format!("{} is not a palette", text)),
+        }
+    }
+}
+
+// Hex RGB, with or without a leading #
+pub(crate) fn parse_color(value: &str) -> Result<u32, String> {
+    let value = value.trim_start_matches('#');
+
+    if value.len() != 6 {
+        return Err(format!("{} is not an RGB color", value));
+    }
+
+    u32::from_str_radix(value, 16).map_err(|_| format!("{} is not an RGB color", value))
+}
+
+// Colors for a terminal that understands 24 bit ANSI escapes. Each
+// character is a half block showing two rows, so the screen keeps its shape
+pub fn render_ansi(pixels: &[[u8; 3]]) -> String {
+    let mut screen = String::new();
+    let rows: Vec<&[[u8; 3]]> = pixels.chunks(WIDTH).collect();
+
+    for pair in rows.chunks(2) {
+        for x in 0..WIDTH {
+            let top = pair[0][x];
+            let bottom = pair.get(1).map_or([0; 3], |row| row[x]);
+            screen += &format!(
+                "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+            );
+        }
+        screen += "\x1b[0m\n";
+    }
+
+    screen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plane_combinations() {
+        let palette = Palette::builtin("octo").unwrap();
+        let first: &[u8] = &[0, 255, 0, 255];
+        let second: &[u8] = &[0, 0, 255, 255];
+
+        assert_eq!(
+            vec![
+                [0x99, 0x66, 0x00],
+                [0xFF, 0xCC, 0x00],
+                [0xFF, 0x66, 0x00],
+                [0x66, 0x22, 0x00]
+            ],
+            palette.rgb(&[first, second])
+        );
+    }
+
+    #[test]
+    fn fading_pixels_mix() {
+        let palette = Palette::two_color(0x000000, 0xFF8000);
+        assert_eq!(vec![[0x80, 0x40, 0x00]], palette.rgb(&[&[128]]));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Palette::builtin("amber"), "amber".parse().ok());
+        assert_eq!(
+            Ok(Palette::two_color(0x000000, 0x33FF66)),
+            "000000,#33ff66".parse::<Palette>()
+        );
+        assert_eq!(
+            Ok([1, 2, 3, 4]),
+            "000001, 000002, 000003, 000004"
+                .parse::<Palette>()
+                .map(|palette| palette.colors)
+        );
+        assert!("000000,111111,222222".parse::<Palette>().is_err());
+        assert!("purple".parse::<Palette>().is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use display::{Chip8Display, HEIGHT, WIDTH};
+
+// How long pixels stay visible after they're turned off. XOR drawing means
+// moving sprites are erased and redrawn every frame, and without some
+// persistence they flicker.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Persistence {
+    #[default]
+    Off,
+    // Pixels fade out evenly over this many frames, like a CRT phosphor
+    Decay(u8),
+    // Pixels show at full brightness if they were on in any of this many
+    // most recent frames
+    Blend(u8),
+}
+
+// Written as off, decay:N or blend:N
+impl FromStr for Persistence {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Persistence, String> {
+        let error = || format!("{} is not a persistence mode", text);
+        if text == "off" {
+            return Ok(Persistence::Off);
+        }
+
+        let mut parts = text.splitn(2, ':');
+        let mode = parts.next().unwrap_or("");
+        let frames = parts
+            .next()
+            .and_then(|frames| frames.parse::<u8>().ok())
+            .filter(|frames| *frames > 0)
+            .ok_or_else(error)?;
+
+        match mode {
+            "decay" => Ok(Persistence::Decay(frames)),
+            "blend" => Ok(Persistence::Blend(frames)),
+            _ => Err(error()),
+        }
+    }
+}
+
+// Turns the display into a brightness from 0 to 255 for every pixel, one
+// frame at a time. Everything that shows the screen goes through this, so
+// they all agree on what it looks like.
+pub struct Phosphor {
+    persistence: Persistence,
+    levels: Vec<u8>,
+    history: VecDeque<[u64; HEIGHT]>,
+}
+
+impl Phosphor {
+    pub fn new(persistence: Persistence) -> Phosphor {
+        Phosphor {
+            persistence,
+            levels: vec![0; WIDTH * HEIGHT],
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn persistence(&self) -> Persistence {
+        self.persistence
+    }
+
+    // Called once at the end of every frame
+    pub fn update(&mut self, display: &Chip8Display) {
+        let mut rows = [0; HEIGHT];
+        for (y, row) in rows.iter_mut().enumerate() {
+            *row = display.row(y);
+        }
+
+        match self.persistence {
+            Persistence::Off => self.show(&rows),
+            Persistence::Decay(frames) => {
+                let step = 255u16.div_ceil(frames as u16);
+                for (index, level) in self.levels.iter_mut().enumerate() {
+                    if lit(&rows, index) {
+                        *level = 255;
+                    } else {
+                        *level = level.saturating_sub(step as u8);
+                    }
+                }
+            }
+            Persistence::Blend(frames) => {
+                self.history.push_back(rows);
+                while self.history.len() > frames as usize {
+                    self.history.pop_front();
+                }
+
+                let mut blended = [0; HEIGHT];
+                for frame in self.history.iter() {
+                    for (row, past) in blended.iter_mut().zip(frame.iter()) {
+                        *row |= past;
+                    }
+                }
+                self.show(&blended);
+            }
+        }
+    }
+
+    fn show(&mut self, rows: &[u64; HEIGHT]) {
+        for (index, level) in self.levels.iter_mut().enumerate() {
+            *level = if lit(rows, index) { 255 } else { 0 };
+        }
+    }
+
+    // Row by row from the top left
+    pub fn levels(&self) -> &[u8] {
+        &self.levels
+    }
+}
+
+fn lit(rows: &[u64; HEIGHT], index: usize) -> bool {
+    let (x, y) = (index % WIDTH, index / WIDTH);
+    rows[y] & (1 << (WIDTH - 1 - x)) != 0
+}
+
+// Levels as text for the terminal. Fully on and off pixels come out as the
+// * and - the display itself prints, with fading ones in between
+pub fn render_text(levels: &[u8]) -> String {
+    const SHADES: [char; 5] = ['-', '.', ':', '+', '*'];
+    let mut screen = String::new();
+
+    for row in levels.chunks(WIDTH) {
+        for level in row {
+            let shade = match *level {
+                0 => 0,
+                255 => 4,
+                level => 1 + level as usize * 3 / 255,
+            };
+            screen.push(SHADES[shade]);
+        }
+        screen.push('\n');
+    }
+
+    screen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flicker(persistence: Persistence, frames: usize) -> Phosphor {
+        let mut display = Chip8Display::default();
+        let mut phosphor = Phosphor::new(persistence);
+
+        display.draw_pixel(0, 0, true);
+        phosphor.update(&display);
+        display.draw_pixel(0, 0, true);
+        for _ in 0..frames {
+            phosphor.update(&display);
+        }
+
+        phosphor
+    }
+
+    #[test]
+    fn off_shows_the_display() {
+        assert_eq!(0, flicker(Persistence::Off, 1).levels()[0]);
+    }
+
+    #[test]
+    fn decay_fades_over_frames() {
+        let levels: Vec<u8> = (1..=4)
+            .map(|frames| flicker(Persistence::Decay(4), frames).levels()[0])
+            .collect();
+
+        assert_eq!(vec![191, 127, 63, 0], levels);
+    }
+
+    #[test]
+    fn blend_keeps_recent_frames() {
+        assert_eq!(255, flicker(Persistence::Blend(3), 2).levels()[0]);
+        assert_eq!(0, flicker(Persistence::Blend(3), 3).levels()[0]);
+    }
+
+    #[test]
+    fn text_matches_the_display() {
+        let mut display = Chip8Display::default();
+        display.draw_row(0, 0, 0xF0);
+        let mut phosphor = Phosphor::new(Persistence::Off);
+        phosphor.update(&display);
+
+        assert_eq!(format!("{:?}", display), render_text(phosphor.levels()));
+        assert_eq!(Ok(Persistence::Decay(6)), "decay:6".parse::<Persistence>());
+        assert!("blend:0".parse::<Persistence>().is_err());
+    }
+}
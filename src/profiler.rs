@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use disassembler;
+use instructions::Instruction;
+use symbols::Symbols;
+
+// Counts what a program spends its time on. Every instruction is one cycle,
+// charged to its address, its kind of instruction and the chain of
+// subroutines that were active when it ran.
+pub struct Profiler {
+    load_address: u16,
+    pub cycles: u64,
+    pub addresses: BTreeMap<u16, u64>,
+    pub instructions: BTreeMap<&'static str, u64>,
+    // Subroutine entry points from the outermost in, always starting with
+    // the load address
+    pub stacks: BTreeMap<Vec<u16>, u64>,
+    decoded: BTreeMap<u16, Instruction>,
+}
+
+impl Profiler {
+    pub fn new(load_address: u16) -> Profiler {
+        Profiler {
+            load_address,
+            cycles: 0,
+            addresses: BTreeMap::new(),
+            instructions: BTreeMap::new(),
+            stacks: BTreeMap::new(),
+            decoded: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, pc: u16, instruction: Option<&Instruction>, stack: Vec<u16>) {
+        self.cycles += 1;
+        *self.addresses.entry(pc).or_insert(0) += 1;
+        *self.stacks.entry(stack).or_insert(0) += 1;
+
+        if let Some(instruction) = instruction {
+            *self.instructions.entry(instruction.name()).or_insert(0) += 1;
+            self.decoded.insert(pc, *instruction);
+        }
+    }
+
+    // Cycles spent in each subroutine itself, and including what it called
+    pub fn subroutines(&self) -> BTreeMap<u16, (u64, u64)> {
+        let mut subroutines = BTreeMap::new();
+
+        for (stack, &count) in self.stacks.iter() {
+            if let Some(innermost) = stack.last() {
+                subroutines.entry(*innermost).or_insert((0, 0)).0 += count;
+            }
+
+            // Recursion shouldn't count the same cycles twice
+            let mut seen = stack.clone();
+            seen.sort();
+            seen.dedup();
+            for entry in seen {
+                subroutines.entry(entry).or_insert((0, 0)).1 += count;
+            }
+        }
+
+        subroutines
+    }
+
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let mut report = String::new();
+        let percent = |count: u64| 100.0 * count as f64 / self.cycles.max(1) as f64;
+
+        writeln!(report, "{} cycles", self.cycles).unwrap();
+
+        writeln!(report, "\nHottest addresses").unwrap();
+        let mut addresses: Vec<(&u16, &u64)> = self.addresses.iter().collect();
+        addresses.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (&address, &count) in addresses.into_iter().take(20) {
+            let text = match self.decoded.get(&address) {
+                Some(instruction) => disassembler::instruction_text(instruction, symbols),
+                None => "??".to_string(),
+            };
+            writeln!(
+                report,
+                "{:>10} {:>6.2}%  {:03x} {:<12} {}",
+                count,
+                percent(count),
+                address,
+                symbols.describe(address),
+                text
+            )
+            .unwrap();
+        }
+
+        writeln!(report, "\nInstructions").unwrap();
+        let mut instructions: Vec<(&&str, &u64)> = self.instructions.iter().collect();
+        instructions.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (name, &count) in instructions {
+            writeln!(report, "{:>10} {:>6.2}%  {}", count, percent(count), name).unwrap();
+        }
+
+        writeln!(report, "\nSubroutines (self, total)").unwrap();
+        let mut subroutines: Vec<(u16, (u64, u64))> = self.subroutines().into_iter().collect();
+        subroutines.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(&b.0)));
+        for (entry, (own, total)) in subroutines {
+            writeln!(
+                report,
+                "{:>10} {:>6.2}% {:>10} {:>6.2}%  {}",
+                own,
+                percent(own),
+                total,
+                percent(total),
+                self.name(entry, symbols)
+            )
+            .unwrap();
+        }
+
+        report
+    }
+
+    // One line per call stack with the frames separated by semicolons and
+    // the cycles spent there, which flamegraph.pl and friends read directly
+    pub fn collapsed_stacks(&self, symbols: &Symbols) -> String {
+        let mut collapsed = String::new();
+
+        for (stack, count) in self.stacks.iter() {
+            let frames: Vec<String> = stack
+                .iter()
+                .map(|entry| self.name(*entry, symbols))
+                .collect();
+            writeln!(collapsed, "{} {}", frames.join(";"), count).unwrap();
+        }
+
+        collapsed
+    }
+
+    fn name(&self, entry: u16, symbols: &Symbols) -> String {
+        match symbols.name(entry) {
+            Some(name) => name.to_string(),
+            None if entry == self.load_address => "main".to_string(),
+            None => format!("sub_{:03x}", entry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octo::assemble;
+    use system::Chip8Machine;
+
+    fn profile(source: &str, steps: usize) -> (Chip8Machine, Symbols) {
+        let assembly = assemble(source).unwrap();
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&assembly.bytes);
+        machine.set_profiling(true);
+        for _ in 0..steps {
+            machine.execute();
+        }
+
+        (machine, Symbols::from_assembly(&assembly, None))
+    }
+
+    const PROGRAM: &str = ": main\n  inner\n  outer\n: halt jump halt\n: outer\n  inner\n  return\n: inner\n  v0 += 1\n  return";
+
+    #[test]
+    fn counts_addresses_and_instructions() {
+        let (machine, _) = profile(PROGRAM, 12);
+        let profiler = machine.profiler().unwrap();
+
+        assert_eq!(12, profiler.cycles);
+        assert_eq!(Some(&2), profiler.addresses.get(&0x20A));
+        assert_eq!(Some(&4), profiler.addresses.get(&0x204));
+        assert_eq!(Some(&2), profiler.instructions.get("ADDC"));
+        assert_eq!(Some(&3), profiler.instructions.get("RET"));
+    }
+
+    #[test]
+    fn attributes_cycles_to_the_call_stack() {
+        let (machine, symbols) = profile(PROGRAM, 12);
+        let profiler = machine.profiler().unwrap();
+
+        assert_eq!(
+            "main 6\nmain;outer 2\nmain;outer;inner 2\nmain;inner 2\n",
+            profiler.collapsed_stacks(&symbols)
+        );
+
+        let subroutines = profiler.subroutines();
+        assert_eq!((4, 4), subroutines[&0x20A]);
+        assert_eq!((2, 4), subroutines[&0x206]);
+        assert_eq!((6, 12), subroutines[&0x200]);
+    }
+
+    #[test]
+    fn report_lists_hot_spots() {
+        let (machine, symbols) = profile(PROGRAM, 12);
+        let report = machine.profiler().unwrap().report(&symbols);
+
+        assert!(report.starts_with("12 cycles\n"));
+        assert!(report.contains("halt         jump halt"));
+        assert!(report.contains("     4  33.33%          4  33.33%  inner"));
+    }
+}
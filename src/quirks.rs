@@ -0,0 +1,41 @@
+// Behaviours that differ between CHIP-8 interpreters. Programs written for
+// one interpreter often depend on its particular choices, so these can be
+// switched to match whatever a program expects.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Quirks {
+    // 8XY6 and 8XYE shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    // FX55 and FX65 leave I pointing past the last register
+    pub load_store_increments_i: bool,
+    // BNNN jumps to NNN + VX, where X is the high nibble of NNN
+    pub jump_uses_vx: bool,
+    // 8XY1, 8XY2 and 8XY3 clear VF
+    pub logic_resets_vf: bool,
+    // DXYN wraps the parts of a sprite that go past an edge around to the
+    // other side instead of clipping them
+    pub wrap_sprites: bool,
+}
+
+impl Quirks {
+    // The original interpreter on the COSMAC VIP
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            wrap_sprites: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1 on the HP 48
+    pub fn super_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            wrap_sprites: false,
+        }
+    }
+}
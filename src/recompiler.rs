@@ -0,0 +1,153 @@
+use std::rc::Rc;
+
+use instructions::Instruction;
+use memory;
+
+// Long straight-line runs are split so a single block can't hold up the
+// caller for too long
+const MAX_BLOCK_LENGTH: usize = 64;
+
+// A basic block: a run of predecoded instructions where only the last one
+// can change the flow of control. Every instruction keeps its address so a
+// block can be left part way through.
+pub struct Block {
+    pub ops: Vec<(u16, Instruction)>,
+    pub end: u16,
+    pub writes_memory: bool,
+}
+
+pub fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        *instruction,
+        Instruction::SYS(_)
+            | Instruction::RET
+            | Instruction::JP(_)
+            | Instruction::CALL(_)
+            | Instruction::SEC(_, _)
+            | Instruction::SNEC(_, _)
+            | Instruction::SER(_, _)
+            | Instruction::SNE(_, _)
+            | Instruction::JPA(_)
+            | Instruction::DRW(_, _, _)
+            | Instruction::SKP(_)
+            | Instruction::SKNP(_)
+            | Instruction::LDVK(_)
+    )
+}
+
+pub fn writes_memory(instruction: &Instruction) -> bool {
+    matches!(*instruction, Instruction::LDBR(_) | Instruction::LDRS(_))
+}
+
+fn compile(memory: &mut memory::Chip8Memory, start: u16) -> Block {
+    let mut ops = Vec::new();
+    // Counted wider than PC so a block can run right up to the top of 64K
+    let mut address = start as usize;
+
+    while address < memory.size() - 1 && ops.len() < MAX_BLOCK_LENGTH {
+        // A zero word is where run() stops, so it has to stay outside of
+        // any block for run() to see it
+        if memory.read_instruction(address) == 0 {
+            break;
+        }
+
+        memory.mark_code(address);
+        let instruction = memory.decode_instruction(address);
+        address += 2;
+
+        // Words that don't decode are skipped over just like the
+        // interpreter does
+        if let Some(instruction) = instruction {
+            ops.push(((address - 2) as u16, instruction));
+
+            if ends_block(&instruction) {
+                break;
+            }
+        }
+    }
+
+    let writes_memory = ops
+        .iter()
+        .any(|(_, instruction)| writes_memory(instruction));
+
+    Block {
+        ops,
+        end: address as u16,
+        writes_memory,
+    }
+}
+
+// Blocks are looked up by their start address
+pub struct BlockCache {
+    blocks: Vec<Option<Rc<Block>>>,
+}
+
+impl BlockCache {
+    pub fn new(memory_size: usize) -> BlockCache {
+        BlockCache {
+            blocks: vec![None; memory_size],
+        }
+    }
+
+    pub fn get(&mut self, memory: &mut memory::Chip8Memory, address: u16) -> Rc<Block> {
+        // Anything written over compiled code throws the whole cache away.
+        // Self-modifying programs are rare enough that finer tracking isn't
+        // worth it
+        if memory.take_code_written() {
+            for block in self.blocks.iter_mut() {
+                *block = None;
+            }
+            memory.clear_code();
+        }
+
+        self.blocks[address as usize]
+            .get_or_insert_with(|| Rc::new(compile(memory, address)))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use registers::Register;
+
+    fn memory_with(program: &[u8]) -> memory::Chip8Memory {
+        let mut memory = memory::Chip8Memory::default();
+        for (offset, byte) in program.iter().enumerate() {
+            memory.write(0x200 + offset, *byte);
+        }
+
+        memory
+    }
+
+    #[test]
+    fn block_ends_at_jump() {
+        let mut memory = memory_with(&[0x60, 0x01, 0x61, 0x02, 0x12, 0x00, 0x62, 0x03]);
+        let block = compile(&mut memory, 0x200);
+
+        assert_eq!(3, block.ops.len());
+        assert_eq!((0x204, Instruction::JP(0x200)), block.ops[2]);
+        assert_eq!(0x206, block.end);
+    }
+
+    #[test]
+    fn block_stops_before_zero_word() {
+        let mut memory = memory_with(&[0x60, 0x01, 0x00, 0x00]);
+        let block = compile(&mut memory, 0x200);
+
+        assert_eq!(vec![(0x200, Instruction::LDC(Register::V0, 1))], block.ops);
+        assert_eq!(0x202, block.end);
+    }
+
+    #[test]
+    fn write_to_code_flushes_cache() {
+        let mut memory = memory_with(&[0x60, 0x01, 0x12, 0x00]);
+        let mut cache = BlockCache::new(memory::MEMORY_SIZE);
+
+        cache.get(&mut memory, 0x200);
+        memory.write(0x201, 0x07);
+
+        let block = cache.get(&mut memory, 0x200);
+        assert_eq!((0x200, Instruction::LDC(Register::V0, 7)), block.ops[0]);
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Chip8Registers {
     v0: u8,
     v1: u8,
@@ -20,7 +20,6 @@ pub struct Chip8Registers {
     pub delay: u8,
     pub sound: u8,
     pub pc: u16,
-    pub sp: u8,
 }
 
 impl Default for Chip8Registers {
@@ -46,12 +45,24 @@ impl Default for Chip8Registers {
             delay: 0,
             sound: 0,
             pc: 512,
-            sp: 0,
         }
     }
 }
 
 impl Chip8Registers {
+    pub fn new(pc: u16) -> Chip8Registers {
+        Chip8Registers {
+            pc,
+            ..Chip8Registers::default()
+        }
+    }
+
+    // Moves PC past an instruction, wrapping around from the top of a full
+    // 64K of memory to the bottom
+    pub fn advance(&mut self) {
+        self.pc = self.pc.wrapping_add(2);
+    }
+
     pub fn get(&self, register: Register) -> u8 {
         match register {
             Register::V0 => self.v0,
@@ -134,7 +145,7 @@ impl Register {
             13 => Register::VD,
             14 => Register::VE,
             15 => Register::VF,
-            _ => panic!(format!("Cannot create Register from value {}", value)),
+            _ => panic!("Cannot create Register from value {}", value),
         }
     }
 }
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use builder::{Chip8MachineBuilder, MachineMode};
+use env::Score;
+use palette::parse_color;
+use quirks::Quirks;
+use script::Condition;
+use sha1;
+
+const EMBEDDED: &str = include_str!("romdb.txt");
+
+// Everything the database knows about one ROM. Settings that aren't given
+// are left as whatever the machine would otherwise use
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct RomInfo {
+    pub title: String,
+    pub mode: Option<MachineMode>,
+    pub quirks: Option<Quirks>,
+    pub tick_rate: Option<u32>,
+    pub keymap: Option<String>,
+    pub colors: Option<(u32, u32)>,
+    // Where the game keeps its score and how to tell it's over, for
+    // playing it as a learning environment
+    pub score: Option<Score>,
+    pub game_over: Option<Condition>,
+}
+
+impl RomInfo {
+    pub fn configure(&self, mut builder: Chip8MachineBuilder) -> Chip8MachineBuilder {
+        if let Some(mode) = self.mode {
+            builder = builder.mode(mode);
+        }
+        if let Some(quirks) = self.quirks {
+            builder = builder.quirks(quirks);
+        }
+        if let Some(tick_rate) = self.tick_rate {
+            builder = builder.tick_rate(tick_rate);
+        }
+
+        builder
+    }
+}
+
+// ROMs are looked up by the lowercase hex SHA-1 of the whole file
+#[derive(Default)]
+pub struct RomDatabase {
+    entries: HashMap<String, RomInfo>,
+}
+
+impl RomDatabase {
+    pub fn embedded() -> RomDatabase {
+        RomDatabase::parse(EMBEDDED)
+            .unwrap_or_else(|error| panic!("Embedded ROM database: {}", error))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<RomDatabase, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|error| error.to_string())?;
+
+        RomDatabase::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<RomDatabase, String> {
+        let mut entries = HashMap::new();
+        let mut current: Option<(String, RomInfo)> = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let line_number = index + 1;
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Some((hash, info)) = current.take() {
+                    entries.insert(hash, info);
+                }
+
+                let hash = line[1..line.len() - 1].trim().to_lowercase();
+                if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!(
+                        "line {}: {} is not a SHA-1 hash",
+                        line_number, hash
+                    ));
+                }
+
+                current = Some((hash, RomInfo::default()));
+                continue;
+            }
+
+            let info = match current {
+                Some((_, ref mut info)) => info,
+                None => return Err(format!("line {}: setting outside of an entry", line_number)),
+            };
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(format!("line {}: expected key = value", line_number)),
+            };
+
+            parse_setting(info, key, value)
+                .map_err(|error| format!("line {}: {}", line_number, error))?;
+        }
+
+        if let Some((hash, info)) = current {
+            entries.insert(hash, info);
+        }
+
+        Ok(RomDatabase { entries })
+    }
+
+    // Entries from other replace entries for the same ROM
+    pub fn merge(&mut self, other: RomDatabase) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&RomInfo> {
+        self.entries.get(&hash.to_lowercase())
+    }
+
+    pub fn lookup(&self, program: &[u8]) -> Option<&RomInfo> {
+        self.get(&sha1::hex_digest(program))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+fn parse_setting(info: &mut RomInfo, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "title" => info.title = value.to_string(),
+        "platform" => info.mode = Some(value.parse()?),
+        "quirks" => info.quirks = Some(parse_quirks(value)?),
+        "tickrate" => {
+            let tick_rate = value
+                .parse()
+                .map_err(|_| format!("{} is not a tick rate", value))?;
+            info.tick_rate = Some(tick_rate);
+        }
+        "keymap" => info.keymap = Some(value.to_string()),
+        "score" => info.score = Some(value.parse()?),
+        "game_over" => info.game_over = Some(value.parse()?),
+        "colors" => {
+            let colors: Vec<&str> = value.split_whitespace().collect();
+            if colors.len() != 2 {
+                return Err("colors needs a background and a foreground".to_string());
+            }
+
+            info.colors = Some((parse_color(colors[0])?, parse_color(colors[1])?));
+        }
+        _ => return Err(format!("unknown setting {}", key)),
+    }
+
+    Ok(())
+}
+
+fn parse_quirks(value: &str) -> Result<Quirks, String> {
+    let mut quirks = Quirks::default();
+
+    for name in value.split(|c: char| c == ',' || c.is_whitespace()) {
+        match name {
+            "" | "none" => {}
+            "shift_uses_vy" => quirks.shift_uses_vy = true,
+            "load_store_increments_i" => quirks.load_store_increments_i = true,
+            "jump_uses_vx" => quirks.jump_uses_vx = true,
+            "logic_resets_vf" => quirks.logic_resets_vf = true,
+            "wrap_sprites" => quirks.wrap_sprites = true,
+            _ => return Err(format!("unknown quirk {}", name)),
+        }
+    }
+
+    Ok(quirks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octo::assemble;
+
+    #[test]
+    fn embedded_database_knows_the_bundled_roms() {
+        // The hash is of the program as assembled here, so it has to be
+        // updated along with the source
+        let catch = assemble(include_str!("../roms/catch.8o")).unwrap();
+        let database = RomDatabase::embedded();
+        let info = database.lookup(&catch.bytes).unwrap();
+
+        assert_eq!("Catch", info.title);
+        assert_eq!(Some(MachineMode::Chip8), info.mode);
+        assert_eq!(Some("qwerty".to_string()), info.keymap);
+        assert_eq!(Some("v6".parse().unwrap()), info.score);
+        assert_eq!(Some("v7 == 3".parse().unwrap()), info.game_over);
+    }
+
+    #[test]
+    fn lookup_by_hash() {
+        let database = RomDatabase::parse(
+            "[A9993E364706816ABA3E25717850C26C9CD0D89D]
+             title = Test
+             platform = vip
+             quirks = shift_uses_vy, logic_resets_vf
+             tickrate = 15
+             keymap = azerty
+             colors = 000000 #33FF66
+             score = digits memory[0x2F0..0x2F2]
+             game_over = v4 == 0",
+        )
+        .unwrap();
+
+        let info = database.lookup(b"abc").unwrap();
+        assert_eq!("Test", info.title);
+        assert_eq!(Some(MachineMode::CosmacVip), info.mode);
+        assert_eq!(
+            Some(Quirks {
+                shift_uses_vy: true,
+                logic_resets_vf: true,
+                ..Quirks::default()
+            }),
+            info.quirks
+        );
+        assert_eq!(Some(15), info.tick_rate);
+        assert_eq!(Some("azerty".to_string()), info.keymap);
+        assert_eq!(Some((0x000000, 0x33FF66)), info.colors);
+        assert_eq!(
+            Some("digits memory[0x2F0..0x2F2]".parse().unwrap()),
+            info.score
+        );
+        assert_eq!(Some("v4 == 0".parse().unwrap()), info.game_over);
+        assert!(database.lookup(b"abd").is_none());
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let error = RomDatabase::parse("[da39a3ee5e6b4b0d3255bfef95601890afd80709]\nspeed = 3")
+            .err()
+            .unwrap();
+        assert_eq!("line 2: unknown setting speed", error);
+
+        assert!(RomDatabase::parse("title = Orphan").is_err());
+        assert!(RomDatabase::parse("[1234]").is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use std::cell::Cell;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use speed::SpeedController;
+use system::Chip8Machine;
+
+const REFRESH_RATE: u64 = 60;
+
+// Monotonic time measured from whenever the clock was made
+pub trait Clock {
+    fn now(&self) -> Duration;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// A clock that only moves when told to, or when something sleeps on it
+#[derive(Default)]
+pub struct MockClock {
+    now: Cell<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct SchedulerStats {
+    pub refreshes: u64,
+    // Refreshes given up on because the host fell too far behind
+    pub dropped: u64,
+    pub frames: u64,
+    pub instructions: u64,
+    pub elapsed: Duration,
+    // How many seconds late the last refreshes to run started, measured
+    // from when the earliest of them was due
+    pub drift: f64,
+}
+
+impl SchedulerStats {
+    pub fn instructions_per_second(&self) -> f64 {
+        if self.elapsed == Duration::from_secs(0) {
+            return 0.0;
+        }
+
+        self.instructions as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+// Paces a machine against a clock: a display refresh every 60th of a
+// second, each running the frames the speed controller asks for. A host
+// that falls behind runs several refreshes back to back to catch up, up
+// to max_catch_up, and drops the rest rather than racing to make them up.
+pub struct Scheduler<C: Clock> {
+    clock: C,
+    pub speed: SpeedController,
+    pub max_catch_up: u64,
+    // Index of the next refresh. Deadlines are worked out from it rather
+    // than added up, so rounding never builds up
+    next: u64,
+    stats: SchedulerStats,
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn new(clock: C) -> Scheduler<C> {
+        Scheduler {
+            clock,
+            speed: SpeedController::new(),
+            max_catch_up: 4,
+            next: 0,
+            stats: SchedulerStats::default(),
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        self.stats
+    }
+
+    // Sets the machine's tick rate to give instructions_per_second at
+    // normal speed, to the nearest whole instruction per frame
+    pub fn set_instructions_per_second(
+        &self,
+        machine: &mut Chip8Machine,
+        instructions_per_second: u32,
+    ) {
+        let tick_rate = (instructions_per_second as f64 / REFRESH_RATE as f64).round();
+        machine.set_tick_rate(tick_rate as u32);
+    }
+
+    fn deadline(&self, refresh: u64) -> Duration {
+        Duration::from_nanos(refresh * 1_000_000_000 / REFRESH_RATE)
+    }
+
+    // Runs every refresh that's come due and returns how many frames ran
+    pub fn run_due(&mut self, machine: &mut Chip8Machine) -> usize {
+        let now = self.clock.now();
+        if now < self.deadline(self.next) {
+            return 0;
+        }
+
+        // Every refresh with a deadline at or before now is due
+        let due = ((now.as_nanos() as u64 + 1) * REFRESH_RATE).div_ceil(1_000_000_000);
+        let behind = due - self.next;
+        if behind > self.max_catch_up {
+            let dropped = behind - self.max_catch_up;
+            self.stats.dropped += dropped;
+            self.next += dropped;
+        }
+
+        self.stats.drift = (now - self.deadline(self.next)).as_secs_f64();
+
+        let mut frames = 0;
+        while self.deadline(self.next) <= now && !machine.halted() {
+            // Turbo gets until the next refresh is due
+            let end = self.deadline(self.next + 1);
+            let clock = &self.clock;
+            let (ran, instructions) = self.speed.run_refresh(machine, || clock.now() >= end);
+
+            frames += ran;
+            self.stats.frames += ran as u64;
+            self.stats.instructions += instructions as u64;
+            self.stats.refreshes += 1;
+            self.next += 1;
+        }
+
+        self.stats.elapsed = self.clock.now();
+        frames
+    }
+
+    // Runs refreshes as they come due until the program stops, sleeping in
+    // between. on_refresh is called after each batch of them, even while
+    // paused when they run no frames, and can press keys or change the
+    // speed for the next
+    pub fn run<F: FnMut(&mut Chip8Machine, &mut SpeedController)>(
+        &mut self,
+        machine: &mut Chip8Machine,
+        mut on_refresh: F,
+    ) {
+        while !machine.halted() {
+            let refreshes = self.stats.refreshes;
+            self.run_due(machine);
+            if self.stats.refreshes > refreshes {
+                on_refresh(machine, &mut self.speed);
+            }
+            if machine.halted() {
+                break;
+            }
+
+            let now = self.clock.now();
+            let deadline = self.deadline(self.next);
+            if deadline > now {
+                self.clock.sleep(deadline - now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts up in v0 forever, at 600 instructions a second
+    fn machine() -> Chip8Machine {
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&[0x70, 0x01, 0x12, 0x00]);
+        Scheduler::new(MockClock::new()).set_instructions_per_second(&mut machine, 600);
+        machine
+    }
+
+    // Moves the clock to when a refresh is due
+    fn advance_to(scheduler: &Scheduler<MockClock>, refresh: u64) {
+        let now = scheduler.clock().now();
+        scheduler.clock().advance(scheduler.deadline(refresh) - now);
+    }
+
+    #[test]
+    fn keeps_to_the_clock() {
+        let mut machine = machine();
+        let mut scheduler = Scheduler::new(MockClock::new());
+
+        for refresh in 0..60 {
+            advance_to(&scheduler, refresh);
+            assert_eq!(1, scheduler.run_due(&mut machine));
+            assert_eq!(0, scheduler.run_due(&mut machine));
+        }
+        advance_to(&scheduler, 60);
+        scheduler.stats.elapsed = scheduler.clock().now();
+
+        let stats = scheduler.stats();
+        assert_eq!(10, machine.tick_rate());
+        assert_eq!(60, stats.refreshes);
+        assert_eq!(0, stats.dropped);
+        assert_eq!(600, stats.instructions);
+        assert!((stats.instructions_per_second() - 600.0).abs() < 0.001);
+        assert_eq!(0.0, stats.drift);
+    }
+
+    #[test]
+    fn catches_up_then_drops() {
+        let mut machine = machine();
+        let mut scheduler = Scheduler::new(MockClock::new());
+        assert_eq!(1, scheduler.run_due(&mut machine));
+
+        // Three refreshes late is made up
+        advance_to(&scheduler, 3);
+        assert_eq!(3, scheduler.run_due(&mut machine));
+        assert_eq!(0, scheduler.stats().dropped);
+        assert!(scheduler.stats().drift > 0.03);
+
+        // Ten is more than max_catch_up, so six are dropped
+        advance_to(&scheduler, 13);
+        assert_eq!(4, scheduler.run_due(&mut machine));
+        assert_eq!(6, scheduler.stats().dropped);
+        assert!(scheduler.stats().drift > 0.04);
+        assert_eq!(0, scheduler.run_due(&mut machine));
+    }
+
+    #[test]
+    fn speed_scales_frames_per_refresh() {
+        let mut machine = machine();
+        let mut scheduler = Scheduler::new(MockClock::new());
+        scheduler.speed.set_speed("2x".parse().unwrap());
+
+        for refresh in 0..30 {
+            advance_to(&scheduler, refresh);
+            scheduler.run_due(&mut machine);
+        }
+        assert_eq!(60, scheduler.stats().frames);
+        assert_eq!(30, scheduler.stats().refreshes);
+    }
+
+    #[test]
+    fn run_sleeps_until_the_program_stops() {
+        // Counts v0 to 30 then runs into a zero word
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&[0x70, 0x01, 0x30, 30, 0x12, 0x00]);
+        machine.set_tick_rate(3);
+        let mut scheduler = Scheduler::new(MockClock::new());
+
+        let mut refreshes = 0;
+        scheduler.run(&mut machine, |_, _| refreshes += 1);
+
+        assert!(machine.halted());
+        assert_eq!(30, refreshes);
+        assert_eq!(scheduler.deadline(29), scheduler.clock().now());
+    }
+
+    #[test]
+    fn run_keeps_calling_back_while_paused() {
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&[0x70, 0x01, 0x30, 30, 0x12, 0x00]);
+        machine.set_tick_rate(3);
+        let mut scheduler = Scheduler::new(MockClock::new());
+        scheduler.speed.set_paused(true);
+
+        let mut refreshes = 0;
+        scheduler.run(&mut machine, |_, speed| {
+            refreshes += 1;
+            if refreshes == 5 {
+                speed.set_paused(false);
+            }
+        });
+
+        assert!(machine.halted());
+        assert_eq!(35, refreshes);
+        assert_eq!(30, scheduler.stats().frames);
+    }
+}
@@ -0,0 +1,117 @@
+use std::io::{self, Write};
+
+use gif;
+use upscale::Image;
+
+// A binary PGM, the simplest greyscale image format that image viewers and
+// converters all read. Colors are turned into their brightness
+pub fn write_pgm<W: Write>(image: &Image, mut output: W) -> io::Result<()> {
+    write!(output, "P5\n{} {}\n255\n", image.width, image.height)?;
+    let grey: Vec<u8> = image
+        .pixels
+        .iter()
+        .map(|pixel| {
+            let [red, green, blue] = pixel.map(|channel| channel as u32);
+            ((red * 299 + green * 587 + blue * 114 + 500) / 1000) as u8
+        })
+        .collect();
+
+    output.write_all(&grey)
+}
+
+// A binary PPM, PGM's RGB sibling
+pub fn write_ppm<W: Write>(image: &Image, mut output: W) -> io::Result<()> {
+    write!(output, "P6\n{} {}\n255\n", image.width, image.height)?;
+    for pixel in &image.pixels {
+        output.write_all(pixel)?;
+    }
+
+    Ok(())
+}
+
+// A GIF. Fails if the image uses more than the 256 colors a GIF can hold,
+// which only happens mixing two fading planes, or is wider or taller than
+// the 16 bits a GIF has for each
+pub fn write_gif<W: Write>(image: &Image, mut output: W) -> io::Result<()> {
+    if image.width > u16::MAX as usize || image.height > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}x{} is too big for a GIF", image.width, image.height),
+        ));
+    }
+
+    let mut colors = Vec::new();
+    let mut indices = Vec::new();
+
+    for pixel in &image.pixels {
+        let index = match colors.iter().position(|color| color == pixel) {
+            Some(index) => index,
+            None => {
+                colors.push(*pixel);
+                colors.len() - 1
+            }
+        };
+        if index > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Too many colors for a GIF",
+            ));
+        }
+        indices.push(index as u8);
+    }
+
+    output.write_all(&gif::encode(
+        image.width as u16,
+        image.height as u16,
+        &colors,
+        &[indices],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use display::{HEIGHT, WIDTH};
+
+    #[test]
+    fn pgm_header_and_pixels() {
+        let mut pixels = vec![[0; 3]; WIDTH * HEIGHT];
+        pixels[1] = [255; 3];
+        pixels[2] = [255, 0, 0];
+        let mut image = Vec::new();
+        write_pgm(&Image::screen(pixels), &mut image).unwrap();
+
+        assert!(image.starts_with(b"P5\n64 32\n255\n\x00\xff\x4c\x00"));
+        assert_eq!(13 + WIDTH * HEIGHT, image.len());
+    }
+
+    #[test]
+    fn ppm_and_gif_keep_colors() {
+        let mut pixels = vec![[0x99, 0x66, 0x00]; WIDTH * HEIGHT];
+        pixels[1] = [0xFF, 0xCC, 0x00];
+        let screen = Image::screen(pixels);
+
+        let mut image = Vec::new();
+        write_ppm(&screen, &mut image).unwrap();
+        assert!(image.starts_with(b"P6\n64 32\n255\n\x99\x66\x00\xff\xcc\x00"));
+        assert_eq!(13 + WIDTH * HEIGHT * 3, image.len());
+
+        let mut image = Vec::new();
+        write_gif(&screen, &mut image).unwrap();
+        let decoded = gif::decode(&image).unwrap();
+        assert_eq!(&[0, 1, 0], &decoded.frames[0].indices[..3]);
+        assert_eq!(&[0x99, 0x66, 0x00, 0xFF, 0xCC, 0x00], &image[13..19]);
+    }
+
+    #[test]
+    fn gifs_too_wide() {
+        let wide = Image {
+            width: 0x10000,
+            height: 1,
+            pixels: vec![[0; 3]; 0x10000],
+        };
+
+        let error = write_gif(&wide, Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+    }
+}
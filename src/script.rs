@@ -0,0 +1,491 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use system::Chip8Machine;
+
+// How long a wait gives up after when the script doesn't say, a minute
+const DEFAULT_TIMEOUT: usize = 60 * 60;
+
+// Something about the machine a condition can look at
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Value {
+    Pc,
+    Index,
+    Register(u8),
+    Memory(u16),
+    DelayTimer,
+    SoundTimer,
+}
+
+impl Value {
+    pub(crate) fn read(&self, machine: &Chip8Machine) -> Result<u16, String> {
+        Ok(match *self {
+            Value::Pc => machine.pc(),
+            Value::Index => machine.index(),
+            Value::Register(register) => machine.register(register) as u16,
+            Value::Memory(address) => machine
+                .read_memory(address)
+                .ok_or_else(|| format!("{:#x} is past the end of memory", address))?
+                as u16,
+            Value::DelayTimer => machine.delay_timer() as u16,
+            Value::SoundTimer => machine.sound_timer() as u16,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    LessOrEqual,
+    GreaterOrEqual,
+    Less,
+    Greater,
+}
+
+// Longer operators come first so <= isn't read as <
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+// A comparison like PC == 0x2A4 or memory[0x300] >= 2, checked against a
+// running machine
+#[derive(Clone, PartialEq, Debug)]
+pub struct Condition {
+    value: Value,
+    comparison: Comparison,
+    operand: u16,
+    // As written in the script, for messages
+    text: String,
+}
+
+impl Condition {
+    fn parse(text: &str) -> Result<Condition, String> {
+        let (operator, comparison) = COMPARISONS
+            .iter()
+            .find(|&&(operator, _)| text.contains(operator))
+            .cloned()
+            .ok_or_else(|| format!("{} is not a comparison", text))?;
+
+        let mut sides = text.splitn(2, operator);
+        let value = parse_value(sides.next().unwrap_or("").trim())?;
+        let operand = parse_number(sides.next().unwrap_or("").trim())?;
+
+        Ok(Condition {
+            value,
+            comparison,
+            operand,
+            text: text.to_string(),
+        })
+    }
+
+    // Fails if it looks past the end of memory
+    pub fn read(&self, machine: &Chip8Machine) -> Result<u16, String> {
+        self.value.read(machine)
+    }
+
+    fn compare(&self, actual: u16) -> bool {
+        match self.comparison {
+            Comparison::Equal => actual == self.operand,
+            Comparison::NotEqual => actual != self.operand,
+            Comparison::LessOrEqual => actual <= self.operand,
+            Comparison::GreaterOrEqual => actual >= self.operand,
+            Comparison::Less => actual < self.operand,
+            Comparison::Greater => actual > self.operand,
+        }
+    }
+
+    pub fn holds(&self, machine: &Chip8Machine) -> bool {
+        self.read(machine).is_ok_and(|actual| self.compare(actual))
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Condition, String> {
+        Condition::parse(text)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Command {
+    // Holds the keys down for this many frames, then lets them go
+    Press(Vec<u8>, usize),
+    Wait(usize),
+    WaitUntil(Condition, usize),
+    Assert(Condition),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Step {
+    line: usize,
+    // The frame to wait for before running the command
+    frame: Option<usize>,
+    command: Command,
+}
+
+// Input for a program written out ahead of time, so games can be played
+// and checked without anyone at the keyboard. One command per line, each
+// of which can start with the frame to wait for:
+//
+//   # Start the game and check it reached the first level
+//   frame 120: press 5 for 3 frames
+//   wait until PC == 0x2A4
+//   wait until memory[0x300] == 1 within 600 frames
+//   assert V3 != 0
+//
+// Frames count from when the script starts. Keys are the hex digits of
+// the keypad and press holds for one frame unless it says how many. Waits
+// give up after a minute of frames unless they say otherwise. Conditions
+// compare PC, I, V0 to VF, DT, ST or memory[address] against a number.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+impl Script {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Script, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|error| error.to_string())?;
+
+        Script::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Script, String> {
+        let mut steps = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let line_number = index + 1;
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let step = parse_step(line, line_number)
+                .map_err(|error| format!("line {}: {}", line_number, error))?;
+            steps.push(step);
+        }
+
+        Ok(Script { steps })
+    }
+
+    // Plays the script and returns how many frames it took. Fails at the
+    // first assertion that doesn't hold, wait that times out or command
+    // that can't run because the program has stopped
+    pub fn run(&self, machine: &mut Chip8Machine) -> Result<usize, String> {
+        let mut driver = InputDriver { machine, frame: 0 };
+
+        for step in &self.steps {
+            driver
+                .run_step(step)
+                .map_err(|error| format!("line {}: {}", step.line, error))?;
+        }
+
+        Ok(driver.frame)
+    }
+}
+
+// Runs frames for a script and keeps count of them
+struct InputDriver<'a> {
+    machine: &'a mut Chip8Machine,
+    frame: usize,
+}
+
+impl<'a> InputDriver<'a> {
+    fn run_step(&mut self, step: &Step) -> Result<(), String> {
+        if let Some(frame) = step.frame {
+            if frame < self.frame {
+                return Err(format!(
+                    "frame {} has already passed, it's frame {}",
+                    frame, self.frame
+                ));
+            }
+            self.run_frames(frame - self.frame)?;
+        }
+
+        match step.command {
+            Command::Press(ref keys, frames) => {
+                for key in keys {
+                    self.machine.set_key(*key, true);
+                }
+                let result = self.run_frames(frames);
+                for key in keys {
+                    self.machine.set_key(*key, false);
+                }
+                result
+            }
+            Command::Wait(frames) => self.run_frames(frames),
+            Command::WaitUntil(ref condition, timeout) => self.wait_until(condition, timeout),
+            Command::Assert(ref condition) => {
+                let actual = condition.read(self.machine)?;
+                if condition.compare(actual) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "expected {} at frame {}, but it was {:#x}",
+                        condition, self.frame, actual
+                    ))
+                }
+            }
+        }
+    }
+
+    fn run_frames(&mut self, frames: usize) -> Result<(), String> {
+        for _ in 0..frames {
+            self.check_running()?;
+            self.machine.run_frame();
+            self.frame += 1;
+        }
+
+        Ok(())
+    }
+
+    // Checks after every instruction, so a PC that's only passed through
+    // still counts
+    fn wait_until(&mut self, condition: &Condition, timeout: usize) -> Result<(), String> {
+        condition.read(self.machine)?;
+        if condition.holds(self.machine) {
+            return Ok(());
+        }
+
+        for _ in 0..timeout {
+            self.check_running()?;
+            let held = self
+                .machine
+                .run_frame_until(|machine| condition.holds(machine));
+            self.frame += 1;
+            if held {
+                return Ok(());
+            }
+        }
+
+        Err(format!(
+            "gave up waiting until {} after {} frames",
+            condition, timeout
+        ))
+    }
+
+    fn check_running(&self) -> Result<(), String> {
+        if self.machine.halted() {
+            Err(format!("the program stopped at frame {}", self.frame))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn parse_step(line: &str, line_number: usize) -> Result<Step, String> {
+    let mut frame = None;
+    let mut command = line;
+
+    if let Some(rest) = line.strip_prefix("frame ") {
+        let mut parts = rest.splitn(2, ':');
+        frame = Some(parse_number(parts.next().unwrap_or(""))? as usize);
+        command = parts
+            .next()
+            .ok_or_else(|| "expected frame N: command".to_string())?
+            .trim();
+    }
+
+    Ok(Step {
+        line: line_number,
+        frame,
+        command: parse_command(command)?,
+    })
+}
+
+fn parse_command(text: &str) -> Result<Command, String> {
+    if let Some(condition) = text.strip_prefix("assert ") {
+        return Ok(Command::Assert(Condition::parse(condition)?));
+    }
+
+    if let Some(condition) = text.strip_prefix("wait until ") {
+        let mut parts = condition.splitn(2, " within ");
+        let condition = Condition::parse(parts.next().unwrap_or(""))?;
+        let timeout = match parts.next() {
+            Some(timeout) => parse_frames(timeout)?,
+            None => DEFAULT_TIMEOUT,
+        };
+        return Ok(Command::WaitUntil(condition, timeout));
+    }
+
+    if let Some(frames) = text.strip_prefix("wait ") {
+        return Ok(Command::Wait(parse_frames(frames)?));
+    }
+
+    if let Some(arguments) = text.strip_prefix("press ") {
+        let mut parts = arguments.splitn(2, " for ");
+        let keys = parts
+            .next()
+            .unwrap_or("")
+            .split_whitespace()
+            .map(|key| match u8::from_str_radix(key, 16) {
+                Ok(key) if key <= 0xF => Ok(key),
+                _ => Err(format!("{} is not a key", key)),
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+        if keys.is_empty() {
+            return Err("press needs a key".to_string());
+        }
+
+        let frames = match parts.next() {
+            Some(frames) => parse_frames(frames)?,
+            None => 1,
+        };
+        return Ok(Command::Press(keys, frames));
+    }
+
+    Err(format!("unknown command {}", text))
+}
+
+// N frames, or 1 frame
+fn parse_frames(text: &str) -> Result<usize, String> {
+    let mut words = text.split_whitespace();
+    let count = words.next().unwrap_or("");
+    match (words.next(), words.next()) {
+        (Some("frame"), None) | (Some("frames"), None) => Ok(parse_number(count)? as usize),
+        _ => Err(format!("expected N frames, not {}", text)),
+    }
+}
+
+pub(crate) fn parse_value(text: &str) -> Result<Value, String> {
+    let lower = text.to_lowercase();
+
+    if lower.starts_with("memory[") && lower.ends_with(']') {
+        return Ok(Value::Memory(parse_number(
+            &lower["memory[".len()..lower.len() - 1],
+        )?));
+    }
+
+    if lower.len() == 2 && lower.starts_with('v') {
+        if let Ok(register) = u8::from_str_radix(&lower[1..], 16) {
+            return Ok(Value::Register(register));
+        }
+    }
+
+    match lower.as_str() {
+        "pc" => Ok(Value::Pc),
+        "i" => Ok(Value::Index),
+        "dt" => Ok(Value::DelayTimer),
+        "st" => Ok(Value::SoundTimer),
+        _ => Err(format!("{} is not something a script can check", text)),
+    }
+}
+
+// Decimal, or hex starting with 0x
+pub(crate) fn parse_number(text: &str) -> Result<u16, String> {
+    let text = text.trim();
+    let parsed = if text.starts_with("0x") || text.starts_with("0X") {
+        u16::from_str_radix(&text[2..], 16)
+    } else {
+        text.parse()
+    };
+
+    parsed.map_err(|_| format!("{} is not a number", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Waits for key 5, then stores V0 and V1 = 1 at 0x300 and stops in a loop
+    const WAIT_FOR_5: [u8; 14] = [
+        0x60, 0x05, 0xE0, 0x9E, 0x12, 0x02, 0x61, 0x01, 0xA3, 0x00, 0xF1, 0x55, 0x12, 0x0C,
+    ];
+
+    fn run(script: &str) -> Result<usize, String> {
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&WAIT_FOR_5);
+        Script::parse(script)?.run(&mut machine)
+    }
+
+    #[test]
+    fn plays_and_checks() {
+        let frames = run("# Nothing happens until 5 is pressed
+             wait 10 frames
+             assert memory[0x301] == 0
+             frame 20: press 5 for 2 frames
+             wait until PC == 0x20C within 5 frames
+             assert memory[0x301] == 1
+             assert V0 == 5
+             assert I >= 0x300");
+
+        assert_eq!(Ok(22), frames);
+    }
+
+    #[test]
+    fn waits_see_every_instruction() {
+        // With 5 held from the start, PC passes 0x206 partway through the
+        // first frame and ends it at 0x20C
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&WAIT_FOR_5);
+        machine.set_key(5, true);
+        let script = Script::parse("wait until pc == 0x206 within 1 frame\nassert pc == 0x20c");
+
+        assert_eq!(Ok(1), script.unwrap().run(&mut machine));
+    }
+
+    #[test]
+    fn key_waits_hold_until_a_press() {
+        // FX0A into V3, then loop forever
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&[0xF3, 0x0A, 0x12, 0x02]);
+        let script = Script::parse(
+            "wait 5 frames
+             assert pc == 0x200
+             frame 10: press 7 for 2 frames
+             assert v3 == 7
+             assert pc == 0x202",
+        );
+
+        assert_eq!(Ok(12), script.unwrap().run(&mut machine));
+    }
+
+    #[test]
+    fn failures_name_the_line() {
+        assert_eq!(
+            Err("line 2: expected v1 == 1 at frame 3, but it was 0x0".to_string()),
+            run("wait 3 frames\nassert v1 == 1")
+        );
+        assert_eq!(
+            Err("line 1: gave up waiting until memory[0x300] == 5 after 4 frames".to_string()),
+            run("wait until memory[0x300] == 5 within 4 frames")
+        );
+        assert_eq!(
+            Err("line 2: frame 1 has already passed, it's frame 2".to_string()),
+            run("wait 2 frames\nframe 1: press 5")
+        );
+        assert_eq!(
+            Err("line 1: 0xf000 is past the end of memory".to_string()),
+            run("assert memory[0xF000] == 0")
+        );
+    }
+
+    #[test]
+    fn bad_scripts() {
+        assert!(Script::parse("jump 5").is_err());
+        assert!(Script::parse("press 10").is_err());
+        assert!(Script::parse("wait 3").is_err());
+        assert!(Script::parse("assert v0 = 1").is_err());
+        assert!(Script::parse("assert vg == 1").is_err());
+        assert!(Script::parse("frame x: press 5").is_err());
+    }
+}
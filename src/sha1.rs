@@ -0,0 +1,106 @@
+// SHA-1 as described in FIPS 180-4. It's only used to identify ROMs, not for
+// anything that needs to be secure.
+
+const INITIAL_STATE: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+pub fn digest(data: &[u8]) -> [u8; 20] {
+    let mut state = INITIAL_STATE;
+
+    // Pad with a single 1 bit, zeros up to 56 bytes into the last block and
+    // then the message length in bits
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let bit_length = (data.len() as u64).wrapping_mul(8);
+    message.extend_from_slice(&bit_length.to_be_bytes());
+
+    for block in message.chunks(64) {
+        compress(&mut state, block);
+    }
+
+    let mut digest = [0u8; 20];
+    for (word, bytes) in state.iter().zip(digest.chunks_mut(4)) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+
+    digest
+}
+
+pub fn hex_digest(data: &[u8]) -> String {
+    digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn compress(state: &mut [u32; 5], block: &[u8]) {
+    let mut schedule = [0u32; 80];
+    for (word, bytes) in schedule.iter_mut().zip(block.chunks(4)) {
+        *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    for index in 16..80 {
+        schedule[index] = (schedule[index - 3]
+            ^ schedule[index - 8]
+            ^ schedule[index - 14]
+            ^ schedule[index - 16])
+            .rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+
+    for (index, word) in schedule.iter().enumerate() {
+        let (f, k) = match index {
+            0..=19 => ((b & c) | (!b & d), 0x5A827999),
+            20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+            _ => (b ^ c ^ d, 0xCA62C1D6),
+        };
+
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*word);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+    state[4] = state[4].wrapping_add(e);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        assert_eq!("da39a3ee5e6b4b0d3255bfef95601890afd80709", hex_digest(b""));
+    }
+
+    #[test]
+    fn abc() {
+        assert_eq!(
+            "a9993e364706816aba3e25717850c26c9cd0d89d",
+            hex_digest(b"abc")
+        );
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        let digest = hex_digest(b"The quick brown fox jumps over the lazy dog");
+        assert_eq!("2fd4e1c67a2d28fced849ee1bb76e7391b93eb12", digest);
+
+        let digest = hex_digest(&[b'a'; 1000]);
+        assert_eq!("291e9a6c66994949b57ba5e650361e98fc36b1ba", digest);
+    }
+}
@@ -0,0 +1,212 @@
+use std::str::FromStr;
+
+use system::Chip8Machine;
+
+// How fast emulated time runs compared to the host's 60 Hz refresh.
+// Whatever the speed, every emulated frame is a whole run_frame(), so the
+// timers always tick once per tick_rate instructions and games play the
+// same, only faster or slower.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Speed {
+    // This many emulated frames per host refresh. Below 1 is slow motion
+    Scaled(f64),
+    // As many frames as the host can run
+    Turbo,
+}
+
+impl Speed {
+    pub fn normal() -> Speed {
+        Speed::Scaled(1.0)
+    }
+}
+
+impl Default for Speed {
+    fn default() -> Speed {
+        Speed::normal()
+    }
+}
+
+// Speeds are written as turbo or a multiplier like 2x or 0.25x
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Speed, String> {
+        if text == "turbo" {
+            return Ok(Speed::Turbo);
+        }
+
+        text.trim_end_matches('x')
+            .parse::<f64>()
+            .ok()
+            .filter(|factor| *factor > 0.0 && factor.is_finite())
+            .map(Speed::Scaled)
+            .ok_or_else(|| format!("{} is not a speed", text))
+    }
+}
+
+// Decides how many emulated frames to run at each host refresh
+#[derive(Clone, Debug, Default)]
+pub struct SpeedController {
+    speed: Speed,
+    paused: bool,
+    // Frames asked for one at a time while paused
+    advances: usize,
+    // The fraction of a frame slow motion and odd multipliers haven't run yet
+    owed: f64,
+}
+
+impl SpeedController {
+    pub fn new() -> SpeedController {
+        SpeedController::default()
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.owed = 0.0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.advances = 0;
+        self.owed = 0.0;
+    }
+
+    // Runs exactly one more frame at the next refresh. Only does anything
+    // while paused
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advances += 1;
+        }
+    }
+
+    // Frames to run at this refresh, or None in turbo where it's however
+    // many fit in the time the host has
+    pub fn frames_due(&mut self) -> Option<usize> {
+        if self.paused {
+            let advances = self.advances;
+            self.advances = 0;
+            return Some(advances);
+        }
+
+        match self.speed {
+            Speed::Turbo => None,
+            Speed::Scaled(factor) => {
+                self.owed += factor;
+                let due = self.owed.floor();
+                self.owed -= due;
+                Some(due as usize)
+            }
+        }
+    }
+
+    // Runs the frames due at one host refresh and returns how many ran and
+    // the instructions they covered. In turbo, frames keep running until
+    // out_of_time says the refresh is over
+    pub fn run_refresh<F: FnMut() -> bool>(
+        &mut self,
+        machine: &mut Chip8Machine,
+        mut out_of_time: F,
+    ) -> (usize, usize) {
+        let mut frames = 0;
+        let mut instructions = 0;
+
+        match self.frames_due() {
+            Some(due) => {
+                while frames < due && !machine.halted() {
+                    instructions += machine.run_frame();
+                    frames += 1;
+                }
+            }
+            None => {
+                // Always at least one frame, so turbo is never slower than
+                // normal speed
+                while !machine.halted() {
+                    instructions += machine.run_frame();
+                    frames += 1;
+                    if out_of_time() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        (frames, instructions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn due(controller: &mut SpeedController, refreshes: usize) -> Vec<Option<usize>> {
+        (0..refreshes).map(|_| controller.frames_due()).collect()
+    }
+
+    #[test]
+    fn multipliers_and_slow_motion() {
+        let mut controller = SpeedController::new();
+        assert_eq!(vec![Some(1), Some(1)], due(&mut controller, 2));
+
+        controller.set_speed("3x".parse().unwrap());
+        assert_eq!(vec![Some(3), Some(3)], due(&mut controller, 2));
+
+        controller.set_speed("0.25x".parse().unwrap());
+        assert_eq!(
+            vec![Some(0), Some(0), Some(0), Some(1), Some(0)],
+            due(&mut controller, 5)
+        );
+
+        controller.set_speed("turbo".parse().unwrap());
+        assert_eq!(vec![None], due(&mut controller, 1));
+        assert!("0x".parse::<Speed>().is_err());
+    }
+
+    #[test]
+    fn paused_frame_advance() {
+        let mut controller = SpeedController::new();
+        controller.set_paused(true);
+        assert_eq!(vec![Some(0), Some(0)], due(&mut controller, 2));
+
+        controller.advance_frame();
+        controller.advance_frame();
+        assert_eq!(vec![Some(2), Some(0)], due(&mut controller, 2));
+
+        controller.set_paused(false);
+        controller.advance_frame();
+        assert_eq!(vec![Some(1)], due(&mut controller, 1));
+    }
+
+    #[test]
+    fn timers_follow_emulated_frames() {
+        // LD V0, 40; LD DT, V0; JP 204
+        let program = [0x60, 40, 0xF0, 0x15, 0x12, 0x04];
+        let mut controller = SpeedController::new();
+        controller.set_speed(Speed::Scaled(0.5));
+
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&program);
+        let frames: usize = (0..20)
+            .map(|_| controller.run_refresh(&mut machine, || true).0)
+            .sum();
+        assert_eq!(10, frames);
+        assert_eq!(30, machine.delay_timer());
+
+        controller.set_speed(Speed::Turbo);
+        let mut checks = 0;
+        let (frames, instructions) = controller.run_refresh(&mut machine, || {
+            checks += 1;
+            checks == 25
+        });
+        assert_eq!(25, frames);
+        assert_eq!(25 * 20, instructions);
+        assert_eq!(5, machine.delay_timer());
+    }
+}
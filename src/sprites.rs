@@ -1,5 +1,6 @@
 pub fn get_location(sprite: ASCIISprite) -> usize {
-    // these are the corresponding indexes to the start of the sprites in memory
+    // these are the corresponding offsets to the start of the sprites from
+    // the start of the font
     match sprite {
         ASCIISprite::Zero => 0,
         ASCIISprite::One => 5,
@@ -59,10 +60,7 @@ impl ASCIISprite {
             13 => ASCIISprite::D,
             14 => ASCIISprite::E,
             15 => ASCIISprite::F,
-            _ => panic!(format!(
-                "Tried to create ASCII sprite from value {}",
-                sprite
-            )),
+            _ => panic!("Tried to create ASCII sprite from value {}", sprite),
         }
     }
 }
@@ -1,13 +1,20 @@
 use std::fmt;
 
 pub struct Chip8Stack {
-    array: [u16; 16],
+    array: Vec<u16>,
     sp: usize,
 }
 
 impl Chip8Stack {
+    pub fn new(depth: usize) -> Chip8Stack {
+        Chip8Stack {
+            array: vec![0; depth],
+            sp: 0,
+        }
+    }
+
     pub fn push(&mut self, value: u16) {
-        if self.sp < 16 {
+        if self.sp < self.array.len() {
             self.array[self.sp] = value;
             self.sp += 1;
         } else {
@@ -18,19 +25,21 @@ impl Chip8Stack {
     pub fn pop(&mut self) -> u16 {
         if self.sp > 0 {
             self.sp -= 1;
-            self.array[self.sp + 1]
+            self.array[self.sp]
         } else {
             panic!("Tried to pop empty stack");
         }
     }
+
+    // The return addresses currently on the stack, oldest first
+    pub fn frames(&self) -> &[u16] {
+        &self.array[..self.sp]
+    }
 }
 
 impl Default for Chip8Stack {
     fn default() -> Chip8Stack {
-        Chip8Stack {
-            array: [0; 16],
-            sp: 0,
-        }
+        Chip8Stack::new(16)
     }
 }
 
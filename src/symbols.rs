@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use octo::Assembly;
+
+// Label names and source positions for a program, so addresses can be shown
+// the way they were written. Saved as text, one entry per line:
+//
+//   source game.8o
+//   label 0x200 main
+//   line 0x200 3 1
+//
+// where a line entry gives the source line and column an address came from.
+// Blank lines and lines starting with # are ignored.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Symbols {
+    pub source: Option<String>,
+    pub labels: BTreeMap<u16, String>,
+    pub lines: BTreeMap<u16, (usize, usize)>,
+}
+
+impl Symbols {
+    pub fn from_assembly(assembly: &Assembly, source: Option<&str>) -> Symbols {
+        Symbols {
+            source: source.map(str::to_string),
+            labels: assembly
+                .labels
+                .iter()
+                .map(|(name, address)| (*address, name.clone()))
+                .collect(),
+            lines: assembly
+                .source_map
+                .iter()
+                .map(|(address, span)| (*address, (span.line, span.column)))
+                .collect(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Symbols, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|error| error.to_string())?;
+
+        Symbols::parse(&text)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        File::create(path)
+            .and_then(|mut file| file.write_all(self.to_text().as_bytes()))
+            .map_err(|error| error.to_string())
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let result = match fields[0] {
+                "source" if fields.len() == 2 => {
+                    symbols.source = Some(fields[1].to_string());
+                    Ok(())
+                }
+                "label" if fields.len() == 3 => parse_address(fields[1]).map(|address| {
+                    symbols.labels.insert(address, fields[2].to_string());
+                }),
+                "line" if fields.len() == 4 => {
+                    let address = parse_address(fields[1]);
+                    let line = fields[2].parse().map_err(|_| "bad line number".to_string());
+                    let column = fields[3].parse().map_err(|_| "bad column".to_string());
+                    address.and_then(|address| {
+                        symbols.lines.insert(address, (line?, column?));
+                        Ok(())
+                    })
+                }
+                _ => Err(format!("can't read {}", line)),
+            };
+
+            result.map_err(|error| format!("line {}: {}", index + 1, error))?;
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        if let Some(ref source) = self.source {
+            writeln!(text, "source {}", source).unwrap();
+        }
+        for (address, name) in self.labels.iter() {
+            writeln!(text, "label {:#05x} {}", address, name).unwrap();
+        }
+        for (address, &(line, column)) in self.lines.iter() {
+            writeln!(text, "line {:#05x} {} {}", address, line, column).unwrap();
+        }
+
+        text
+    }
+
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    // The closest label at or before an address, with the distance from it,
+    // like main+4. Addresses before every label are shown as they are.
+    pub fn describe(&self, address: u16) -> String {
+        match self.labels.range(..=address).next_back() {
+            Some((label, name)) if *label == address => name.clone(),
+            Some((label, name)) => format!("{}+{}", name, address - label),
+            None => format!("{:#05x}", address),
+        }
+    }
+
+    // Where in the source an address came from, as file:line:column
+    pub fn location(&self, address: u16) -> Option<String> {
+        let &(line, column) = self.lines.get(&address)?;
+
+        Some(match self.source {
+            Some(ref source) => format!("{}:{}:{}", source, line, column),
+            None => format!("{}:{}", line, column),
+        })
+    }
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("{} is not an address", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octo::assemble;
+
+    #[test]
+    fn round_trip() {
+        let assembly = assemble(": main\n  v0 := 1\n: data 7").unwrap();
+        let symbols = Symbols::from_assembly(&assembly, Some("game.8o"));
+
+        assert_eq!(symbols, Symbols::parse(&symbols.to_text()).unwrap());
+        assert_eq!(Some("data"), symbols.name(0x202));
+        assert_eq!(Some("game.8o:2:3".to_string()), symbols.location(0x200));
+    }
+
+    #[test]
+    fn describe_addresses() {
+        let symbols = Symbols::parse("label 0x200 main\nlabel 300 sprites").unwrap();
+
+        assert_eq!("main", symbols.describe(0x200));
+        assert_eq!("main+6", symbols.describe(0x206));
+        assert_eq!("sprites+16", symbols.describe(0x310));
+        assert_eq!("0x100", symbols.describe(0x100));
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let error = Symbols::parse("# symbols\nlabel 0x200\n").unwrap_err();
+        assert!(error.starts_with("line 2:"));
+    }
+}
@@ -1,14 +1,36 @@
+use builder;
+use coverage;
+use disassembler;
 use display;
+use fontset;
+use idle;
 use keyboard;
+use lint;
 use memory;
+use phosphor;
+use profiler;
+use recompiler;
 use registers;
 use sprites;
 use stack;
+use vip;
+
+use std::io::Write;
 
 use instructions::Instruction;
-use keyboard::Key;
+use lint::LintWarning;
+use quirks::Quirks;
 use registers::Register;
 use sprites::ASCIISprite;
+use symbols::Symbols;
+
+// How instructions get executed. The interpreter decodes and runs one
+// instruction at a time, the recompiler runs whole predecoded basic blocks
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Backend {
+    Interpreter,
+    Recompiler,
+}
 
 pub struct Chip8Machine {
     memory_bank: memory::Chip8Memory,
@@ -16,21 +38,254 @@ pub struct Chip8Machine {
     keyboard: keyboard::Chip8Keyboard,
     display: display::Chip8Display,
     stack: stack::Chip8Stack,
+    native_sys: bool,
+    native: Option<vip::Suspended>,
+    decode_cache: bool,
+    backend: Backend,
+    blocks: recompiler::BlockCache,
+    load_address: u16,
+    font_address: u16,
+    font: fontset::FontSet,
+    quirks: Quirks,
+    symbols: Symbols,
+    trace: Option<Box<dyn Write>>,
+    profiler: Option<profiler::Profiler>,
+    coverage: Option<coverage::Coverage>,
+    tick_rate: u32,
+    idle_skip: bool,
+    idle: Option<idle::IdleLoop>,
+    skipped: u64,
+    phosphor: phosphor::Phosphor,
+    // Instructions the last frame ran past its budget finishing a block,
+    // which come out of the next frame's
+    overshoot: usize,
+    rng: rand::rngs::StdRng,
 }
 
 impl Chip8Machine {
     pub fn new() -> Chip8Machine {
+        builder::Chip8MachineBuilder::new().build()
+    }
+
+    pub(crate) fn from_builder(settings: &builder::Chip8MachineBuilder) -> Chip8Machine {
         Chip8Machine {
-            memory_bank: memory::Chip8Memory::default(),
-            registers: registers::Chip8Registers::default(),
+            memory_bank: memory::Chip8Memory::new(
+                settings.memory_size,
+                settings.font_address as usize,
+                &settings.font,
+            ),
+            registers: registers::Chip8Registers::new(settings.load_address),
             keyboard: keyboard::Chip8Keyboard::default(),
             display: display::Chip8Display::default(),
-            stack: stack::Chip8Stack::default(),
+            stack: stack::Chip8Stack::new(settings.stack_depth),
+            native_sys: settings.mode == builder::MachineMode::CosmacVip,
+            native: None,
+            decode_cache: true,
+            backend: Backend::Interpreter,
+            blocks: recompiler::BlockCache::new(settings.memory_size),
+            load_address: settings.load_address,
+            font_address: settings.font_address,
+            font: settings.font.clone(),
+            quirks: settings.quirks,
+            symbols: Symbols::default(),
+            trace: None,
+            profiler: None,
+            coverage: None,
+            tick_rate: settings.tick_rate,
+            idle_skip: true,
+            idle: None,
+            skipped: 0,
+            overshoot: 0,
+            phosphor: phosphor::Phosphor::new(phosphor::Persistence::Off),
+            rng: rand::SeedableRng::from_entropy(),
+        }
+    }
+
+    // With native SYS enabled, 0NNN runs the 1802 machine code at NNN like
+    // the COSMAC VIP does instead of treating it as a jump
+    pub fn set_native_sys(&mut self, enabled: bool) {
+        if enabled && self.memory_bank.size() < vip::MEMORY_SIZE {
+            panic!("Native SYS needs the 4K of memory the COSMAC VIP had");
+        }
+
+        self.native_sys = enabled;
+    }
+
+    // The decode cache is on by default. Turning it off decodes every
+    // instruction from scratch each time it runs
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = enabled;
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    pub fn set_tick_rate(&mut self, tick_rate: u32) {
+        self.tick_rate = tick_rate.max(1);
+    }
+
+    // Idle skipping is on by default. It only applies to run_frame(), and
+    // not while anything is watching every instruction
+    pub fn set_idle_skip(&mut self, enabled: bool) {
+        self.idle_skip = enabled;
+    }
+
+    // How many instructions idle skipping has saved running so far
+    pub fn skipped_instructions(&self) -> u64 {
+        self.skipped
+    }
+
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // Makes RND give the same numbers every run with the same seed.
+    // Otherwise they're seeded from the system
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = rand::SeedableRng::seed_from_u64(seed);
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    // Symbols are used to name addresses in the trace
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    // Writes a line for every instruction before it runs. While tracing,
+    // execute() steps one instruction at a time whatever the backend
+    pub fn set_trace_output(&mut self, output: Option<Box<dyn Write>>) {
+        self.trace = output;
+    }
+
+    // Profiling starts from nothing each time it's turned on. Like tracing,
+    // it makes execute() step one instruction at a time
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiler = if enabled {
+            Some(profiler::Profiler::new(self.load_address))
+        } else {
+            None
+        };
+    }
+
+    pub fn profiler(&self) -> Option<&profiler::Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // Coverage also starts from nothing and makes execute() step one
+    // instruction at a time
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = if enabled {
+            Some(coverage::Coverage::new())
+        } else {
+            None
+        };
+    }
+
+    pub fn coverage(&self) -> Option<&coverage::Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // Tracing, profiling and coverage all need to see every instruction
+    fn observed(&self) -> bool {
+        self.trace.is_some() || self.profiler.is_some() || self.coverage.is_some()
+    }
+
+    // The subroutines active right now, found from the CALL just before
+    // each return address on the stack
+    fn call_stack(&self) -> Vec<u16> {
+        let mut entries = vec![self.load_address];
+
+        for &return_address in self.stack.frames() {
+            let call = return_address.wrapping_sub(2);
+            entries.push(
+                match Instruction::new(self.memory_bank.read_instruction(call as usize)) {
+                    Some(Instruction::CALL(target)) => target,
+                    _ => call,
+                },
+            );
+        }
+
+        entries
+    }
+
+    fn trace_step(&mut self, instruction: &Option<Instruction>) {
+        let pc = self.registers.pc;
+        let i = self.registers.i;
+        let text = match *instruction {
+            Some(ref instruction) => disassembler::instruction_text(instruction, &self.symbols),
+            None => "??".to_string(),
+        };
+        let location = self
+            .symbols
+            .location(pc)
+            .map(|location| format!("  {}", location))
+            .unwrap_or_default();
+        let line = format!(
+            "{:03x} {:<12} {:04x}  {:<24} i={:03x} {}{}",
+            pc,
+            self.symbols.describe(pc),
+            self.memory_bank.read_instruction(pc as usize),
+            text,
+            i,
+            self.symbols.describe(i),
+            location
+        );
+
+        if let Some(ref mut output) = self.trace {
+            // A trace that can't be written shouldn't stop the program
+            let _ = writeln!(output, "{}", line.trim_end());
         }
     }
 
     fn run_sys(&mut self, address: u16) {
-        self.registers.pc = address;
+        if self.native_sys {
+            self.run_native(address);
+        } else {
+            self.registers.pc = address;
+        }
+    }
+
+    fn run_native(&mut self, address: u16) {
+        let mut variables = [0u8; 16];
+        for (index, value) in variables.iter_mut().enumerate() {
+            *value = self.registers.get(Register::new(index as u8));
+        }
+
+        let mut state = vip::NativeState {
+            memory: &mut self.memory_bank,
+            display: &mut self.display,
+            keyboard: &self.keyboard,
+            variables,
+            i: self.registers.i,
+            pc: self.registers.pc,
+            delay: self.registers.delay,
+            sound: self.registers.sound,
+        };
+        let suspended = self.native.take();
+        self.native = vip::call_native(&mut state, address, suspended);
+        if self.native.is_some() {
+            // Still running, so the SYS runs again to carry on with it
+            self.registers.delay = state.delay;
+            self.registers.sound = state.sound;
+            self.registers.pc -= 2;
+            return;
+        }
+
+        for (index, value) in state.variables.iter().enumerate() {
+            *self.registers.get_mut(Register::new(index as u8)) = *value;
+        }
+        self.registers.i = state.i;
+        self.registers.pc = state.pc;
+        self.registers.delay = state.delay;
+        self.registers.sound = state.sound;
     }
 
     fn run_cls(&mut self) {
@@ -39,7 +294,6 @@ impl Chip8Machine {
 
     fn run_ret(&mut self) {
         self.registers.pc = self.stack.pop();
-        self.registers.sp -= 1;
     }
 
     fn run_jp(&mut self, address: u16) {
@@ -47,7 +301,6 @@ impl Chip8Machine {
     }
 
     fn run_call(&mut self, address: u16) {
-        self.registers.sp += 1;
         self.stack.push(self.registers.pc);
         self.registers.pc = address;
     }
@@ -56,7 +309,7 @@ impl Chip8Machine {
         let register_value = self.registers.get(register);
 
         if register_value == constant {
-            self.registers.pc += 2;
+            self.registers.advance();
         }
     }
 
@@ -64,7 +317,7 @@ impl Chip8Machine {
         let register_value = self.registers.get(register);
 
         if register_value != constant {
-            self.registers.pc += 2;
+            self.registers.advance();
         }
     }
 
@@ -73,7 +326,7 @@ impl Chip8Machine {
         let register_y_value = self.registers.get(register_y);
 
         if register_x_value == register_y_value {
-            self.registers.pc += 2;
+            self.registers.advance();
         }
     }
 
@@ -82,7 +335,8 @@ impl Chip8Machine {
     }
 
     fn run_addc(&mut self, register: Register, constant: u8) {
-        *self.registers.get_mut(register) += constant;
+        let register_value = self.registers.get(register);
+        *self.registers.get_mut(register) = register_value.wrapping_add(constant);
     }
 
     fn run_ldr(&mut self, register_x: Register, register_y: Register) {
@@ -91,14 +345,26 @@ impl Chip8Machine {
 
     fn run_or(&mut self, register_x: Register, register_y: Register) {
         *self.registers.get_mut(register_x) |= self.registers.get(register_y);
+
+        if self.quirks.logic_resets_vf {
+            *self.registers.get_mut(Register::VF) = 0;
+        }
     }
 
     fn run_and(&mut self, register_x: Register, register_y: Register) {
         *self.registers.get_mut(register_x) &= self.registers.get(register_y);
+
+        if self.quirks.logic_resets_vf {
+            *self.registers.get_mut(Register::VF) = 0;
+        }
     }
 
     fn run_xor(&mut self, register_x: Register, register_y: Register) {
         *self.registers.get_mut(register_x) ^= self.registers.get(register_y);
+
+        if self.quirks.logic_resets_vf {
+            *self.registers.get_mut(Register::VF) = 0;
+        }
     }
 
     fn run_addr(&mut self, register_x: Register, register_y: Register) {
@@ -121,50 +387,61 @@ impl Chip8Machine {
         let register_x_value = self.registers.get(register_x);
         let register_y_value = self.registers.get(register_y);
 
-        if register_x_value > register_y_value {
+        // VF is set last so it holds the flag even when it's the target
+        *self.registers.get_mut(register_x) = register_x_value.wrapping_sub(register_y_value);
+
+        if register_x_value >= register_y_value {
             *self.registers.get_mut(Register::VF) = 1;
         } else {
             *self.registers.get_mut(Register::VF) = 0;
         }
-
-        *self.registers.get_mut(register_x) -= register_y_value;
     }
 
-    fn run_shr(&mut self, register: Register) {
-        let register_value = self.registers.get(register);
+    fn run_shr(&mut self, register_x: Register, register_y: Register) {
+        let source = if self.quirks.shift_uses_vy {
+            register_y
+        } else {
+            register_x
+        };
+        let register_value = self.registers.get(source);
+
+        *self.registers.get_mut(register_x) = register_value >> 1;
 
         if register_value & 0b1 == 1 {
             *self.registers.get_mut(Register::VF) = 1;
         } else {
             *self.registers.get_mut(Register::VF) = 0;
         }
-
-        *self.registers.get_mut(register) >>= 1;
     }
 
     fn run_subn(&mut self, register_x: Register, register_y: Register) {
         let register_x_value = self.registers.get(register_x);
         let register_y_value = self.registers.get(register_y);
 
-        if register_y_value > register_x_value {
+        *self.registers.get_mut(register_x) = register_y_value.wrapping_sub(register_x_value);
+
+        if register_y_value >= register_x_value {
             *self.registers.get_mut(Register::VF) = 1;
         } else {
             *self.registers.get_mut(Register::VF) = 0;
         }
-
-        *self.registers.get_mut(register_x) = register_y_value - register_x_value;
     }
 
-    fn run_shl(&mut self, register: Register) {
-        let register_value = self.registers.get(register);
+    fn run_shl(&mut self, register_x: Register, register_y: Register) {
+        let source = if self.quirks.shift_uses_vy {
+            register_y
+        } else {
+            register_x
+        };
+        let register_value = self.registers.get(source);
+
+        *self.registers.get_mut(register_x) = register_value << 1;
 
-        if register_value & 0b10000000 == 1 {
+        if register_value & 0b10000000 == 0b10000000 {
             *self.registers.get_mut(Register::VF) = 1;
         } else {
             *self.registers.get_mut(Register::VF) = 0;
         }
-
-        *self.registers.get_mut(register) <<= 1;
     }
 
     fn run_sne(&mut self, register_x: Register, register_y: Register) {
@@ -172,7 +449,7 @@ impl Chip8Machine {
         let register_y_value = self.registers.get(register_y);
 
         if register_x_value != register_y_value {
-            self.registers.pc += 2;
+            self.registers.advance();
         }
     }
 
@@ -181,87 +458,63 @@ impl Chip8Machine {
     }
 
     fn run_jpa(&mut self, address: u16) {
-        self.registers.pc = self.registers.get(Register::V0) as u16 + address;
+        let register = if self.quirks.jump_uses_vx {
+            Register::new((address >> 8) as u8 & 0xF)
+        } else {
+            Register::V0
+        };
+
+        self.registers.pc = self.registers.get(register) as u16 + address;
     }
 
     fn run_rnd(&mut self, register: Register, constant: u8) {
-        *self.registers.get_mut(register) = rand::random::<u8>() & constant;
+        *self.registers.get_mut(register) = rand::Rng::gen::<u8>(&mut self.rng) & constant;
     }
 
+    // Only the start of a sprite wraps onto the screen. Whatever then runs
+    // past the right or bottom edge is clipped or wrapped by the quirk.
     fn run_drw(&mut self, register_x: Register, register_y: Register, bytes: u8) {
         let i_value = self.registers.i as usize;
-        let mut sprite: Vec<u8> = Vec::new();
 
-        let x = self.registers.get(register_x) as usize;
-        let y = self.registers.get(register_y) as usize;
+        let x = self.registers.get(register_x) as usize % display::WIDTH;
+        let y = self.registers.get(register_y) as usize % display::HEIGHT;
+        let wrap = self.quirks.wrap_sprites;
 
         let mut collision = false;
 
-        for offset in 0..bytes as usize {
-            let sprite_piece = self.memory_bank.read(i_value + offset);
-            sprite.push(sprite_piece);
-        }
-
-        for (y_offset, layer) in sprite.iter().enumerate() {
-            let mut pixels: [bool; 8] = [false; 8];
-
-            if layer & 0b10000000 == 0b10000000 {
-                pixels[0] = true;
-            }
-            if layer & 0b01000000 == 0b01000000 {
-                pixels[1] = true;
-            }
-            if layer & 0b00100000 == 0b00100000 {
-                pixels[2] = true;
-            }
-            if layer & 0b00010000 == 0b00010000 {
-                pixels[3] = true;
-            }
-            if layer & 0b00001000 == 0b00001000 {
-                pixels[4] = true;
-            }
-            if layer & 0b00000100 == 0b00000100 {
-                pixels[5] = true;
-            }
-            if layer & 0b00000010 == 0b00000010 {
-                pixels[6] = true;
-            }
-            if layer & 0b00000001 == 0b00000001 {
-                pixels[7] = true;
-            }
-
-            for (x_offset, pixel) in pixels.iter().enumerate() {
-                let mut x_draw_position = x + x_offset;
-                let mut y_draw_position = y + y_offset;
-
-                if x_draw_position > 63 {
-                    x_draw_position -= 64;
-                }
-                if y_draw_position > 32 {
-                    y_draw_position -= 32;
-                }
-
-                if self
-                    .display
-                    .draw_pixel(x_draw_position, y_draw_position, *pixel)
-                {
-                    collision = true;
+        for y_offset in 0..bytes as usize {
+            let mut y_draw_position = y + y_offset;
+            if y_draw_position >= display::HEIGHT {
+                if !wrap {
+                    break;
                 }
+                y_draw_position -= display::HEIGHT;
             }
 
-            if collision {
-                *self.registers.get_mut(Register::VF) = 1;
+            let layer = self.memory_bank.read(i_value + y_offset);
+            let collided = if wrap {
+                self.display.draw_row(x, y_draw_position, layer)
             } else {
-                *self.registers.get_mut(Register::VF) = 0;
+                self.display.draw_row_clipped(x, y_draw_position, layer)
+            };
+
+            if collided {
+                collision = true;
             }
         }
+
+        if collision {
+            *self.registers.get_mut(Register::VF) = 1;
+        } else {
+            *self.registers.get_mut(Register::VF) = 0;
+        }
     }
 
     fn run_skp(&mut self, register: Register) {
         let register_value = self.registers.get(register);
 
         if self.keyboard.is_pressed(register_value) {
-            self.registers.pc += 2;
+            self.registers.advance();
         }
     }
 
@@ -269,7 +522,7 @@ impl Chip8Machine {
         let register_value = self.registers.get(register);
 
         if !self.keyboard.is_pressed(register_value) {
-            self.registers.pc += 2;
+            self.registers.advance();
         }
     }
 
@@ -277,26 +530,11 @@ impl Chip8Machine {
         *self.registers.get_mut(register) = self.registers.delay;
     }
 
+    // Waits for a key by running itself again until one is held down
     fn run_ldvk(&mut self, register: Register) {
-        let key = keyboard::get_key();
-
-        match key {
-            Key::Zero => *self.registers.get_mut(register) = 0,
-            Key::One => *self.registers.get_mut(register) = 1,
-            Key::Two => *self.registers.get_mut(register) = 2,
-            Key::Three => *self.registers.get_mut(register) = 3,
-            Key::Four => *self.registers.get_mut(register) = 4,
-            Key::Five => *self.registers.get_mut(register) = 5,
-            Key::Six => *self.registers.get_mut(register) = 6,
-            Key::Seven => *self.registers.get_mut(register) = 7,
-            Key::Eight => *self.registers.get_mut(register) = 8,
-            Key::Nine => *self.registers.get_mut(register) = 9,
-            Key::A => *self.registers.get_mut(register) = 10,
-            Key::B => *self.registers.get_mut(register) = 11,
-            Key::C => *self.registers.get_mut(register) = 12,
-            Key::D => *self.registers.get_mut(register) = 13,
-            Key::E => *self.registers.get_mut(register) = 14,
-            Key::F => *self.registers.get_mut(register) = 15,
+        match self.keyboard.first_pressed() {
+            Some(key) => *self.registers.get_mut(register) = key,
+            None => self.registers.pc -= 2,
         }
     }
 
@@ -309,12 +547,23 @@ impl Chip8Machine {
     }
 
     fn run_addi(&mut self, register: Register) {
-        self.registers.i = self.registers.get(register) as u16;
+        self.registers.i += self.registers.get(register) as u16;
     }
 
     fn run_ldir(&mut self, register: Register) {
         let register_value = self.registers.get(register);
-        self.registers.i = sprites::get_location(ASCIISprite::new(register_value)) as u16;
+        self.registers.i =
+            self.font_address + sprites::get_location(ASCIISprite::new(register_value)) as u16;
+    }
+
+    // Fonts without a large glyph for the digit, like the VIP's, point I at
+    // the small one instead
+    fn run_ldih(&mut self, register: Register) {
+        let register_value = self.registers.get(register);
+        match self.font.large_offset(register_value) {
+            Some(offset) => self.registers.i = self.font_address + offset as u16,
+            None => self.run_ldir(register),
+        }
     }
 
     fn run_ldbr(&mut self, register: Register) {
@@ -330,6 +579,12 @@ impl Chip8Machine {
         self.memory_bank.write(i_value + 2, ones);
     }
 
+    fn advance_i_after_load_store(&mut self, register: Register) {
+        if self.quirks.load_store_increments_i {
+            self.registers.i += register as u16 + 1;
+        }
+    }
+
     fn run_ldrs(&mut self, register: Register) {
         let i_value = self.registers.i as usize;
 
@@ -639,6 +894,8 @@ impl Chip8Machine {
                     .write(i_value + 15, self.registers.get(Register::VF));
             }
         }
+
+        self.advance_i_after_load_store(register);
     }
 
     fn run_rdrs(&mut self, register: Register) {
@@ -706,7 +963,7 @@ impl Chip8Machine {
                 *self.registers.get_mut(Register::V5) = self.memory_bank.read(i_value + 5);
                 *self.registers.get_mut(Register::V6) = self.memory_bank.read(i_value + 6);
                 *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 7);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 8);
+                *self.registers.get_mut(Register::V8) = self.memory_bank.read(i_value + 8);
             }
             Register::V9 => {
                 *self.registers.get_mut(Register::V0) = self.memory_bank.read(i_value);
@@ -717,8 +974,8 @@ impl Chip8Machine {
                 *self.registers.get_mut(Register::V5) = self.memory_bank.read(i_value + 5);
                 *self.registers.get_mut(Register::V6) = self.memory_bank.read(i_value + 6);
                 *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 7);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 8);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 9);
+                *self.registers.get_mut(Register::V8) = self.memory_bank.read(i_value + 8);
+                *self.registers.get_mut(Register::V9) = self.memory_bank.read(i_value + 9);
             }
             Register::VA => {
                 *self.registers.get_mut(Register::V0) = self.memory_bank.read(i_value);
@@ -729,8 +986,8 @@ impl Chip8Machine {
                 *self.registers.get_mut(Register::V5) = self.memory_bank.read(i_value + 5);
                 *self.registers.get_mut(Register::V6) = self.memory_bank.read(i_value + 6);
                 *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 7);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 8);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 9);
+                *self.registers.get_mut(Register::V8) = self.memory_bank.read(i_value + 8);
+                *self.registers.get_mut(Register::V9) = self.memory_bank.read(i_value + 9);
                 *self.registers.get_mut(Register::VA) = self.memory_bank.read(i_value + 10);
             }
             Register::VB => {
@@ -742,8 +999,8 @@ impl Chip8Machine {
                 *self.registers.get_mut(Register::V5) = self.memory_bank.read(i_value + 5);
                 *self.registers.get_mut(Register::V6) = self.memory_bank.read(i_value + 6);
                 *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 7);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 8);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 9);
+                *self.registers.get_mut(Register::V8) = self.memory_bank.read(i_value + 8);
+                *self.registers.get_mut(Register::V9) = self.memory_bank.read(i_value + 9);
                 *self.registers.get_mut(Register::VA) = self.memory_bank.read(i_value + 10);
                 *self.registers.get_mut(Register::VB) = self.memory_bank.read(i_value + 11);
             }
@@ -756,8 +1013,8 @@ impl Chip8Machine {
                 *self.registers.get_mut(Register::V5) = self.memory_bank.read(i_value + 5);
                 *self.registers.get_mut(Register::V6) = self.memory_bank.read(i_value + 6);
                 *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 7);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 8);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 9);
+                *self.registers.get_mut(Register::V8) = self.memory_bank.read(i_value + 8);
+                *self.registers.get_mut(Register::V9) = self.memory_bank.read(i_value + 9);
                 *self.registers.get_mut(Register::VA) = self.memory_bank.read(i_value + 10);
                 *self.registers.get_mut(Register::VB) = self.memory_bank.read(i_value + 11);
                 *self.registers.get_mut(Register::VC) = self.memory_bank.read(i_value + 12);
@@ -771,8 +1028,8 @@ impl Chip8Machine {
                 *self.registers.get_mut(Register::V5) = self.memory_bank.read(i_value + 5);
                 *self.registers.get_mut(Register::V6) = self.memory_bank.read(i_value + 6);
                 *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 7);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 8);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 9);
+                *self.registers.get_mut(Register::V8) = self.memory_bank.read(i_value + 8);
+                *self.registers.get_mut(Register::V9) = self.memory_bank.read(i_value + 9);
                 *self.registers.get_mut(Register::VA) = self.memory_bank.read(i_value + 10);
                 *self.registers.get_mut(Register::VB) = self.memory_bank.read(i_value + 11);
                 *self.registers.get_mut(Register::VC) = self.memory_bank.read(i_value + 12);
@@ -787,8 +1044,8 @@ impl Chip8Machine {
                 *self.registers.get_mut(Register::V5) = self.memory_bank.read(i_value + 5);
                 *self.registers.get_mut(Register::V6) = self.memory_bank.read(i_value + 6);
                 *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 7);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 8);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 9);
+                *self.registers.get_mut(Register::V8) = self.memory_bank.read(i_value + 8);
+                *self.registers.get_mut(Register::V9) = self.memory_bank.read(i_value + 9);
                 *self.registers.get_mut(Register::VA) = self.memory_bank.read(i_value + 10);
                 *self.registers.get_mut(Register::VB) = self.memory_bank.read(i_value + 11);
                 *self.registers.get_mut(Register::VC) = self.memory_bank.read(i_value + 12);
@@ -804,8 +1061,8 @@ impl Chip8Machine {
                 *self.registers.get_mut(Register::V5) = self.memory_bank.read(i_value + 5);
                 *self.registers.get_mut(Register::V6) = self.memory_bank.read(i_value + 6);
                 *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 7);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 8);
-                *self.registers.get_mut(Register::V7) = self.memory_bank.read(i_value + 9);
+                *self.registers.get_mut(Register::V8) = self.memory_bank.read(i_value + 8);
+                *self.registers.get_mut(Register::V9) = self.memory_bank.read(i_value + 9);
                 *self.registers.get_mut(Register::VA) = self.memory_bank.read(i_value + 10);
                 *self.registers.get_mut(Register::VB) = self.memory_bank.read(i_value + 11);
                 *self.registers.get_mut(Register::VC) = self.memory_bank.read(i_value + 12);
@@ -814,6 +1071,8 @@ impl Chip8Machine {
                 *self.registers.get_mut(Register::VF) = self.memory_bank.read(i_value + 15);
             }
         }
+
+        self.advance_i_after_load_store(register);
     }
 
     fn run_op(&mut self, op: &Instruction) {
@@ -866,14 +1125,14 @@ impl Chip8Machine {
             Instruction::SUB(register_x, register_y) => {
                 self.run_sub(register_x, register_y);
             }
-            Instruction::SHR(register) => {
-                self.run_shr(register);
+            Instruction::SHR(register_x, register_y) => {
+                self.run_shr(register_x, register_y);
             }
             Instruction::SUBN(register_x, register_y) => {
                 self.run_subn(register_x, register_y);
             }
-            Instruction::SHL(register) => {
-                self.run_shl(register);
+            Instruction::SHL(register_x, register_y) => {
+                self.run_shl(register_x, register_y);
             }
             Instruction::SNE(register_x, register_y) => {
                 self.run_sne(register_x, register_y);
@@ -914,6 +1173,9 @@ impl Chip8Machine {
             Instruction::LDIR(register) => {
                 self.run_ldir(register);
             }
+            Instruction::LDIH(register) => {
+                self.run_ldih(register);
+            }
             Instruction::LDBR(register) => {
                 self.run_ldbr(register);
             }
@@ -926,6 +1188,251 @@ impl Chip8Machine {
         }
     }
 
+    // Execute the instruction at PC. PC is moved past the instruction before
+    // it runs so jumps, calls and skips only have to set where to go next
+    pub fn step(&mut self) {
+        let pc = self.registers.pc as usize;
+
+        let instruction = if self.decode_cache {
+            self.memory_bank.decode_instruction(pc)
+        } else {
+            Instruction::new(self.memory_bank.read_instruction(pc))
+        };
+
+        if self.trace.is_some() {
+            self.trace_step(&instruction);
+        }
+        if self.profiler.is_some() {
+            let stack = self.call_stack();
+            let pc = self.registers.pc;
+            if let Some(ref mut profiler) = self.profiler {
+                profiler.record(pc, instruction.as_ref(), stack);
+            }
+        }
+
+        self.registers.advance();
+
+        if let Some(ref instruction) = instruction {
+            self.run_op(instruction);
+        }
+
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record(pc as u16);
+            if instruction.is_some_and(|instruction| instruction.is_skip()) {
+                coverage.record_branch(pc as u16, self.registers.pc == pc as u16 + 4);
+            }
+        }
+    }
+
+    // Run the next piece of the program with the selected backend and return
+    // how many instructions were executed
+    pub fn execute(&mut self) -> usize {
+        match self.backend {
+            _ if self.observed() => {
+                self.step();
+                1
+            }
+            Backend::Interpreter => {
+                self.step();
+                1
+            }
+            Backend::Recompiler => self.run_block(),
+        }
+    }
+
+    fn run_block(&mut self) -> usize {
+        let block = self.blocks.get(&mut self.memory_bank, self.registers.pc);
+
+        // Zero words never make it into a block, so they get stepped over
+        // the same way the interpreter does to keep execute() moving
+        if block.ops.is_empty() {
+            self.step();
+            return 1;
+        }
+
+        // Nothing inside a block reads PC except the last instruction, so it
+        // can be set once up front
+        self.registers.pc = block.end;
+
+        if !block.writes_memory {
+            for (_, instruction) in block.ops.iter() {
+                self.run_op(instruction);
+            }
+
+            return block.ops.len();
+        }
+
+        for (index, &(address, ref instruction)) in block.ops.iter().enumerate() {
+            self.run_op(instruction);
+
+            // The rest of this block may have just been written over
+            if recompiler::writes_memory(instruction) && self.memory_bank.code_written() {
+                self.registers.pc = address.wrapping_add(2);
+                return index + 1;
+            }
+        }
+
+        block.ops.len()
+    }
+
+    // Presses or releases a key on the keypad, 0 to F. Keys should only
+    // change between frames, the same as they would between 60 Hz polls
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        self.keyboard.set_pressed(key, pressed);
+    }
+
+    pub fn is_key_pressed(&self, key: u8) -> bool {
+        self.keyboard.is_pressed(key)
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.registers.pc
+    }
+
+    pub fn index(&self) -> u16 {
+        self.registers.i
+    }
+
+    // V0 to VF by number
+    pub fn register(&self, register: u8) -> u8 {
+        self.registers.get(Register::new(register))
+    }
+
+    // None past the end of memory
+    pub fn read_memory(&self, address: u16) -> Option<u8> {
+        if (address as usize) < self.memory_bank.size() {
+            Some(self.memory_bank.read(address as usize))
+        } else {
+            None
+        }
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.registers.delay
+    }
+
+    // The buzzer sounds for as long as this is above zero
+    pub fn sound_timer(&self) -> u8 {
+        self.registers.sound
+    }
+
+    // The 60 Hz countdown of the delay and sound timers
+    pub fn tick_timers(&mut self) {
+        self.registers.delay = self.registers.delay.saturating_sub(1);
+        self.registers.sound = self.registers.sound.saturating_sub(1);
+    }
+
+    // A zero word is where programs stop
+    pub fn halted(&self) -> bool {
+        self.memory_bank
+            .read_instruction(self.registers.pc as usize)
+            == 0
+    }
+
+    // Runs one 60 Hz frame of tick_rate instructions and then ticks the
+    // timers. Returns how many instructions the frame covered, including
+    // any that were skipped as idle
+    pub fn run_frame(&mut self) -> usize {
+        let budget = (self.tick_rate as usize).saturating_sub(self.overshoot);
+        let skip = self.idle_skip && !self.observed();
+        let mut executed = 0;
+        self.idle = None;
+
+        while executed < budget && !self.halted() {
+            let before = self.registers.pc;
+            executed += self.execute();
+
+            // Loops can only close with a jump backwards
+            if skip && self.registers.pc <= before {
+                let skipped = self.skip_idle(executed, budget);
+                self.skipped += skipped as u64;
+                executed += skipped;
+            }
+        }
+
+        self.overshoot = executed.saturating_sub(budget);
+        self.tick_timers();
+        self.phosphor.update(&self.display);
+
+        executed
+    }
+
+    // Runs a frame like run_frame, but one instruction at a time, and says
+    // whether condition held after any of them. Slower, since it can't use
+    // the recompiler or skip idle loops, but nothing goes past unseen
+    pub fn run_frame_until<F: FnMut(&Chip8Machine) -> bool>(&mut self, mut condition: F) -> bool {
+        let budget = (self.tick_rate as usize).saturating_sub(self.overshoot);
+        let mut executed = 0;
+        let mut held = false;
+        self.idle = None;
+
+        while executed < budget && !self.halted() {
+            self.step();
+            executed += 1;
+            held = condition(self) || held;
+        }
+
+        self.overshoot = 0;
+        self.tick_timers();
+        self.phosphor.update(&self.display);
+
+        held
+    }
+
+    fn skip_idle(&mut self, executed: usize, budget: usize) -> usize {
+        let pc = self.registers.pc;
+
+        if let Some(ref mut idle) = self.idle {
+            if idle.start == pc {
+                return idle.revisit(&self.registers, executed, budget);
+            }
+        }
+
+        if idle::is_polling_loop(&self.memory_bank, pc) {
+            self.idle = Some(idle::IdleLoop::new(pc, &self.registers, executed));
+        }
+
+        0
+    }
+
+    pub fn display(&self) -> &display::Chip8Display {
+        &self.display
+    }
+
+    // What changed on screen since this was last called, so a renderer can
+    // redraw just that
+    pub fn take_display_changes(&mut self) -> Option<display::DirtyRect> {
+        self.display.take_dirty_rect()
+    }
+
+    // Persistence builds up over frames from run_frame()
+    pub fn set_persistence(&mut self, persistence: phosphor::Persistence) {
+        self.phosphor = phosphor::Phosphor::new(persistence);
+    }
+
+    // How bright each pixel is, from 0 to 255, row by row. This is what
+    // anything showing the screen should use
+    pub fn screen_levels(&self) -> Vec<u8> {
+        if self.phosphor.persistence() == phosphor::Persistence::Off {
+            let mut phosphor = phosphor::Phosphor::new(phosphor::Persistence::Off);
+            phosphor.update(&self.display);
+            return phosphor.levels().to_vec();
+        }
+
+        self.phosphor.levels().to_vec()
+    }
+
+    // Runs a fixed number of frames as fast as possible without showing
+    // anything, for batch runs with no one watching
+    pub fn run_frames(&mut self, frames: usize) {
+        for _ in 0..frames {
+            if self.halted() {
+                break;
+            }
+            self.run_frame();
+        }
+    }
+
     pub fn run(&mut self) {
         loop {
             let instruction = self
@@ -936,25 +1443,635 @@ impl Chip8Machine {
                 break;
             }
 
-            let instruction = Instruction::new(instruction);
+            self.execute();
 
-            match instruction {
-                Some(ref instruction) => {
-                    self.run_op(instruction);
-                    self.registers.pc += 2;
-                }
-                None => {
-                    self.registers.pc += 2;
+            println!("{:?}", self.display);
+        }
+    }
+
+    // Loads a program after checking it fits in memory, and returns anything
+    // suspicious a static pass over it turns up
+    pub fn load_rom(&mut self, program: &[u8]) -> Result<Vec<LintWarning>, String> {
+        let room = self.memory_bank.size() - self.load_address as usize;
+        if program.len() > room {
+            return Err(format!(
+                "Program is {} bytes but only {} fit after {:#x}",
+                program.len(),
+                room,
+                self.load_address
+            ));
+        }
+
+        self.load_memory(program);
+        Ok(lint::lint(program, self.load_address))
+    }
+
+    pub fn load_memory(&mut self, program: &[u8]) {
+        for (position, byte) in program.iter().enumerate() {
+            self.memory_bank
+                .write(self.load_address as usize + position, *byte);
+        }
+    }
+}
+
+impl Default for Chip8Machine {
+    fn default() -> Chip8Machine {
+        Chip8Machine::new()
+    }
+}
+
+// Conformance tests. Every program runs on both backends and has to leave
+// the machine in the same expected state
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octo;
+
+    const BACKENDS: [Backend; 2] = [Backend::Interpreter, Backend::Recompiler];
+
+    fn run_until(program: &[u8], backend: Backend, halt: u16) -> Chip8Machine {
+        run_built(builder::Chip8MachineBuilder::new(), program, backend, halt)
+    }
+
+    fn run_built(
+        builder: builder::Chip8MachineBuilder,
+        program: &[u8],
+        backend: Backend,
+        halt: u16,
+    ) -> Chip8Machine {
+        let mut machine = builder.build();
+        machine.set_backend(backend);
+        machine.load_memory(program);
+
+        let mut executed = 0;
+        while machine.registers.pc != halt {
+            executed += machine.execute();
+            if executed > 10_000 {
+                panic!("{:?} never reached {:#x}", backend, halt);
+            }
+        }
+
+        machine
+    }
+
+    // Programs end in a jump to themselves, which is where they halt
+    fn conformance<F: Fn(&Chip8Machine)>(program: &[u8], check: F) {
+        let halt = 0x200 + program.len() as u16 - 2;
+        conformance_until(program, halt, check);
+    }
+
+    fn conformance_until<F: Fn(&Chip8Machine)>(program: &[u8], halt: u16, check: F) {
+        for backend in BACKENDS.iter() {
+            check(&run_until(program, *backend, halt));
+        }
+    }
+
+    #[test]
+    fn load_rom_checks_size() {
+        let mut machine = Chip8Machine::new();
+
+        assert!(machine.load_rom(&[0; 0xE00]).unwrap().is_empty());
+        assert!(machine.load_rom(&[0; 0xE01]).is_err());
+    }
+
+    #[test]
+    fn load_rom_returns_lint_warnings() {
+        let mut machine = Chip8Machine::new();
+        let warnings = machine.load_rom(&[0x01, 0x23]).unwrap();
+
+        assert_eq!(
+            vec![LintWarning::SysCall {
+                address: 0x200,
+                target: 0x123
+            }],
+            warnings
+        );
+    }
+
+    #[test]
+    fn frames_tick_the_timers() {
+        // LD V0, 3; LD DT, V0; JP 204
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&[0x60, 0x03, 0xF0, 0x15, 0x12, 0x04]);
+
+        machine.run_frame();
+        assert_eq!(2, machine.registers.delay);
+        machine.run_frame();
+        machine.run_frame();
+        machine.run_frame();
+        assert_eq!(0, machine.registers.delay);
+    }
+
+    #[test]
+    fn idle_skip_keeps_results() {
+        // Waits out the delay timer, counting in v2 each time it runs out
+        let source = ": main\n  v0 := 5\n  delay := v0\n: wait\n  v0 := delay\n  if v0 != 0 then jump wait\n  v2 += 1\n  jump main";
+        let program = octo::assemble(source).unwrap().bytes;
+
+        for backend in BACKENDS.iter() {
+            let run = |idle_skip: bool| {
+                let mut machine = builder::Chip8MachineBuilder::new().tick_rate(30).build();
+                machine.set_backend(*backend);
+                machine.set_idle_skip(idle_skip);
+                machine.load_memory(&program);
+                let executed: usize = (0..30).map(|_| machine.run_frame()).sum();
+                (machine, executed)
+            };
+
+            let (skipping, skipping_executed) = run(true);
+            let (stepping, stepping_executed) = run(false);
+
+            assert_eq!(stepping.registers, skipping.registers);
+            assert_eq!(stepping_executed, skipping_executed);
+            assert_eq!(5, skipping.registers.get(Register::V2));
+            assert!(skipping.skipped_instructions() > 0);
+            assert_eq!(0, stepping.skipped_instructions());
+        }
+    }
+
+    #[test]
+    fn loops_with_side_effects_are_not_idle() {
+        // Counts in v1 while waiting, so every pass is different
+        let source = ": main\n  v0 := 5\n  delay := v0\n: wait\n  v1 += 1\n  v0 := delay\n  if v0 != 0 then jump wait\n: halt jump halt";
+        let mut machine = Chip8Machine::new();
+        machine.load_memory(&octo::assemble(source).unwrap().bytes);
+        machine.run_frame();
+        machine.run_frame();
+
+        assert_eq!(0, machine.skipped_instructions());
+    }
+
+    // Collects the trace so tests can read it back
+    #[derive(Clone, Default)]
+    struct SharedBuffer(::std::rc::Rc<::std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> ::std::io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_names_addresses() {
+        let source =
+            ": main\n  i := sprite\n  draw\n: halt jump halt\n: draw\n  return\n: sprite 0xF0";
+        let assembly = octo::assemble(source).unwrap();
+        let buffer = SharedBuffer::default();
+
+        for backend in BACKENDS.iter() {
+            buffer.0.borrow_mut().clear();
+            let mut machine = Chip8Machine::new();
+            machine.set_backend(*backend);
+            machine.load_memory(&assembly.bytes);
+            machine.set_symbols(Symbols::from_assembly(&assembly, Some("game.8o")));
+            machine.set_trace_output(Some(Box::new(buffer.clone())));
+            for _ in 0..4 {
+                machine.execute();
+            }
+
+            let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+            let lines: Vec<&str> = trace.lines().collect();
+            assert_eq!(4, lines.len());
+            assert_eq!(
+                "200 main         a208  i := sprite              i=000 0x000  game.8o:2:3",
+                lines[0]
+            );
+            assert!(lines[1].starts_with("202 main+2       2206  draw "));
+            assert!(lines[1].contains("i=208 sprite"));
+            assert!(lines[2].starts_with("206 draw"));
+            assert!(lines[3].starts_with("204 halt"));
+        }
+    }
+
+    fn v(machine: &Chip8Machine, register: u8) -> u8 {
+        machine.registers.get(Register::new(register))
+    }
+
+    #[test]
+    fn add_with_carry() {
+        conformance(
+            &[0x60, 0xFF, 0x61, 0x02, 0x80, 0x14, 0x12, 0x06],
+            |machine| {
+                assert_eq!(0x01, v(machine, 0x0));
+                assert_eq!(1, v(machine, 0xF));
+            },
+        );
+    }
+
+    #[test]
+    fn add_constant_wraps() {
+        conformance(&[0x60, 0xFF, 0x70, 0x02, 0x12, 0x04], |machine| {
+            assert_eq!(0x01, v(machine, 0x0));
+        });
+    }
+
+    #[test]
+    fn sub_with_borrow() {
+        conformance(
+            &[0x60, 0x10, 0x61, 0x20, 0x80, 0x15, 0x12, 0x06],
+            |machine| {
+                assert_eq!(0xF0, v(machine, 0x0));
+                assert_eq!(0, v(machine, 0xF));
+            },
+        );
+    }
+
+    #[test]
+    fn flag_wins_when_vf_is_the_target() {
+        // VF := 7; V1 := 5; VF -= V1
+        conformance(
+            &[0x6F, 0x07, 0x61, 0x05, 0x8F, 0x15, 0x12, 0x06],
+            |machine| {
+                assert_eq!(1, v(machine, 0xF));
+            },
+        );
+    }
+
+    #[test]
+    fn assembled_octo_comparisons() {
+        let source = ": main
+            v0 := 3  v1 := 0  v2 := 0
+            if v0 < 5 then v1 := 1
+            if v0 > 5 then v2 := 1
+            loop v3 += 1 while v3 <= 7 again
+            : halt jump halt";
+        let program = octo::assemble(source).unwrap().bytes;
+        let halt = 0x200 + program.len() as u16 - 2;
+
+        conformance_until(&program, halt, |machine| {
+            assert_eq!(1, v(machine, 0x1));
+            assert_eq!(0, v(machine, 0x2));
+            assert_eq!(8, v(machine, 0x3));
+        });
+    }
+
+    #[test]
+    fn subn_without_borrow() {
+        conformance(
+            &[0x60, 0x10, 0x61, 0x20, 0x80, 0x17, 0x12, 0x06],
+            |machine| {
+                assert_eq!(0x10, v(machine, 0x0));
+                assert_eq!(1, v(machine, 0xF));
+            },
+        );
+    }
+
+    #[test]
+    fn shifts_set_vf() {
+        conformance(
+            &[
+                0x60, 0x81, 0x80, 0x0E, 0x82, 0xF0, 0x61, 0x03, 0x81, 0x06, 0x12, 0x0A,
+            ],
+            |machine| {
+                assert_eq!(0x02, v(machine, 0x0));
+                assert_eq!(1, v(machine, 0x2));
+                assert_eq!(0x01, v(machine, 0x1));
+                assert_eq!(1, v(machine, 0xF));
+            },
+        );
+    }
+
+    #[test]
+    fn skips() {
+        conformance(
+            &[
+                0x60, 0x05, 0x30, 0x05, 0x61, 0x01, 0x40, 0x05, 0x62, 0x01, 0x12, 0x0A,
+            ],
+            |machine| {
+                assert_eq!(0, v(machine, 0x1));
+                assert_eq!(1, v(machine, 0x2));
+            },
+        );
+    }
+
+    #[test]
+    fn counting_loop() {
+        conformance(
+            &[0x60, 0x00, 0x70, 0x01, 0x30, 0x0A, 0x12, 0x02, 0x12, 0x08],
+            |machine| {
+                assert_eq!(10, v(machine, 0x0));
+            },
+        );
+    }
+
+    #[test]
+    fn call_and_return() {
+        conformance_until(
+            &[0x22, 0x06, 0x61, 0x02, 0x12, 0x04, 0x60, 0x01, 0x00, 0xEE],
+            0x204,
+            |machine| {
+                assert_eq!(1, v(machine, 0x0));
+                assert_eq!(2, v(machine, 0x1));
+            },
+        );
+    }
+
+    #[test]
+    fn jump_plus_v0() {
+        conformance(
+            &[
+                0x60, 0x04, 0xB2, 0x04, 0x61, 0x01, 0x12, 0x06, 0x62, 0x02, 0x12, 0x0A,
+            ],
+            |machine| {
+                assert_eq!(0, v(machine, 0x1));
+                assert_eq!(2, v(machine, 0x2));
+            },
+        );
+    }
+
+    #[test]
+    fn bcd_and_load_registers() {
+        conformance(
+            &[0xA3, 0x00, 0x60, 0x7B, 0xF0, 0x33, 0xF2, 0x65, 0x12, 0x08],
+            |machine| {
+                assert_eq!(1, v(machine, 0x0));
+                assert_eq!(2, v(machine, 0x1));
+                assert_eq!(3, v(machine, 0x2));
+            },
+        );
+    }
+
+    #[test]
+    fn add_to_i() {
+        conformance(
+            &[0xA3, 0x00, 0x60, 0x05, 0xF0, 0x1E, 0x12, 0x06],
+            |machine| {
+                assert_eq!(0x305, machine.registers.i);
+            },
+        );
+    }
+
+    #[test]
+    fn draw_sets_collision() {
+        conformance(
+            &[0xA0, 0x00, 0x60, 0x00, 0xD0, 0x05, 0xD0, 0x05, 0x12, 0x08],
+            |machine| {
+                assert!(!machine.display.get_pixel(0, 0));
+                assert_eq!(1, v(machine, 0xF));
+            },
+        );
+    }
+
+    // Draws a 2x2 block at (x, y) and returns the pixels that came out lit
+    fn draw_block(x: u8, y: u8, wrap_sprites: bool) -> Vec<(usize, usize)> {
+        let quirks = Quirks {
+            wrap_sprites,
+            ..Quirks::default()
+        };
+        let mut machine = builder::Chip8MachineBuilder::new().quirks(quirks).build();
+        machine.memory_bank.write(0x300, 0b1100_0000);
+        machine.memory_bank.write(0x301, 0b1100_0000);
+        machine.registers.i = 0x300;
+        *machine.registers.get_mut(Register::V0) = x;
+        *machine.registers.get_mut(Register::V1) = y;
+        machine.run_drw(Register::V0, Register::V1, 2);
+
+        let mut lit = Vec::new();
+        for y in 0..display::HEIGHT {
+            for x in 0..display::WIDTH {
+                if machine.display.get_pixel(x, y) {
+                    lit.push((x, y));
                 }
             }
+        }
+        lit
+    }
 
-            println!("{:?}", self.display);
+    #[test]
+    fn sprites_at_the_edges() {
+        let middle = vec![(10, 5), (11, 5), (10, 6), (11, 6)];
+        let corner = vec![(63, 31)];
+        let wrapped_corner = vec![(0, 0), (63, 0), (0, 31), (63, 31)];
+
+        // x, y, clipped, wrapped
+        let cases = vec![
+            (10, 5, middle.clone(), middle.clone()),
+            (
+                63,
+                5,
+                vec![(63, 5), (63, 6)],
+                vec![(0, 5), (63, 5), (0, 6), (63, 6)],
+            ),
+            (
+                10,
+                31,
+                vec![(10, 31), (11, 31)],
+                vec![(10, 0), (11, 0), (10, 31), (11, 31)],
+            ),
+            (63, 31, corner.clone(), wrapped_corner.clone()),
+            // The start coordinate always wraps, whatever the quirk
+            (74, 37, middle.clone(), middle.clone()),
+            (
+                64,
+                32,
+                vec![(0, 0), (1, 0), (0, 1), (1, 1)],
+                vec![(0, 0), (1, 0), (0, 1), (1, 1)],
+            ),
+            (255, 255, corner, wrapped_corner),
+        ];
+
+        for (x, y, clipped, wrapped) in cases {
+            assert_eq!(
+                clipped,
+                draw_block(x, y, false),
+                "clipped at ({}, {})",
+                x,
+                y
+            );
+            assert_eq!(wrapped, draw_block(x, y, true), "wrapped at ({}, {})", x, y);
         }
     }
 
-    pub fn load_memory(&mut self, program: &Vec<u8>) {
-        for (position, byte) in program.iter().enumerate() {
-            self.memory_bank.write(512 + position, *byte);
+    #[test]
+    fn clipped_pixels_never_collide() {
+        // Lights (0, 5) then draws over it from the right edge
+        let program = [
+            0xA2, 0x10, 0x60, 0x00, 0x61, 0x05, 0xD0, 0x11, 0x60, 0x3F, 0xD0, 0x11, 0x12, 0x0C,
+            0x00, 0x00, 0xC0,
+        ];
+
+        for wrap_sprites in [false, true].iter() {
+            let quirks = Quirks {
+                wrap_sprites: *wrap_sprites,
+                ..Quirks::default()
+            };
+            for backend in BACKENDS.iter() {
+                let builder = builder::Chip8MachineBuilder::new().quirks(quirks);
+                let machine = run_built(builder, &program, *backend, 0x20C);
+                assert_eq!(*wrap_sprites as u8, v(&machine, 0xF));
+                assert_eq!(!*wrap_sprites, machine.display.get_pixel(0, 5));
+            }
+        }
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // Stores 0x6207 over the instruction at 0x20A before it runs
+        conformance(
+            &[
+                0x60, 0x62, 0x61, 0x07, 0xA2, 0x0A, 0xF1, 0x55, 0x63, 0x00, 0x62, 0x00, 0x12, 0x0C,
+            ],
+            |machine| {
+                assert_eq!(7, v(machine, 0x2));
+            },
+        );
+    }
+
+    #[test]
+    fn vip_shift_uses_vy() {
+        for backend in BACKENDS.iter() {
+            let builder = builder::Chip8MachineBuilder::new().quirks(Quirks::cosmac_vip());
+            let machine = run_built(
+                builder,
+                &[0x61, 0x03, 0x80, 0x16, 0x12, 0x04],
+                *backend,
+                0x204,
+            );
+
+            assert_eq!(1, v(&machine, 0x0));
+            assert_eq!(1, v(&machine, 0xF));
+        }
+    }
+
+    #[test]
+    fn vip_load_store_moves_i() {
+        for backend in BACKENDS.iter() {
+            let builder = builder::Chip8MachineBuilder::new().quirks(Quirks::cosmac_vip());
+            let machine = run_built(
+                builder,
+                &[0xA3, 0x00, 0xF2, 0x55, 0x12, 0x04],
+                *backend,
+                0x204,
+            );
+
+            assert_eq!(0x303, machine.registers.i);
+        }
+    }
+
+    #[test]
+    fn eti660_programs_start_at_0x600() {
+        for backend in BACKENDS.iter() {
+            let builder = builder::Chip8MachineBuilder::new().mode(builder::MachineMode::Eti660);
+            let machine = run_built(builder, &[0x60, 0x07, 0x16, 0x02], *backend, 0x602);
+
+            assert_eq!(7, v(&machine, 0x0));
+        }
+    }
+
+    #[test]
+    fn deeper_stack() {
+        // Recurses 19 calls deep, more than the standard 16 entry stack holds
+        for backend in BACKENDS.iter() {
+            let builder = builder::Chip8MachineBuilder::new().stack_depth(32);
+            let machine = run_built(
+                builder,
+                &[0x70, 0x01, 0x30, 0x14, 0x22, 0x00, 0x12, 0x06],
+                *backend,
+                0x206,
+            );
+
+            assert_eq!(20, v(&machine, 0x0));
+        }
+    }
+
+    #[test]
+    fn stack_deeper_than_255() {
+        // CALL 0x200 calling itself
+        for backend in BACKENDS.iter() {
+            let mut machine = builder::Chip8MachineBuilder::new()
+                .stack_depth(1000)
+                .build();
+            machine.set_backend(*backend);
+            machine.load_memory(&[0x22, 0x00]);
+            for _ in 0..300 {
+                machine.execute();
+            }
+
+            assert_eq!(300, machine.stack.frames().len());
+        }
+    }
+
+    #[test]
+    fn pc_wraps_at_the_top_of_64k() {
+        // V0 := 5; V0 += 1; SE V0, 6 in the last word, which skips over
+        // the first word of memory
+        for backend in BACKENDS.iter() {
+            let mut machine = builder::Chip8MachineBuilder::new()
+                .memory_size(0x10000)
+                .load_address(0xFFFA)
+                .build();
+            machine.set_backend(*backend);
+            machine.load_memory(&[0x60, 0x05, 0x70, 0x01, 0x30, 0x06]);
+            let mut executed = 0;
+            while executed < 3 {
+                executed += machine.execute();
+            }
+
+            assert_eq!(2, machine.pc());
+            assert_eq!(0x06F0, machine.memory_bank.read_instruction(0xFFFF));
+        }
+    }
+
+    #[test]
+    fn load_all_registers() {
+        let mut program = vec![0xA2, 0x08, 0xFF, 0x65, 0x12, 0x04, 0x00, 0x00];
+        program.extend(0x10..0x20);
+        conformance_until(&program, 0x204, |machine| {
+            for register in 0..16 {
+                assert_eq!(0x10 + register, v(machine, register));
+            }
+        });
+    }
+
+    #[test]
+    fn zero_words_still_count() {
+        for backend in BACKENDS.iter() {
+            let mut machine = Chip8Machine::new();
+            machine.set_backend(*backend);
+            machine.load_memory(&[0x00, 0x00]);
+
+            // 0000 runs as SYS 0, which jumps there
+            assert_eq!(1, machine.execute());
+            assert_eq!(0, machine.pc());
+        }
+    }
+
+    #[test]
+    fn large_digit_address() {
+        for backend in BACKENDS.iter() {
+            let builder = builder::Chip8MachineBuilder::new()
+                .font_address(0x50)
+                .font(fontset::FontSet::builtin(fontset::BuiltinFont::SuperChip));
+            let machine = run_built(
+                builder,
+                &[0x60, 0x03, 0xF0, 0x30, 0x12, 0x04],
+                *backend,
+                0x204,
+            );
+
+            assert_eq!(0x50 + 80 + 30, machine.registers.i);
+            assert_eq!(0x3C, machine.memory_bank.read(machine.registers.i as usize));
+        }
+    }
+
+    #[test]
+    fn missing_large_digits_use_the_small_font() {
+        for backend in BACKENDS.iter() {
+            let builder = builder::Chip8MachineBuilder::new()
+                .font_address(0x50)
+                .font(fontset::FontSet::builtin(fontset::BuiltinFont::SuperChip));
+            let machine = run_built(
+                builder,
+                &[0x60, 0x0C, 0xF0, 0x30, 0x12, 0x04],
+                *backend,
+                0x204,
+            );
+
+            assert_eq!(0x50 + 12 * 5, machine.registers.i);
         }
     }
 }
@@ -0,0 +1,298 @@
+use std::str::FromStr;
+
+use display::{HEIGHT, WIDTH};
+
+// An RGB picture of the screen, row by row from the top left
+#[derive(Clone, PartialEq, Debug)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    // The screen at its own size, one pixel per display pixel
+    pub fn screen(pixels: Vec<[u8; 3]>) -> Image {
+        if pixels.len() != WIDTH * HEIGHT {
+            panic!(
+                "A screen is {} pixels, not {}",
+                WIDTH * HEIGHT,
+                pixels.len()
+            );
+        }
+
+        Image {
+            width: WIDTH,
+            height: HEIGHT,
+            pixels,
+        }
+    }
+
+    // The pixel at (x, y), or the nearest one on the edge for anything
+    // outside, which is how Scale2x and Scale3x treat the border
+    fn get(&self, x: isize, y: isize) -> [u8; 3] {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    // Makes an image factor times the size, filling each block of
+    // factor x factor pixels with block(x, y, dx, dy)
+    fn blocks<F: Fn(isize, isize, usize, usize) -> [u8; 3]>(
+        &self,
+        factor: usize,
+        block: F,
+    ) -> Image {
+        let (width, height) = (self.width * factor, self.height * factor);
+        let mut pixels = Vec::with_capacity(width * height);
+
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(block(
+                    (x / factor) as isize,
+                    (y / factor) as isize,
+                    x % factor,
+                    y % factor,
+                ));
+            }
+        }
+
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+// Ways of making the screen bigger for export. They run in the order
+// given, so scale2x then nearest:4 gives an image eight times the size.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    // Every pixel becomes a square this many pixels across
+    Nearest(usize),
+    // Scale2x, also known as EPX, which doubles the size and rounds off
+    // the corners of diagonal edges
+    Scale2x,
+    // Scale3x, the same idea at three times the size
+    Scale3x,
+    // Scales up this much and darkens the bottom row of every pixel, like
+    // the gaps between the lines on a CRT
+    Scanlines(usize),
+    // Scales up this much and darkens the bottom row and right column of
+    // every pixel, so each one stands out on its own
+    Grid(usize),
+}
+
+impl Filter {
+    pub fn apply(&self, image: &Image) -> Image {
+        match *self {
+            Filter::Nearest(factor) => image.blocks(factor, |x, y, _, _| image.get(x, y)),
+            Filter::Scale2x => scale2x(image),
+            Filter::Scale3x => scale3x(image),
+            Filter::Scanlines(factor) => image.blocks(factor, |x, y, _, dy| {
+                let pixel = image.get(x, y);
+                if dy == factor - 1 {
+                    darken(pixel)
+                } else {
+                    pixel
+                }
+            }),
+            Filter::Grid(factor) => image.blocks(factor, |x, y, dx, dy| {
+                let pixel = image.get(x, y);
+                if dx == factor - 1 || dy == factor - 1 {
+                    darken(pixel)
+                } else {
+                    pixel
+                }
+            }),
+        }
+    }
+}
+
+// Written as nearest:N, scale2x (or epx), scale3x, scanlines:N or grid:N.
+// Scanlines and grids need at least two pixels to split between the
+// picture and the lines, and default to three.
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Filter, String> {
+        let error = || format!("{} is not a filter", text);
+        let mut parts = text.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let factor = match parts.next() {
+            Some(factor) => Some(factor.parse::<usize>().map_err(|_| error())?),
+            None => None,
+        };
+
+        match (name, factor) {
+            ("nearest", Some(factor)) if factor >= 1 => Ok(Filter::Nearest(factor)),
+            ("scale2x", None) | ("epx", None) => Ok(Filter::Scale2x),
+            ("scale3x", None) => Ok(Filter::Scale3x),
+            ("scanlines", factor) if factor.unwrap_or(3) >= 2 => {
+                Ok(Filter::Scanlines(factor.unwrap_or(3)))
+            }
+            ("grid", factor) if factor.unwrap_or(3) >= 2 => Ok(Filter::Grid(factor.unwrap_or(3))),
+            _ => Err(error()),
+        }
+    }
+}
+
+// Filters separated by commas, run one after another
+pub fn parse_filters(text: &str) -> Result<Vec<Filter>, String> {
+    text.split(',')
+        .map(|filter| filter.trim().parse())
+        .collect()
+}
+
+pub fn upscale(image: &Image, filters: &[Filter]) -> Image {
+    filters
+        .iter()
+        .fold(image.clone(), |image, filter| filter.apply(&image))
+}
+
+fn darken(pixel: [u8; 3]) -> [u8; 3] {
+    [pixel[0] / 2, pixel[1] / 2, pixel[2] / 2]
+}
+
+//   A
+// C P B
+//   D
+fn scale2x(image: &Image) -> Image {
+    image.blocks(2, |x, y, dx, dy| {
+        let p = image.get(x, y);
+        let a = image.get(x, y - 1);
+        let b = image.get(x + 1, y);
+        let c = image.get(x - 1, y);
+        let d = image.get(x, y + 1);
+
+        match (dx, dy) {
+            (0, 0) if c == a && c != d && a != b => a,
+            (1, 0) if a == b && a != c && b != d => b,
+            (0, 1) if d == c && d != b && c != a => c,
+            (1, 1) if b == d && b != a && d != c => d,
+            _ => p,
+        }
+    })
+}
+
+// A B C
+// D E F
+// G H I
+fn scale3x(image: &Image) -> Image {
+    image.blocks(3, |x, y, dx, dy| {
+        let a = image.get(x - 1, y - 1);
+        let b = image.get(x, y - 1);
+        let c = image.get(x + 1, y - 1);
+        let d = image.get(x - 1, y);
+        let e = image.get(x, y);
+        let f = image.get(x + 1, y);
+        let g = image.get(x - 1, y + 1);
+        let h = image.get(x, y + 1);
+        let i = image.get(x + 1, y + 1);
+
+        let top_left = d == b && b != f && d != h;
+        let top_right = b == f && b != d && f != h;
+        let bottom_left = d == h && d != b && h != f;
+        let bottom_right = h == f && d != h && b != f;
+
+        match (dx, dy) {
+            (0, 0) if top_left => d,
+            (1, 0) if (top_left && e != c) || (top_right && e != a) => b,
+            (2, 0) if top_right => f,
+            (0, 1) if (top_left && e != g) || (bottom_left && e != a) => d,
+            (2, 1) if (top_right && e != i) || (bottom_right && e != c) => f,
+            (0, 2) if bottom_left => d,
+            (1, 2) if (bottom_left && e != i) || (bottom_right && e != g) => h,
+            (2, 2) if bottom_right => f,
+            _ => e,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ON: [u8; 3] = [255, 255, 255];
+    const OFF: [u8; 3] = [0, 0, 0];
+
+    // An image from rows of # and .
+    fn image(rows: &[&str]) -> Image {
+        Image {
+            width: rows[0].len(),
+            height: rows.len(),
+            pixels: rows
+                .iter()
+                .flat_map(|row| row.chars().map(|pixel| if pixel == '#' { ON } else { OFF }))
+                .collect(),
+        }
+    }
+
+    fn text(image: &Image) -> Vec<String> {
+        image
+            .pixels
+            .chunks(image.width)
+            .map(|row| {
+                row.iter()
+                    .map(|pixel| match *pixel {
+                        ON => '#',
+                        OFF => '.',
+                        _ => '+',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nearest_neighbour() {
+        let scaled = Filter::Nearest(2).apply(&image(&["#.", ".."]));
+        assert_eq!(vec!["##..", "##..", "....", "...."], text(&scaled));
+    }
+
+    #[test]
+    fn scale2x_joins_diagonals() {
+        // Each pixel gives up the corner facing away from the other and
+        // the gap between them is filled in
+        let scaled = Filter::Scale2x.apply(&image(&["#.", ".#"]));
+        assert_eq!(vec!["##..", "#.#.", ".#.#", "..##"], text(&scaled));
+
+        // A lone pixel has no diagonal to follow and stays square
+        let scaled = Filter::Scale2x.apply(&image(&["...", ".#.", "..."]));
+        assert_eq!(
+            text(&Filter::Nearest(2).apply(&image(&["...", ".#.", "..."]))),
+            text(&scaled)
+        );
+    }
+
+    #[test]
+    fn scale3x_joins_diagonals() {
+        let scaled = Filter::Scale3x.apply(&image(&["#.", ".#"]));
+        assert_eq!(
+            vec!["###...", "##.#..", "#..##.", ".##..#", "..#.##", "...###"],
+            text(&scaled)
+        );
+    }
+
+    #[test]
+    fn lines_darken_the_edges() {
+        let scaled = Filter::Scanlines(2).apply(&image(&["#"]));
+        assert_eq!(vec!["##", "++"], text(&scaled));
+
+        let scaled = Filter::Grid(3).apply(&image(&["#"]));
+        assert_eq!(vec!["##+", "##+", "+++"], text(&scaled));
+    }
+
+    #[test]
+    fn parse_and_chain() {
+        let filters = parse_filters("epx, nearest:3").unwrap();
+        assert_eq!(vec![Filter::Scale2x, Filter::Nearest(3)], filters);
+        assert_eq!(12, upscale(&image(&["#.", ".."]), &filters).width);
+
+        assert_eq!(Ok(Filter::Scanlines(3)), "scanlines".parse());
+        assert!("nearest".parse::<Filter>().is_err());
+        assert!("grid:1".parse::<Filter>().is_err());
+        assert!("scale2x:2".parse::<Filter>().is_err());
+    }
+}
@@ -0,0 +1,491 @@
+use cdp1802::{Bus, Cdp1802};
+use display;
+use keyboard;
+use memory;
+use phosphor;
+
+// The COSMAC VIP memory map as the CHIP-8 interpreter lays it out on a 4K
+// machine
+pub const PROGRAM_START: u16 = 0x200;
+pub const STACK_TOP: u16 = 0xECF;
+pub const VARIABLES_START: u16 = 0xEF0;
+pub const DISPLAY_START: u16 = 0xF00;
+pub const ADDRESS_MASK: u16 = 0xFFF;
+pub const MEMORY_SIZE: usize = 0x1000;
+
+// CDP1861 timing. The VIP clock is 1.76 MHz and a machine cycle is 8 clocks,
+// which comes out to 14 machine cycles per scan line and 262 lines per frame
+pub const CYCLES_PER_LINE: u64 = 14;
+pub const LINES_PER_FRAME: u64 = 262;
+pub const CYCLES_PER_FRAME: u64 = CYCLES_PER_LINE * LINES_PER_FRAME;
+const INTERRUPT_LINE: u64 = 62;
+const DISPLAY_FIRST_LINE: u64 = 64;
+const DISPLAY_LINES: u64 = 128;
+const DMA_BYTES_PER_LINE: usize = 8;
+
+// The interpreter returns from a machine code subroutine with SEP R4
+const INTERPRETER_PC: u8 = 4;
+
+// How long a native subroutine runs before it yields back to the CHIP-8
+// machine, about a frame. One waiting on a key or a timer would otherwise
+// never see it change
+const NATIVE_SLICE_CYCLES: u64 = CYCLES_PER_FRAME;
+
+// EF1 is asserted for the four lines before the display area starts and the
+// four lines before it ends, which is what display routines sync on
+fn ef1_active(line: u64) -> bool {
+    (DISPLAY_FIRST_LINE - 4..DISPLAY_FIRST_LINE).contains(&line)
+        || (DISPLAY_FIRST_LINE + DISPLAY_LINES - 4..DISPLAY_FIRST_LINE + DISPLAY_LINES)
+            .contains(&line)
+}
+
+fn current_line(cycles: u64) -> u64 {
+    (cycles % CYCLES_PER_FRAME) / CYCLES_PER_LINE
+}
+
+// The VIP hex keypad is scanned by writing a key number with OUT 2 and then
+// testing EF3, which is asserted while that key is held
+struct Keypad<'a> {
+    keyboard: &'a keyboard::Chip8Keyboard,
+    latch: u8,
+}
+
+impl<'a> Keypad<'a> {
+    fn flag(&self) -> bool {
+        self.keyboard.is_pressed(self.latch)
+    }
+}
+
+// The bus used when a Chip8Machine hands a SYS call to native code. It sits
+// directly on top of the machine's memory
+struct SysBus<'a> {
+    memory: &'a mut memory::Chip8Memory,
+    keypad: Keypad<'a>,
+    cycles: u64,
+}
+
+impl<'a> Bus for SysBus<'a> {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory.read((address & ADDRESS_MASK) as usize)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory.write((address & ADDRESS_MASK) as usize, value);
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port == 2 {
+            self.keypad.latch = value & 0xF;
+        }
+    }
+
+    fn flag(&mut self, line: u8) -> bool {
+        match line {
+            1 => ef1_active(current_line(self.cycles)),
+            3 => self.keypad.flag(),
+            _ => false,
+        }
+    }
+}
+
+// Everything from the CHIP-8 machine that a native subroutine can see
+pub struct NativeState<'a> {
+    pub memory: &'a mut memory::Chip8Memory,
+    pub display: &'a mut display::Chip8Display,
+    pub keyboard: &'a keyboard::Chip8Keyboard,
+    pub variables: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub delay: u8,
+    pub sound: u8,
+}
+
+// A native subroutine that yielded before returning
+pub struct Suspended {
+    address: u16,
+    cpu: Cdp1802,
+    latch: u8,
+}
+
+// Run the 1802 subroutine at `address` the way the VIP interpreter does for
+// 0MMM. The CHIP-8 state is copied into the VIP memory map and register
+// conventions, the subroutine runs until it hands control back with SEP R4,
+// and then the state is copied back out.
+//
+// A subroutine still running after a slice of time yields instead, and is
+// returned to be passed back in when the same 0MMM runs again. Only the
+// screen and the timers are brought up to date in between.
+pub fn call_native(
+    state: &mut NativeState,
+    address: u16,
+    suspended: Option<Suspended>,
+) -> Option<Suspended> {
+    let (mut cpu, latch) = match suspended {
+        Some(suspended) if suspended.address == address => (suspended.cpu, suspended.latch),
+        _ => (enter_native(state, address), 0),
+    };
+    cpu.r[8] = (state.delay as u16) << 8 | state.sound as u16;
+
+    let end = cpu.cycles + NATIVE_SLICE_CYCLES;
+    let latch = {
+        let mut bus = SysBus {
+            memory: state.memory,
+            keypad: Keypad {
+                keyboard: state.keyboard,
+                latch,
+            },
+            cycles: cpu.cycles,
+        };
+
+        while cpu.p != INTERPRETER_PC && cpu.cycles < end {
+            cpu.step(&mut bus);
+            bus.cycles = cpu.cycles;
+        }
+
+        bus.keypad.latch
+    };
+
+    read_display(state.memory, state.display);
+    state.delay = (cpu.r[8] >> 8) as u8;
+    state.sound = cpu.r[8] as u8;
+    if cpu.p != INTERPRETER_PC {
+        return Some(Suspended {
+            address,
+            cpu,
+            latch,
+        });
+    }
+
+    for (offset, value) in state.variables.iter_mut().enumerate() {
+        *value = state.memory.read((VARIABLES_START as usize) + offset);
+    }
+    state.i = cpu.r[0xA] & ADDRESS_MASK;
+    state.pc = cpu.r[5] & ADDRESS_MASK;
+
+    None
+}
+
+fn enter_native(state: &mut NativeState, address: u16) -> Cdp1802 {
+    for (offset, value) in state.variables.iter().enumerate() {
+        state
+            .memory
+            .write((VARIABLES_START as usize) + offset, *value);
+    }
+    write_display(state.memory, state.display);
+
+    let mut r = [0u16; 16];
+    r[2] = STACK_TOP;
+    r[3] = address & ADDRESS_MASK;
+    r[5] = state.pc;
+    r[6] = VARIABLES_START + ((address >> 8) & 0xF);
+    r[7] = VARIABLES_START + ((address >> 4) & 0xF);
+    r[0xA] = state.i;
+    r[0xB] = DISPLAY_START;
+
+    Cdp1802 {
+        r,
+        p: 3,
+        x: 2,
+        ..Cdp1802::default()
+    }
+}
+
+// The display refresh area is one bit per pixel, eight pixels per byte
+fn write_display(memory: &mut memory::Chip8Memory, display: &display::Chip8Display) {
+    for y in 0..display::HEIGHT {
+        for column in 0..display::WIDTH / 8 {
+            let mut byte = 0u8;
+            for bit in 0..8 {
+                if display.get_pixel(column * 8 + bit, y) {
+                    byte |= 0x80 >> bit;
+                }
+            }
+
+            let address = DISPLAY_START as usize + y * (display::WIDTH / 8) + column;
+            memory.write(address, byte);
+        }
+    }
+}
+
+fn read_display(memory: &memory::Chip8Memory, display: &mut display::Chip8Display) {
+    for y in 0..display::HEIGHT {
+        for column in 0..display::WIDTH / 8 {
+            let address = DISPLAY_START as usize + y * (display::WIDTH / 8) + column;
+            let byte = memory.read(address);
+
+            for bit in 0..8 {
+                display.set_pixel(column * 8 + bit, y, byte & (0x80 >> bit) != 0);
+            }
+        }
+    }
+}
+
+struct VipHardware {
+    memory: [u8; 4096],
+    keyboard: keyboard::Chip8Keyboard,
+    keypad_latch: u8,
+    display_enabled: bool,
+    line: u64,
+}
+
+impl Bus for VipHardware {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory[(address & ADDRESS_MASK) as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory[(address & ADDRESS_MASK) as usize] = value;
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_enabled = false,
+            2 => self.keypad_latch = value & 0xF,
+            _ => {}
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.display_enabled = true;
+        }
+
+        0
+    }
+
+    fn flag(&mut self, line: u8) -> bool {
+        match line {
+            1 => self.display_enabled && ef1_active(self.line),
+            3 => self.keyboard.is_pressed(self.keypad_latch),
+            _ => false,
+        }
+    }
+}
+
+// A whole COSMAC VIP: a 1802, 4K of RAM and a CDP1861 video chip. The CHIP-8
+// interpreter is loaded at 0x000 as a native program and runs CHIP-8 programs
+// exactly like the real machine did, SYS calls included. The interpreter image
+// itself isn't distributed with this crate and has to be supplied by the user.
+pub struct VipMachine {
+    cpu: Cdp1802,
+    hardware: VipHardware,
+    lines: [[u8; DMA_BYTES_PER_LINE]; DISPLAY_LINES as usize],
+}
+
+impl VipMachine {
+    pub fn new(interpreter: &[u8]) -> VipMachine {
+        if interpreter.len() > PROGRAM_START as usize {
+            panic!(
+                "Interpreter image is {} bytes but must fit below {:#x}",
+                interpreter.len(),
+                PROGRAM_START
+            );
+        }
+
+        let mut hardware = VipHardware {
+            memory: [0; 4096],
+            keyboard: keyboard::Chip8Keyboard::default(),
+            keypad_latch: 0,
+            display_enabled: false,
+            line: 0,
+        };
+        hardware.memory[..interpreter.len()].copy_from_slice(interpreter);
+
+        // The monitor ROM leaves the top page of RAM in R1.1, which is where
+        // the interpreter puts the display refresh area
+        let mut cpu = Cdp1802::default();
+        cpu.r[1] = DISPLAY_START;
+
+        VipMachine {
+            cpu,
+            hardware,
+            lines: [[0; DMA_BYTES_PER_LINE]; DISPLAY_LINES as usize],
+        }
+    }
+
+    pub fn load_memory(&mut self, program: &[u8]) {
+        let start = PROGRAM_START as usize;
+        if program.len() > DISPLAY_START as usize - start {
+            panic!(
+                "Program is {} bytes but must fit below {:#x}",
+                program.len(),
+                DISPLAY_START
+            );
+        }
+
+        self.hardware.memory[start..start + program.len()].copy_from_slice(program);
+    }
+
+    pub fn keyboard(&mut self) -> &mut keyboard::Chip8Keyboard {
+        &mut self.hardware.keyboard
+    }
+
+    pub fn sound_on(&self) -> bool {
+        self.cpu.q
+    }
+
+    // Run one 60 Hz frame, servicing the 1861 interrupt and DMA as the beam
+    // moves down the screen
+    pub fn run_frame(&mut self) {
+        let frame_end = (self.cpu.cycles / CYCLES_PER_FRAME + 1) * CYCLES_PER_FRAME;
+
+        while self.cpu.cycles < frame_end {
+            let line = current_line(self.cpu.cycles);
+
+            if line != self.hardware.line {
+                self.hardware.line = line;
+                self.start_line(line);
+            }
+
+            self.cpu.step(&mut self.hardware);
+        }
+    }
+
+    fn start_line(&mut self, line: u64) {
+        if !self.hardware.display_enabled {
+            return;
+        }
+
+        if line == INTERRUPT_LINE {
+            self.cpu.interrupt();
+        }
+
+        if (DISPLAY_FIRST_LINE..DISPLAY_FIRST_LINE + DISPLAY_LINES).contains(&line) {
+            let row = (line - DISPLAY_FIRST_LINE) as usize;
+            for byte in 0..DMA_BYTES_PER_LINE {
+                self.lines[row][byte] = self.cpu.dma_out(&mut self.hardware);
+            }
+        }
+    }
+
+    // The interpreter shows each row for four scan lines, so the 64x32
+    // CHIP-8 display is every fourth line of the picture
+    pub fn display(&self) -> display::Chip8Display {
+        let mut display = display::Chip8Display::default();
+
+        for y in 0..display::HEIGHT {
+            let line = &self.lines[y * 4];
+            for x in 0..display::WIDTH {
+                display.set_pixel(x, y, line[x / 8] & (0x80 >> (x % 8)) != 0);
+            }
+        }
+
+        display
+    }
+
+    // How bright each pixel is, from 0 to 255, the same as
+    // Chip8Machine::screen_levels without persistence
+    pub fn screen_levels(&self) -> Vec<u8> {
+        let mut phosphor = phosphor::Phosphor::new(phosphor::Persistence::Off);
+        phosphor.update(&self.display());
+        phosphor.levels().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use builder::{Chip8MachineBuilder, MachineMode};
+
+    fn native_state_call(program: &[u8], variables: [u8; 16]) -> ([u8; 16], u16) {
+        let mut memory = memory::Chip8Memory::default();
+        let mut display = display::Chip8Display::default();
+        let keyboard = keyboard::Chip8Keyboard::default();
+
+        for (offset, byte) in program.iter().enumerate() {
+            memory.write(0x300 + offset, *byte);
+        }
+
+        let mut state = NativeState {
+            memory: &mut memory,
+            display: &mut display,
+            keyboard: &keyboard,
+            variables,
+            i: 0x123,
+            pc: 0x200,
+            delay: 0,
+            sound: 0,
+        };
+        assert!(call_native(&mut state, 0x300, None).is_none());
+
+        (state.variables, state.i)
+    }
+
+    #[test]
+    fn native_subroutine_updates_variables() {
+        // SEX R6; LDX; ADI 1; STR R6; SEP R4
+        let (variables, _) = native_state_call(&[0xE6, 0xF0, 0xFC, 0x01, 0x56, 0xD4], [7; 16]);
+        assert_eq!(8, variables[0x3]);
+        assert_eq!(7, variables[0x0]);
+    }
+
+    #[test]
+    fn native_subroutine_sets_i() {
+        // LDI 0x04; PHI RA; LDI 0x56; PLO RA; SEP R4
+        let (_, i) = native_state_call(&[0xF8, 0x04, 0xBA, 0xF8, 0x56, 0xAA, 0xD4], [0; 16]);
+        assert_eq!(0x456, i);
+    }
+
+    #[test]
+    fn native_key_waits_yield() {
+        // V3 := 5, SYS 0x300, then loop. At 0x300: SEX R6; OUT 2 to latch
+        // key V3; BN3 back to itself until it's held; SEP R4
+        let mut program = vec![0x63, 0x05, 0x03, 0x00, 0x12, 0x04];
+        program.resize(0x100, 0);
+        program.extend_from_slice(&[0xE6, 0x62, 0x3E, 0x02, 0xD4]);
+
+        let mut machine = Chip8MachineBuilder::new()
+            .mode(MachineMode::CosmacVip)
+            .build();
+        machine.load_memory(&program);
+
+        machine.run_frame();
+        assert_eq!(0x202, machine.pc());
+        machine.set_key(5, true);
+        machine.run_frame();
+        assert_eq!(0x204, machine.pc());
+    }
+
+    // Moves to R3, points R1 at an interrupt routine that starts the
+    // display at 0x200 each frame, turns the display on with INP 1 and
+    // waits
+    fn interpreter() -> Vec<u8> {
+        let mut image = vec![0xF8, 0x10, 0xA3, 0xD3];
+        image.resize(0x10, 0);
+        image.extend_from_slice(&[
+            0xF8, 0x31, 0xA1, 0xF8, 0x00, 0xB1, 0xF8, 0x0E, 0xB2, 0xF8, 0xCF, 0xA2, 0xE2, 0x69,
+            0x30, 0x1E,
+        ]);
+        image.resize(0x30, 0);
+        image.extend_from_slice(&[
+            0x70, 0x22, 0x78, 0xF8, 0x02, 0xB0, 0xF8, 0x00, 0xA0, 0x30, 0x30,
+        ]);
+        image
+    }
+
+    #[test]
+    fn interrupts_and_dma_draw_the_screen() {
+        let mut vip = VipMachine::new(&interpreter());
+        vip.load_memory(&[0xFF; 8]);
+        vip.run_frame();
+        vip.run_frame();
+
+        let display = vip.display();
+        assert!((0..display::WIDTH).all(|x| display.get_pixel(x, 0)));
+        assert!((0..display::WIDTH).all(|x| !display.get_pixel(x, 1)));
+        assert_eq!(255, vip.screen_levels()[0]);
+    }
+
+    #[test]
+    fn screen_stays_dark_until_enabled() {
+        // INP 1 becomes a NOP
+        let mut image = interpreter();
+        image[0x1D] = 0xC4;
+        let mut vip = VipMachine::new(&image);
+        vip.load_memory(&[0xFF; 8]);
+        vip.run_frame();
+        vip.run_frame();
+
+        assert!(vip.screen_levels().iter().all(|level| *level == 0));
+    }
+}